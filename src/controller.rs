@@ -0,0 +1,692 @@
+/*!
+ * This file contains the interface the UI uses to drive a machine controller.
+ * Each firmware implements `Controller` on top of its own protocol, and is
+ * listed in `CONTROLLERS` so that it can be picked when connecting.
+ */
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bitflags::bitflags;
+use tokio::runtime::Handle;
+
+use crate::preprocess::{Pipeline, Preprocessed};
+use crate::simulation::GcodeProgram;
+
+mod axes;
+mod history;
+mod settings;
+mod traffic;
+
+pub use axes::*;
+pub use history::*;
+pub use settings::*;
+pub use traffic::*;
+
+/// A step of the feed or spindle override.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverrideStep {
+    /// back to 100%
+    Reset,
+    Increase10,
+    Decrease10,
+    Increase1,
+    Decrease1,
+}
+
+/// A rapid override, as a fraction of the maximum rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RapidOverride {
+    Full,
+    Half,
+    Quarter,
+}
+
+/// An action that takes effect immediately, even while a program is running.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RealtimeAction {
+    CycleStart,
+    FeedHold,
+    Reset,
+    SafetyDoor,
+    JogCancel,
+    FeedOverride(OverrideStep),
+    SpindleOverride(OverrideStep),
+    RapidOverride(RapidOverride),
+    SpindleStop,
+    FloodToggle,
+    MistToggle,
+}
+
+/// What the machine is doing.
+#[derive(Debug, Default, Copy, Clone, PartialEq, Eq)]
+pub enum MachineState {
+    #[default]
+    Idle,
+    Run,
+    /// a feed hold, `true` while the machine is still slowing down
+    Hold(bool),
+    Jog,
+    Alarm,
+    /// the safety door cycle: 0 closed, 1 open, 2 parking, 3 restoring from park
+    Door(u8),
+    /// streaming a program without moving, to find errors
+    Check,
+    Home,
+    Sleep,
+    /// waiting for a manual tool change to be acknowledged
+    Tool,
+}
+
+impl MachineState {
+    /// Describes the sub-state of a hold or a safety door.
+    pub fn description(&self) -> Option<&'static str> {
+        match *self {
+            MachineState::Hold(false) => Some("Hold complete. Ready to resume."),
+            MachineState::Hold(true)  => Some("Hold in progress. Reset will throw an alarm."),
+            MachineState::Door(0)     => Some("Door closed. Ready to resume."),
+            MachineState::Door(1)     => Some("Machine stopped. Door still ajar. Can't resume until closed."),
+            MachineState::Door(2)     => Some("Door opened. Hold (or parking retract) in progress. Reset will throw an alarm."),
+            MachineState::Door(3)     => Some("Door closed and resuming. Restoring from park, if applicable. Reset will throw an alarm."),
+            _                         => None,
+        }
+    }
+
+    /// Returns false while a hold or a safety door is still in progress, when a cycle start is ignored.
+    pub fn can_resume(&self) -> bool {
+        match *self {
+            MachineState::Hold(in_progress) => !in_progress,
+            MachineState::Door(n)           => n == 0,
+            _                               => true,
+        }
+    }
+}
+
+/// An action that gets the machine out of an alarm.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AlarmRecovery {
+    /// clears the alarm and keeps the position
+    Unlock,
+    /// finds the position again
+    Home,
+    SoftReset,
+}
+
+/// An alarm, which locks the machine until it is cleared.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Alarm {
+    /// the alarm as the controller names it, e.g. `ALARM:1 Hard limit`
+    pub name : String,
+    pub description : String,
+    /// the actions that clear the alarm, the recommended one first
+    pub recovery : Vec<AlarmRecovery>,
+}
+
+bitflags! {
+    /// The input pins a status report shows as triggered.
+    #[derive(Default)]
+    pub struct InputPins : u16 {
+        const X_LIMIT     = 0b0000_0000_0001;
+        const Y_LIMIT     = 0b0000_0000_0010;
+        const Z_LIMIT     = 0b0000_0000_0100;
+        const PROBE       = 0b0000_0000_1000;
+        const DOOR        = 0b0000_0001_0000;
+        const HOLD        = 0b0000_0010_0000;
+        const SOFT_RESET  = 0b0000_0100_0000;
+        const CYCLE_START = 0b0000_1000_0000;
+        const A_LIMIT     = 0b0001_0000_0000;
+        const B_LIMIT     = 0b0010_0000_0000;
+        const C_LIMIT     = 0b0100_0000_0000;
+    }
+}
+
+impl InputPins {
+    /// The limit pin of `axis`, indexed like `AXIS_NAMES`.
+    pub fn limit(axis : usize) -> Self {
+        [Self::X_LIMIT, Self::Y_LIMIT, Self::Z_LIMIT, Self::A_LIMIT, Self::B_LIMIT, Self::C_LIMIT][axis]
+    }
+}
+
+/// The state and position of the machine, from its last status report.
+#[derive(Debug, Default, Clone)]
+pub struct MachineStatus {
+    pub machine_position : AxisValues,
+    pub work_offset : AxisValues,
+    pub state : MachineState,
+    pub buffer_free_blocks : u32,
+    pub buffer_free_bytes : u32,
+    /// the line being executed, when the program is numbered
+    pub line_number : u32,
+    pub feed : f32,
+    pub speed : f32,
+    pub override_feed : u32,
+    pub override_speed : u32,
+    pub override_rapid : u32,
+    pub spindle_cw : bool,
+    pub spindle_ccw : bool,
+    pub flood_coolant : bool,
+    pub mist_coolant : bool,
+    pub inputs : InputPins,
+    /// the tool in the spindle, if the controller reports it
+    pub tool : Option<u32>,
+    /// whether the machine has been homed, if the controller reports it
+    pub homed : Option<bool>,
+    /// the progress of a job run from the controller's SD card, and the file name
+    pub sd_progress : Option<(f32, String)>,
+    /// the last alarm, until the machine leaves the alarm state
+    pub alarm : Option<Alarm>,
+    /// set when the controller asks for a soft reset before the alarm can be cleared
+    pub reset_required : bool,
+    /// set while the spindle is restored after a hold or a safety door
+    pub restoring_spindle : bool,
+}
+
+impl MachineStatus {
+    /// The number of axes in the last status report.
+    pub fn axis_count(&self) -> usize {
+        if self.machine_position.is_empty() {DEFAULT_AXIS_COUNT} else {self.machine_position.len()}
+    }
+
+    /// The position in the active work coordinate system.
+    pub fn work_position(&self) -> AxisValues {
+        self.machine_position - self.work_offset
+    }
+}
+
+bitflags! {
+    /// The optional features a controller may have been built without.
+    #[derive(Default)]
+    pub struct Features : u32 {
+        const VARIABLE_SPINDLE = 1 << 0;
+        const LINE_NUMBERS     = 1 << 1;
+        const MIST_COOLANT     = 1 << 2;
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParserMotionMode {
+    Rapid,              // G0
+    Linear,             // G1
+    ArcCW,              // G2
+    ArcCCW,             // G3
+    Probe(u8),          // G38.x
+    Cancel,             // G80
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParserPlane {
+    XY,                 // G17
+    ZX,                 // G18
+    YZ,                 // G19
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParserUnits {
+    Inches,             // G20
+    Millimeters,        // G21
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParserDistanceMode {
+    Absolute,           // G90
+    Incremental,        // G91
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParserFeedRateMode {
+    InverseTime,        // G93
+    UnitsPerMinute,     // G94
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParserProgramMode {
+    Running,
+    Pause,              // M0
+    OptionalStop,       // M1
+    End,                // M2
+    EndAndReset,        // M30
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum ParserSpindleState {
+    CW,                 // M3
+    CCW,                // M4
+    Off,                // M5
+}
+
+/// The modal state of the controller's g-code parser.
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct GCodeParserState {
+    pub motion_mode : ParserMotionMode,
+    /// 0 for G54 through 5 for G59
+    pub coordinate_system : u8,
+    pub plane : ParserPlane,
+    pub units : ParserUnits,
+    pub distance_mode : ParserDistanceMode,
+    pub feed_rate_mode : ParserFeedRateMode,
+    /// true if a dynamic tool length offset (G43.1) is active
+    pub tool_length_offset : bool,
+    pub program_mode : ParserProgramMode,
+    pub spindle : ParserSpindleState,
+    pub flood_coolant : bool,
+    pub mist_coolant : bool,
+    pub tool : u32,
+    pub feed : f32,
+    pub speed : f32,
+}
+
+impl Default for GCodeParserState {
+    fn default() -> Self {
+        // the usual power-up defaults
+        GCodeParserState {
+            motion_mode : ParserMotionMode::Rapid,
+            coordinate_system : 0,
+            plane : ParserPlane::XY,
+            units : ParserUnits::Millimeters,
+            distance_mode : ParserDistanceMode::Absolute,
+            feed_rate_mode : ParserFeedRateMode::UnitsPerMinute,
+            tool_length_offset : false,
+            program_mode : ParserProgramMode::Running,
+            spindle : ParserSpindleState::Off,
+            flood_coolant : false,
+            mist_coolant : false,
+            tool : 0,
+            feed : 0.0,
+            speed : 0.0,
+        }
+    }
+}
+
+/// The coordinate systems and offsets stored by the controller.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct GCodeOffsets {
+    /// G54 through G59
+    pub work_coordinates : [AxisValues; 6],
+    pub g28 : AxisValues,
+    pub g30 : AxisValues,
+    pub g92 : AxisValues,
+    pub tool_length_offset : f32,
+}
+
+/// The result of the last probing cycle.
+#[derive(Debug, Default, Copy, Clone, PartialEq)]
+pub struct ProbeResult {
+    /// the probed position in machine coordinates
+    pub position : AxisValues,
+    pub success : bool,
+}
+
+/// Everything learned about the controller besides its status.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ControllerInfo {
+    /// the version and build of the firmware, once reported
+    pub version : Option<String>,
+    /// the options the firmware was built with, as the controller lists them
+    pub options : Option<String>,
+    pub planner_blocks : Option<u32>,
+    /// the size of the receive buffer, in bytes
+    pub rx_buffer_size : usize,
+    /// the optional features, or `None` until they are known
+    pub features : Option<Features>,
+    pub parser_state : Option<GCodeParserState>,
+    pub offsets : GCodeOffsets,
+    pub probe : Option<ProbeResult>,
+}
+
+impl ControllerInfo {
+    /// Returns true if the controller has all of `features`, or if its features aren't known yet.
+    pub fn supports(&self, features : Features) -> bool {
+        self.features.map_or(true, |f| f.contains(features))
+    }
+}
+
+/// An error the controller answered a line with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ControllerError {
+    /// the error as the controller names it, e.g. `error:20 Unsupported command`
+    pub name : String,
+    pub description : String,
+}
+
+impl fmt::Display for ControllerError {
+    fn fmt(&self, f : &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.name)
+    }
+}
+
+/// An error, together with the line that caused it.
+#[derive(Debug, Clone)]
+pub struct ErrorReport {
+    pub error : ControllerError,
+    /// the line as it was sent
+    pub line : String,
+    /// index of the line in the program, if it was sent as part of a program
+    pub program_line : Option<usize>,
+}
+
+/// The lines a controller runs after every reset.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct StartupBlocks {
+    /// every block, or `None` for those that haven't been reported
+    pub blocks : Vec<Option<String>>,
+    /// the blocks run after the last reset, and the errors they raised
+    pub results : Vec<(String, Result<(), ControllerError>)>,
+}
+
+/// Where and how tools are changed, in machine coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ToolChangeSettings {
+    /// where the machine waits for the tool to be changed
+    pub position : [f32; 3],
+    /// X and Y of the tool length sensor, which is probed from the Z of `position`
+    pub probe_position : [f32; 2],
+    /// how far down the probe may move before it gives up
+    pub probe_distance : f32,
+    /// mm/min
+    pub probe_feed : f32,
+    /// also stop for a T word without M6, as some post processors leave M6 out
+    pub on_tool_select : bool,
+    /// probe the length of every new tool, against the tool in the spindle at the first tool change
+    pub measure : bool,
+}
+
+impl Default for ToolChangeSettings {
+    fn default() -> Self {
+        Self {
+            position : [0.0, 0.0, -5.0],
+            probe_position : [0.0, 0.0],
+            probe_distance : 50.0,
+            probe_feed : 100.0,
+            on_tool_select : false,
+            measure : false,
+        }
+    }
+}
+
+/// The steps of a tool change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToolChangeStage {
+    /// reading the modal state the lines before the tool change left, to restore it afterwards
+    ReadingState,
+    /// moving to the tool change position
+    Moving,
+    /// probing the tool that was used to set work Z, before it is taken out
+    MeasuringReference,
+    /// waiting for the new tool to be put in
+    WaitingForTool,
+    /// probing the length of the new tool
+    Measuring,
+    /// restarting the spindle before the program continues
+    Resuming,
+}
+
+/// A tool change the program is stopped for.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ToolChange {
+    /// the tool to put in, if the program selected one
+    pub tool : Option<u32>,
+    /// index of the line with the tool change
+    pub line : usize,
+    pub stage : ToolChangeStage,
+    /// the tool length offset applied after measuring, relative to the reference tool
+    pub offset : Option<f32>,
+    /// whether the reference tool has been measured, so that the new tool can be
+    pub has_reference : bool,
+}
+
+/// A connection to a machine controller. Every method returns immediately; the work is done by a
+/// task in the background, and the results show up in the state returned by the getters.
+/// The lifecycle of the connection to the controller.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnectionState {
+    /// opening the port for the first time
+    Connecting,
+    /// the port is open, but the controller hasn't announced itself yet
+    WaitingForWelcome,
+    /// the controller has announced itself and accepts commands
+    Ready,
+    /// the port failed; it will be re-opened shortly
+    Lost(String),
+    /// re-opening the port has failed `attempt` times, most recently because of `reason`
+    Reconnecting {
+        attempt : u32,
+        reason : String,
+    },
+}
+
+/// The protocol used to stream program lines to the controller.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum StreamingMode {
+    /// Send a line, then wait for its `ok` or `error` before sending the next one.
+    SendResponse,
+    /// Keep the controller's receive buffer full by counting the bytes of every unacknowledged line.
+    #[default]
+    CharacterCounting,
+}
+
+/// How far the program being streamed has got.
+#[derive(Debug, Clone)]
+pub struct ProgramProgress {
+    pub started : Instant,
+    /// index of the first line streamed, which isn't 0 when starting from a line
+    pub start_line : usize,
+    /// the number of lines in the program, including those before `start_line`
+    pub total_lines : usize,
+    /// index after the last line the controller has acknowledged
+    pub acknowledged : usize,
+    /// set when the sender numbered the lines, so that `MachineStatus::line_number` is one more
+    /// than the index of the line being executed
+    pub numbered : bool,
+    /// set when the last line was acknowledged, or the program was stopped
+    pub finished : Option<Instant>,
+    /// the time spent waiting in a hold, behind the safety door or for a tool change
+    pub waited : Duration,
+    /// set while the program is waiting
+    pub waiting_since : Option<Instant>,
+}
+
+impl ProgramProgress {
+    pub fn new(start_line : usize, total_lines : usize, numbered : bool) -> Self {
+        Self {
+            started : Instant::now(),
+            start_line,
+            total_lines,
+            acknowledged : start_line,
+            numbered,
+            finished : None,
+            waited : Duration::from_secs(0),
+            waiting_since : None,
+        }
+    }
+
+    /// Starts or stops counting the time the program waits.
+    pub fn set_waiting(&mut self, waiting : bool) {
+        match (waiting, self.waiting_since) {
+            (true, None) => {self.waiting_since = Some(Instant::now());}
+            (false, Some(since)) => {
+                self.waited += since.elapsed();
+                self.waiting_since = None;
+            }
+            _ => {}
+        }
+    }
+
+    /// The time since the program was started, until it finished.
+    pub fn elapsed(&self) -> Duration {
+        self.finished.unwrap_or_else(Instant::now).duration_since(self.started)
+    }
+
+    /// The time the program has been running, not counting the time it waited.
+    pub fn running_time(&self) -> Duration {
+        let end = self.finished.unwrap_or_else(Instant::now);
+        let waiting = self.waiting_since.map_or(Duration::from_secs(0), |since| end.saturating_duration_since(since));
+        self.elapsed().saturating_sub(self.waited + waiting)
+    }
+}
+
+pub trait Controller {
+    /// The firmware on the other end, e.g. `GRBL`.
+    fn firmware(&self) -> String;
+
+    fn connection_state(&self) -> ConnectionState;
+
+    /// Returns true if the controller accepts commands.
+    fn is_ready(&self) -> bool;
+
+    /// Closes the connection.
+    fn stop(self : Box<Self>);
+
+    /// Starts streaming a program. Returns false if the controller is busy.
+    fn start_program(&self, program : GcodeProgram) -> bool;
+
+    /// Starts streaming a program from the line with index `start`, after sending `lead_in`.
+    /// Returns false if the controller is busy.
+    fn start_program_from(&self, program : GcodeProgram, start : usize, lead_in : Vec<String>) -> bool;
+
+    /// Streams a program without moving the machine, to find errors. Returns false if the controller is busy.
+    fn validate_program(&self, program : GcodeProgram) -> bool;
+
+    fn stop_program(&self);
+
+    fn pause_program(&self);
+
+    fn resume_program(&self);
+
+    fn is_paused(&self) -> bool;
+
+    /// Returns true while a program is being streamed.
+    fn has_program(&self) -> bool;
+
+    /// Why the last program was stopped before its end, e.g. by an alarm.
+    fn program_stopped(&self) -> Option<String>;
+
+    /// How far the last program started has got.
+    fn progress(&self) -> Option<ProgramProgress>;
+
+    /// The number of lines of the program sent so far.
+    fn program_line(&self) -> u64;
+
+    fn set_streaming_mode(&self, mode : StreamingMode);
+
+    /// Sets how often the controller is asked for its status.
+    fn set_poll_rates(&self, rates : PollRates);
+
+    /// Sets the passes the programs started from now on go through before they are sent.
+    fn set_pipeline(&self, pipeline : Pipeline);
+
+    /// Runs `pipeline` over the lines of a program, as it would before sending them to this controller.
+    fn preprocess(&self, pipeline : &Pipeline, lines : &[String]) -> Result<Preprocessed, String>;
+
+    /// Numbers the lines of the programs started from now on, so that the controller reports the
+    /// line it executes in `MachineStatus::line_number`.
+    fn set_line_numbering(&self, enabled : bool);
+
+    /// Sets where tools are changed when the program asks for a new one with M6.
+    fn set_tool_change_settings(&self, settings : ToolChangeSettings);
+
+    /// The tool change the program is stopped for, if any.
+    fn tool_change(&self) -> Option<ToolChange>;
+
+    /// Continues the program once the new tool is in, measuring its length first if the tool
+    /// change settings say so.
+    fn continue_tool_change(&self);
+
+    /// Sends a line of G-code or a firmware command. Returns false if the controller is busy.
+    fn send_line(&self, line : String) -> bool;
+
+    /// Returns every error reported since the last call to `clear_errors`.
+    fn errors(&self) -> Vec<ErrorReport>;
+
+    fn clear_errors(&self);
+
+    /// The last reported state and position of the machine.
+    fn machine_status(&self) -> MachineStatus;
+
+    /// The build, parser state, offsets and probe result last reported by the controller.
+    fn info(&self) -> ControllerInfo;
+
+    /// Asks the controller to report its parser state and offsets again.
+    fn refresh_info(&self);
+
+    fn realtime(&self, action : RealtimeAction);
+
+    /// Jogs the axes that have a target, at `feed` mm/min. Returns false if the controller is busy.
+    fn jog(&self, axes : [Option<f32>; MAX_AXES], feed : f32, incremental : bool) -> bool;
+
+    /// Runs the homing cycle. Returns false if the controller is busy.
+    fn home(&self) -> bool;
+
+    /// Clears an alarm without homing. Returns false if the controller is busy.
+    fn unlock(&self) -> bool;
+
+    /// The settings of the controller, described well enough to edit them.
+    fn settings(&self) -> ExtendedSettings;
+
+    /// Asks the controller to report its settings again.
+    fn fetch_settings(&self) -> bool;
+
+    /// Writes the given values, by setting number. Returns false if the controller is busy.
+    fn write_settings(&self, changes : Vec<(u32, String)>) -> bool;
+
+    /// Writes setting values to a file that `settings_from_file` reads back, or returns `None` if
+    /// the controller's settings can't be saved.
+    fn settings_to_file(&self, _values : &BTreeMap<u32, String>) -> Option<String> {
+        None
+    }
+
+    /// Reads the setting values in a file, by setting number, or returns `None` if the
+    /// controller's settings can't be restored.
+    fn settings_from_file(&self, _contents : &str) -> Option<Result<Vec<(u32, String)>, String>> {
+        None
+    }
+
+    /// The startup blocks, or `None` if the controller doesn't have them.
+    fn startup_blocks(&self) -> Option<StartupBlocks> {
+        None
+    }
+
+    /// Asks the controller to report its startup blocks again.
+    fn fetch_startup_blocks(&self) -> bool {
+        false
+    }
+
+    /// Checks that `line` can be stored as a startup block, and returns it as it would be stored.
+    fn validate_startup_block(&self, _line : &str) -> Result<String, String> {
+        Err(String::from("this controller doesn't have startup blocks"))
+    }
+
+    /// Stores `line` as the startup block `index`, or clears it if `line` is empty.
+    fn write_startup_block(&self, _index : usize, _line : &str) -> Result<(), String> {
+        Err(String::from("this controller doesn't have startup blocks"))
+    }
+
+    /// Every status received from the controller, oldest first.
+    fn status_history(&self) -> Arc<Mutex<StatusHistory>>;
+
+    /// Every line sent to and received from the controller.
+    fn traffic(&self) -> Arc<Mutex<TrafficLog>>;
+
+    /// Starts writing every byte exchanged with the controller to `path`.
+    fn start_recording(&self, path : &Path) -> io::Result<()>;
+
+    fn stop_recording(&self);
+}
+
+/// The endpoint that connects to a new virtual controller.
+pub const VIRTUAL_ENDPOINT : &str = "virtual://";
+
+/// A firmware that can be connected to.
+pub struct ControllerKind {
+    pub name : &'static str,
+    /// Connects to the controller at `endpoint`. The baud rate is only used by serial devices.
+    pub connect : fn(endpoint : String, baud_rate : u32, runtime : &Handle) -> Box<dyn Controller>,
+}
+
+/// The firmwares that can be picked when connecting, the first one being the default.
+pub const CONTROLLERS : [ControllerKind; 1] = [
+    ControllerKind {name : "GRBL", connect : crate::grbl::connect_grbl},
+];
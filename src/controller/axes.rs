@@ -0,0 +1,105 @@
+/*!
+ * This file contains the per-axis values reported by a controller. Most
+ * controllers report three axes, while some can be built with up to six: X, Y,
+ * Z, A, B and C.
+ */
+
+use std::ops::{Add, Index, IndexMut, Sub};
+
+/// The most axes a controller can report.
+pub const MAX_AXES : usize = 6;
+
+/// The number of axes assumed until the controller reports its own.
+pub const DEFAULT_AXIS_COUNT : usize = 3;
+
+/// The axis letters, in the order they are reported.
+pub const AXIS_NAMES : [char; MAX_AXES] = ['X', 'Y', 'Z', 'A', 'B', 'C'];
+
+/// A value for each of the axes in a report, e.g. a position or an offset.
+/// Indexing past the reported axes gives zero.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct AxisValues {
+    values : [f32; MAX_AXES],
+    count : usize,
+}
+
+impl AxisValues {
+    /// Takes a value for each axis, ignoring any past `MAX_AXES`.
+    pub fn new(values : &[f32]) -> Self {
+        let count = values.len().min(MAX_AXES);
+        let mut axes = Self {values : [0.0; MAX_AXES], count};
+        axes.values[..count].copy_from_slice(&values[..count]);
+        axes
+    }
+
+    pub fn zero(count : usize) -> Self {
+        Self {values : [0.0; MAX_AXES], count : count.min(MAX_AXES)}
+    }
+
+    /// The number of axes that were reported.
+    pub fn len(&self) -> usize {
+        self.count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    pub fn as_slice(&self) -> &[f32] {
+        &self.values[..self.count]
+    }
+
+    /// The value of each reported axis, together with the axis letter.
+    pub fn named(&self) -> impl Iterator<Item = (char, f32)> + '_ {
+        AXIS_NAMES.iter().copied().zip(self.as_slice().iter().copied())
+    }
+
+    pub fn xyz(&self) -> [f32; 3] {
+        [self.values[0], self.values[1], self.values[2]]
+    }
+}
+
+impl From<[f32; 3]> for AxisValues {
+    fn from(xyz : [f32; 3]) -> Self {
+        Self::new(&xyz)
+    }
+}
+
+impl Index<usize> for AxisValues {
+    type Output = f32;
+
+    fn index(&self, axis : usize) -> &f32 {
+        &self.values[axis]
+    }
+}
+
+impl IndexMut<usize> for AxisValues {
+    fn index_mut(&mut self, axis : usize) -> &mut f32 {
+        self.count = self.count.max(axis + 1);
+        &mut self.values[axis]
+    }
+}
+
+impl Add for AxisValues {
+    type Output = AxisValues;
+
+    fn add(mut self, other : AxisValues) -> AxisValues {
+        for i in 0..MAX_AXES {
+            self.values[i] += other.values[i];
+        }
+        self.count = self.count.max(other.count);
+        self
+    }
+}
+
+impl Sub for AxisValues {
+    type Output = AxisValues;
+
+    fn sub(mut self, other : AxisValues) -> AxisValues {
+        for i in 0..MAX_AXES {
+            self.values[i] -= other.values[i];
+        }
+        self.count = self.count.max(other.count);
+        self
+    }
+}
@@ -0,0 +1,91 @@
+/*!
+ * This file contains the history of status reports, which is kept so that the
+ * feed, spindle speed, buffer fill level and position can be charted over time.
+ */
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::{MachineState, MachineStatus};
+
+/// The number of samples kept before the oldest ones are dropped.
+pub const STATUS_HISTORY_CAPACITY : usize = 6_000;
+
+/// How often the controller is asked for a status report while the machine is moving.
+pub const ACTIVE_STATUS_INTERVAL : Duration = Duration::from_millis(100);
+
+/// How often the controller is asked for a status report while the machine is idle.
+pub const IDLE_STATUS_INTERVAL : Duration = Duration::from_millis(500);
+
+/// How often the controller is asked for a status report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PollRates {
+    /// while a program runs, or the machine jogs or homes
+    pub active : Duration,
+    /// otherwise
+    pub idle : Duration,
+}
+
+impl Default for PollRates {
+    fn default() -> Self {
+        Self {
+            active : ACTIVE_STATUS_INTERVAL,
+            idle : IDLE_STATUS_INTERVAL,
+        }
+    }
+}
+
+impl PollRates {
+    /// The interval to use when the machine is in `state`, and `streaming` is true while a program is sent.
+    pub fn interval(&self, state : MachineState, streaming : bool) -> Duration {
+        match state {
+            _ if streaming     => self.active,
+            MachineState::Run  |
+            MachineState::Jog  |
+            MachineState::Home => self.active,
+            _                  => self.idle,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct StatusSample {
+    /// time since the history was started
+    pub time : Duration,
+    pub status : MachineStatus,
+}
+
+pub struct StatusHistory {
+    start : Instant,
+    pub samples : VecDeque<StatusSample>,
+}
+
+impl StatusHistory {
+    pub fn new() -> Self {
+        Self {
+            start : Instant::now(),
+            samples : VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, status : MachineStatus) {
+        if self.samples.len() >= STATUS_HISTORY_CAPACITY {
+            self.samples.pop_front();
+        }
+
+        self.samples.push_back(StatusSample {
+            time : self.start.elapsed(),
+            status,
+        });
+    }
+
+    /// Returns the samples of the last `duration`, oldest first.
+    pub fn recent(&self, duration : Duration) -> impl Iterator<Item = &StatusSample> {
+        let since = self.start.elapsed().saturating_sub(duration);
+        self.samples.iter().filter(move |s| s.time >= since)
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+    }
+}
@@ -0,0 +1,149 @@
+/*!
+ * This file contains the settings a controller describes itself, with their
+ * names, units, types and limits, so the settings editor doesn't need to know
+ * them in advance.
+ */
+
+use std::collections::BTreeMap;
+use std::net::Ipv4Addr;
+
+/// The data types a controller reports for its settings.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExtendedSettingType {
+    Boolean,
+    /// any combination of the bits named in the format
+    Bitfield,
+    /// like `Bitfield`, but the other bits are ignored unless the first one is set
+    ExclusiveBitfield,
+    /// exactly one of the options named in the format
+    RadioButtons,
+    AxisMask,
+    Integer,
+    Decimal,
+    String,
+    Password,
+    IPv4,
+}
+
+/// A setting as described by the controller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExtendedSettingDescriptor {
+    pub setting : u32,
+    pub group : u32,
+    pub name : String,
+    pub unit : String,
+    pub kind : ExtendedSettingType,
+    /// the names of the bits or options for bitfields and radio buttons, otherwise a number format
+    pub format : String,
+    /// for strings and passwords, the limits are on the length
+    pub min : Option<f64>,
+    pub max : Option<f64>,
+}
+
+impl ExtendedSettingDescriptor {
+    /// The names of the bits of a bitfield, or of the options of radio buttons.
+    pub fn options(&self) -> Vec<&str> {
+        match self.kind {
+            ExtendedSettingType::Bitfield | ExtendedSettingType::ExclusiveBitfield | ExtendedSettingType::RadioButtons => {
+                self.format.split(',').map(|o| o.trim()).collect()
+            }
+            _ => vec![],
+        }
+    }
+
+    /// Checks that `value` is valid for this setting, and returns it in the format the controller expects.
+    pub fn validate(&self, value : &str) -> Result<String, String> {
+        let value = value.trim();
+
+        let in_range = |n : f64, what : &str| -> Result<(), String> {
+            match (self.min, self.max) {
+                (Some(min), _) if n < min => Err(format!("${} {} must be at least {}", self.setting, what, min)),
+                (_, Some(max)) if n > max => Err(format!("${} {} must be at most {}", self.setting, what, max)),
+                _ => Ok(()),
+            }
+        };
+
+        match self.kind {
+            ExtendedSettingType::String | ExtendedSettingType::Password => {
+                in_range(value.len() as f64, "length")?;
+                Ok(value.to_string())
+            }
+            ExtendedSettingType::IPv4 => {
+                value.parse::<Ipv4Addr>()
+                    .map(|ip| ip.to_string())
+                    .map_err(|_| format!("${} must be an IPv4 address", self.setting))
+            }
+            ExtendedSettingType::Decimal => {
+                let number = value.parse::<f64>()
+                    .map_err(|_| format!("${} must be a number", self.setting))?;
+                in_range(number, "value")?;
+                Ok(format!("{}", number))
+            }
+            _ => {
+                let number = value.parse::<u32>()
+                    .map_err(|_| format!("${} must be a whole number", self.setting))?;
+
+                let options = self.options().len() as u32;
+                let limit = match self.kind {
+                    ExtendedSettingType::Boolean => Some(1),
+                    ExtendedSettingType::RadioButtons if options > 0 => Some(options - 1),
+                    ExtendedSettingType::Bitfield | ExtendedSettingType::ExclusiveBitfield if options > 0 => {
+                        Some(((1u64 << options.min(32)) - 1) as u32)
+                    }
+                    _ => None,
+                };
+
+                if let Some(limit) = limit {
+                    if number > limit {
+                        return Err(format!("${} must be at most {}", self.setting, limit));
+                    }
+                }
+
+                in_range(number as f64, "value")?;
+                Ok(format!("{}", number))
+            }
+        }
+    }
+}
+
+/// A group of settings as described by the controller.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SettingGroup {
+    pub group : u32,
+    pub parent : u32,
+    pub name : String,
+}
+
+/// The settings of a controller, described by the controller itself.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ExtendedSettings {
+    /// the settings, in the order they were described
+    pub descriptors : Vec<ExtendedSettingDescriptor>,
+    /// the groups the settings are sorted into
+    pub groups : Vec<SettingGroup>,
+    /// the values of the settings, as text
+    pub values : BTreeMap<u32, String>,
+}
+
+impl ExtendedSettings {
+    pub fn describe(&mut self, descriptor : ExtendedSettingDescriptor) {
+        match self.descriptors.iter_mut().find(|d| d.setting == descriptor.setting) {
+            Some(d) => *d = descriptor,
+            None => self.descriptors.push(descriptor),
+        }
+    }
+
+    pub fn add_group(&mut self, group : SettingGroup) {
+        match self.groups.iter_mut().find(|g| g.group == group.group) {
+            Some(g) => *g = group,
+            None => self.groups.push(group),
+        }
+    }
+
+    pub fn group_name(&self, group : u32) -> String {
+        self.groups.iter()
+            .find(|g| g.group == group)
+            .map(|g| g.name.clone())
+            .unwrap_or_else(|| format!("Group {}", group))
+    }
+}
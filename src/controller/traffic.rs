@@ -0,0 +1,97 @@
+/*!
+ * This file contains the log of every line exchanged with the controller,
+ * which is shown in the console window and can be exported to a file.
+ */
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// The number of entries kept before the oldest ones are dropped.
+pub const TRAFFIC_LOG_CAPACITY : usize = 10_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficDirection {
+    Sent,
+    Received,
+    /// a single byte realtime command
+    Realtime,
+}
+
+/// What a logged line is, so that the console can filter and highlight it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrafficKind {
+    Other,
+    /// a status query or a status report, which are exchanged several times a second
+    Status,
+    /// an error or an alarm
+    Error,
+}
+
+#[derive(Debug, Clone)]
+pub struct TrafficEntry {
+    /// time since the log was started
+    pub time : Duration,
+    pub direction : TrafficDirection,
+    pub line : String,
+    pub kind : TrafficKind,
+}
+
+impl TrafficEntry {
+    /// Returns true for status queries and status reports, which are sent several times a second.
+    pub fn is_status(&self) -> bool {
+        self.kind == TrafficKind::Status
+    }
+
+    pub fn is_error(&self) -> bool {
+        self.kind == TrafficKind::Error
+    }
+
+    /// Formats the entry as a single line, e.g. `    1.250 >> G1 X10`.
+    pub fn to_log_line(&self) -> String {
+        let direction = match self.direction {
+            TrafficDirection::Sent     => ">>",
+            TrafficDirection::Received => "<<",
+            TrafficDirection::Realtime => ">!",
+        };
+        format!("{:>9.3} {} {}", self.time.as_secs_f32(), direction, self.line)
+    }
+}
+
+pub struct TrafficLog {
+    start : Instant,
+    pub entries : VecDeque<TrafficEntry>,
+}
+
+impl TrafficLog {
+    pub fn new() -> Self {
+        Self {
+            start : Instant::now(),
+            entries : VecDeque::new(),
+        }
+    }
+
+    pub fn push(&mut self, direction : TrafficDirection, line : &str, kind : TrafficKind) {
+        if self.entries.len() >= TRAFFIC_LOG_CAPACITY {
+            self.entries.pop_front();
+        }
+
+        self.entries.push_back(TrafficEntry {
+            time : self.start.elapsed(),
+            direction,
+            line : line.trim_end().to_string(),
+            kind,
+        });
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Formats every entry that passes `filter`, one per line.
+    pub fn export<F : Fn(&TrafficEntry) -> bool>(&self, filter : F) -> String {
+        self.entries.iter()
+            .filter(|e| filter(e))
+            .map(|e| e.to_log_line() + "\n")
+            .collect()
+    }
+}
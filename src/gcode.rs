@@ -30,8 +30,13 @@ impl GCodeLine<'_> {
 }
 
 pub fn parse<'i>(program : &'i str) -> Vec<GCodeLine<'i>> {
+    try_parse(program).unwrap()
+}
+
+/// Like `parse`, but returns an error instead of panicking when the program is not valid G-code.
+pub fn try_parse<'i>(program : &'i str) -> Result<Vec<GCodeLine<'i>>, pest::error::Error<Rule>> {
 
-    GCodeParser::parse(Rule::file, program).unwrap()
+    Ok(GCodeParser::parse(Rule::file, program)?
         .map(|l| {
 
             let line = l.as_str();
@@ -55,5 +60,5 @@ pub fn parse<'i>(program : &'i str) -> Vec<GCodeLine<'i>> {
             }
 
         })
-        .collect::<Vec<_>>()
+        .collect::<Vec<_>>())
 }
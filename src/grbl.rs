@@ -5,10 +5,27 @@ mod error;
 mod msgs;
 mod state;
 mod gcode_task;
+mod settings;
+mod startup;
+mod transport;
+mod virtual_grbl;
+mod recording;
+mod dialect;
+mod grbl_controller;
+mod tool_change;
 
+use crate::controller::{AXIS_NAMES, AxisValues, ConnectionState, DEFAULT_AXIS_COUNT, ExtendedSettingDescriptor, ExtendedSettings, ExtendedSettingType, GCodeOffsets, GCodeParserState, InputPins, MachineState, MAX_AXES, ParserDistanceMode, ParserFeedRateMode, ParserMotionMode, ParserPlane, ParserProgramMode, ParserSpindleState, ParserUnits, PollRates, ProbeResult, ProgramProgress, SettingGroup, StatusHistory, StreamingMode, TrafficDirection, TrafficKind, TrafficLog, VIRTUAL_ENDPOINT};
 
 pub use connection::*;
 pub use error::*;
 pub use msgs::*;
 pub use state::*;
 pub use gcode_task::*;
+pub use settings::*;
+pub use startup::*;
+pub use transport::*;
+pub use virtual_grbl::*;
+pub use recording::*;
+pub use dialect::*;
+pub use grbl_controller::*;
+pub use tool_change::*;
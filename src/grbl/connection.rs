@@ -1,266 +1,542 @@
 /*!
  * 
  * This file contains the logic to communicate with GRBL and keep an updated state.
+ * The connection doesn't do any I/O itself: bytes received from GRBL are passed to
+ * `receive`, and the bytes to send are collected with `take_output`.
  * 
  */
 
-use std::time::Duration;
-use pest::Parser;
-use serialport::SerialPort;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::io;
+use std::sync::{Arc, Mutex};
+use bytes::BytesMut;
+use tokio::sync::broadcast;
+use tokio_util::codec::Decoder;
 
 use super::*;
 
+/// A line that has been written to GRBL, but not yet answered with `ok` or `error`.
+#[derive(Debug, Clone)]
+pub struct PendingLine {
+    pub message : String,
+    pub program_line : Option<usize>,
+    /// set for the lines the sender adds on its own, e.g. to change tools, which keeps track of their effects itself
+    pub internal : bool,
+}
+
+/// Splits GRBL's responses into lines, including the line ending.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GRBLLineCodec;
+
+impl Decoder for GRBLLineCodec {
+    type Item = String;
+    type Error = io::Error;
+
+    fn decode(&mut self, src : &mut BytesMut) -> Result<Option<String>, io::Error> {
+        match src.iter().position(|&c| c == b'\n') {
+            Some(end) => {
+                let line = src.split_to(end + 1);
+                // line noise, e.g. while the controller resets, must not end the connection
+                Ok(Some(String::from_utf8_lossy(&line).into_owned()))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
 pub struct GRBLConnection {
-    pub port : Box<dyn SerialPort>,
     pub machine_status : GRBLStatus,
-    pub alarm : Option<u8>,
     pub settings : Option<GRBLSettings>,
-    pub read_buffer : Vec<u8>,
+    /// set whenever `settings` is updated
+    pub settings_changed : bool,
+    pub read_buffer : BytesMut,
+    pub codec : GRBLLineCodec,
     pub write_buffer : Vec<u8>,
     pub ready : bool,
     pub error : bool,
+    /// lines sent to GRBL, oldest first, that are still waiting for a response
+    pub pending : VecDeque<PendingLine>,
+    /// lines that have been answered, together with the response, oldest first
+    pub completed : VecDeque<(PendingLine, Result<(), GRBLError>)>,
+    /// parser state, offsets, probe result and build info reported by GRBL
+    pub info : GRBLInfo,
+    /// set whenever `info` is updated
+    pub info_changed : bool,
+    /// set when a command changed the offsets, which are fetched again once GRBL is idle
+    pub offsets_stale : bool,
+    /// the version in the last welcome message, `None` until GRBL has announced itself
+    pub version : Option<String>,
+    /// set whenever GRBL announces itself, which it does after every reset
+    pub reset : bool,
+    /// set once a status report has been received
+    pub status_received : bool,
+    /// set whenever `machine_status` is updated
+    pub status_changed : bool,
+    /// every message received from GRBL is published here
+    pub messages : broadcast::Sender<GRBLMessage>,
+    /// every line sent and received is recorded here
+    pub traffic : Arc<Mutex<TrafficLog>>,
+    /// if set, every byte sent and received is written to a recording
+    pub recorder : Option<SessionRecorder>,
 }
 
 use std::error::Error;
 
-impl GRBLConnection {
-    pub fn open(path : &str, baud_rate : u32) -> Result<Self, Box<dyn Error>> {
+/// The G codes that select a coordinate system or change a stored offset.
+const OFFSET_CODES : [f32; 13] = [10.0, 28.1, 30.1, 43.1, 49.0, 54.0, 55.0, 56.0, 57.0, 58.0, 59.0, 92.0, 92.1];
+
+/// Returns true if the line selects a coordinate system or changes a stored offset.
+fn changes_offsets(line : &str) -> bool {
+    // system commands, like jogs, don't change offsets
+    if line.trim_start().starts_with('$') {
+        return false;
+    }
+
+    // GRBL ignores spaces, so a word runs from one letter to the next
+    let mut words : Vec<String> = vec![];
+    let mut in_comment = false;
+    for c in line.chars() {
+        match c {
+            '(' => in_comment = true,
+            ')' => in_comment = false,
+            ';' if !in_comment => break,
+            _ if in_comment || c.is_whitespace() => {}
+            _ if c.is_ascii_alphabetic() => words.push(c.to_ascii_uppercase().to_string()),
+            _ => if let Some(word) = words.last_mut() { word.push(c); },
+        }
+    }
 
-        let mut port = serialport::new(path, baud_rate)
-            .timeout(Duration::from_millis(100))
-            .open()?;
+    words.iter()
+        .filter_map(|w| w.strip_prefix('G')?.parse::<f32>().ok())
+        .any(|code| OFFSET_CODES.contains(&code))
+}
 
-        port.set_timeout(Duration::from_millis(1)).unwrap();
+/// How a received message is marked in the traffic log.
+fn traffic_kind(msg : &GRBLMessage) -> TrafficKind {
+    match msg {
+        GRBLMessage::StatusMessage(_) => TrafficKind::Status,
+        GRBLMessage::Error(_) |
+        GRBLMessage::AlarmMessage(_) |
+        GRBLMessage::StartupLine{result : Err(_), ..} => TrafficKind::Error,
+        _ => TrafficKind::Other,
+    }
+}
 
-        Ok(Self {
-            port,
+impl GRBLConnection {
+    pub fn new(messages : broadcast::Sender<GRBLMessage>, traffic : Arc<Mutex<TrafficLog>>) -> Self {
+        Self {
             machine_status : GRBLStatus::default(),
-            alarm : None,
             settings : None,
-            read_buffer : vec![],
+            settings_changed : false,
+            read_buffer : BytesMut::new(),
+            codec : GRBLLineCodec,
             write_buffer : vec![],
             ready : true,
             error : false,
-        })
+            pending : VecDeque::new(),
+            completed : VecDeque::new(),
+            info : GRBLInfo::default(),
+            info_changed : false,
+            offsets_stale : false,
+            version : None,
+            reset : false,
+            status_received : false,
+            status_changed : false,
+            messages,
+            traffic,
+            recorder : None,
+        }
+    }
+
+    fn record(&mut self, direction : RecordDirection, bytes : &[u8]) {
+        if let Some(recorder) = self.recorder.as_mut() {
+            if let Err(e) = recorder.record(direction, bytes) {
+                log::warn!("stopped recording: {}", e);
+                self.recorder = None;
+            }
+        }
     }
 
     pub fn send_message(&mut self, msg : String) -> Result<(), Box<dyn Error>> {
+        self.send_line(msg, None, false)
+    }
+
+    /// Sends a line of the running program, so that the response can be matched back to `line`.
+    pub fn send_program_line(&mut self, msg : String, line : usize) -> Result<(), Box<dyn Error>> {
+        self.send_line(msg, Some(line), false)
+    }
+
+    /// Sends a line the sender adds on its own, whose changes to the offsets aren't fetched again.
+    pub fn send_internal_line(&mut self, msg : String) -> Result<(), Box<dyn Error>> {
+        self.send_line(msg, None, true)
+    }
+
+    fn send_line(&mut self, msg : String, program_line : Option<usize>, internal : bool) -> Result<(), Box<dyn Error>> {
 
         self.ready = false;
+        self.traffic.lock().unwrap().push(TrafficDirection::Sent, &msg, TrafficKind::Other);
         self.write_buffer.extend(msg.bytes());
+        self.pending.push_back(PendingLine {
+            message : msg,
+            program_line,
+            internal,
+        });
 
         Ok(())
     }
 
-    pub fn poll(&mut self) -> Result<(), Box<dyn Error>> {
+    pub fn send_command(&mut self, cmd : GRBLCommand) -> Result<(), Box<dyn Error>> {
+        self.send_message(String::from_utf8(cmd.to_bytes())?)
+    }
 
-        // read up to 1024 chars
-        let mut buf = [0;1024];
-        let n = match self.port.read(&mut buf) {
-            Ok(n) => {n}
-            Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {0}
-            Err(e) => {return Err(e.into());}
-        };
+    /// The number of bytes sent to GRBL that have not been acknowledged yet, i.e. the number of
+    /// bytes that may still be occupying GRBL's serial receive buffer.
+    pub fn pending_bytes(&self) -> usize {
+        self.pending.iter().map(|l| l.message.len()).sum()
+    }
 
-        let mut read_buffer = core::mem::replace(&mut self.read_buffer, vec![]);
+    /// Handles bytes received from GRBL. Every complete line is passed to `handle_message`.
+    pub fn receive(&mut self, bytes : &[u8]) {
+        self.record(RecordDirection::Received, bytes);
+        self.read_buffer.extend_from_slice(bytes);
 
-        read_buffer.extend_from_slice(&buf[..n]);
+        while let Ok(Some(line)) = self.codec.decode(&mut self.read_buffer) {
+            self.handle_message(&line);
+        }
+    }
 
-        //check if the message is complete
-        if let Some(n) = self.handle_message(std::str::from_utf8(&read_buffer)?) {
-            read_buffer.drain(0..n);
+    /// Returns the bytes waiting to be written to GRBL.
+    pub fn take_output(&mut self) -> Vec<u8> {
+        let output = std::mem::take(&mut self.write_buffer);
+        if !output.is_empty() {
+            log::trace!("sent: {:?}", String::from_utf8_lossy(&output));
         }
+        self.record(RecordDirection::Sent, &output);
+        output
+    }
 
-        self.read_buffer = read_buffer;
+    /// Creates a new receiver for the messages received from GRBL.
+    pub fn subscribe(&self) -> broadcast::Receiver<GRBLMessage> {
+        self.messages.subscribe()
+    }
 
-        if self.write_buffer.len() > 0 {
+    pub fn execute_realtime_command(&mut self, command : GRBLRealtimeCommand) {
+        let kind = if matches!(command, GRBLRealtimeCommand::StatusQuery) {TrafficKind::Status} else {TrafficKind::Other};
+        self.traffic.lock().unwrap().push(TrafficDirection::Realtime, &format!("{:?}", command), kind);
+        // realtime commands are picked out of the stream by GRBL, so they can skip ahead of queued lines
+        self.write_buffer.insert(0, command as u8);
+    }
 
-            // write message if available
-            let n = match self.port.write(&self.write_buffer) {
-                Ok(n) => {n}
-                Err(e) if e.kind() == std::io::ErrorKind::TimedOut => {0}
-                Err(e) => {return Err(e.into());}
-            };
+    /// Switches to `dialect`. The settings of grblHAL are described by the controller itself,
+    /// so they are fetched as soon as grblHAL is detected.
+    fn set_dialect(&mut self, dialect : GRBLDialect) {
+        if self.info.dialect == dialect {
+            return;
+        }
 
-            // self.write_buffer.drain(0..n);
-            let sent = self.write_buffer.drain(0..n).collect::<Vec<_>>();
+        log::info!("detected {:?}.", dialect);
 
-            println!("sent: {:?}", std::str::from_utf8(&sent).unwrap());
-        }
+        self.info.dialect = dialect;
+        self.info_changed = true;
 
-        Ok(())
+        if dialect == GRBLDialect::GrblHAL {
+            let _ = self.send_command(GRBLCommand::QuerySettingGroups);
+            let _ = self.send_command(GRBLCommand::QueryExtendedSettings);
+        }
     }
 
-    pub fn execute_realtime_command(&mut self, command : GRBLRealtimeCommand) {
-        self.port.write_all(&[command as u8]).unwrap();
+    /// Fetches the parser state and the offsets once they are stale and GRBL is idle, so that
+    /// the queries don't end up between the lines of a stream of commands.
+    fn refresh_offsets(&mut self) {
+        if self.offsets_stale && self.pending.is_empty() && self.machine_status.state == MachineState::Idle {
+            self.offsets_stale = false;
+            let _ = self.send_command(GRBLCommand::QueryParserState);
+            let _ = self.send_command(GRBLCommand::QueryGCodeParameters);
+        }
     }
 
-    pub fn handle_message(&mut self, s : &str) -> Option<usize> {
-        
-        if let Ok(mut parsed) = GRBLParser::parse(Rule::line, s) {
+    /// Parses a line received from GRBL, updates the connection state with it and
+    /// publishes it to every subscriber of `messages`.
+    pub fn handle_message(&mut self, s : &str) -> Option<GRBLMessage> {
 
-            if let Some(msg) = parsed.next() {
+        let msg = GRBLMessage::parse(s)?;
 
-                let consumed = msg.as_span().end();
+        self.traffic.lock().unwrap().push(TrafficDirection::Received, s, traffic_kind(&msg));
 
-                if msg.as_rule() == Rule::unrecognized_message && msg.as_str() != "\r\n" {
-                    println!("received unrecognized message: {:?}: {:?}", msg.as_rule(), msg.as_str());
-                }
+        match msg {
+            GRBLMessage::Ok | GRBLMessage::Error(_) => {
+                let result = match msg {
+                    GRBLMessage::Error(error) => {
+                        self.error = true;
+                        Err(error)
+                    }
+                    _ => Ok(()),
+                };
 
-                match msg.as_rule() {
-                    Rule::response_message => {
-                        let msg = msg.into_inner().next()?;
-                        self.ready = true; 
-                        match msg.as_rule() {
-                            Rule::ok => {}
-                            Rule::error => {
-                                // log the error type
-                            }
-                            _ => unreachable!()
-                        }
+                // GRBL answers lines in order, so the response belongs to the oldest pending line
+                if let Some(line) = self.pending.pop_front() {
+
+                    // keep the reported offsets up to date when they are changed by a command
+                    if result.is_ok() && line.program_line.is_none() && !line.internal && changes_offsets(&line.message) {
+                        self.offsets_stale = true;
+                    }
+
+                    self.completed.push_back((line, result));
+                }
+                self.ready = self.pending.is_empty();
+                self.refresh_offsets();
+            }
+            GRBLMessage::StatusMessage(ref report) => {
+                self.machine_status.update(report);
+                self.status_received = true;
+                self.status_changed = true;
+                self.refresh_offsets();
+            }
+            GRBLMessage::FeedbackMessage(ref feedback) => {
+                match *feedback {
+                    GRBLFeedback::ParserState(state)                 => {self.info.parser_state = Some(state);}
+                    GRBLFeedback::Offset(offset, value)              => {self.info.offsets.set(offset, value);}
+                    GRBLFeedback::ToolLengthOffset(tlo)              => {self.info.offsets.tool_length_offset = tlo;}
+                    GRBLFeedback::Probe(probe)                       => {self.info.probe = Some(probe);}
+                    GRBLFeedback::Version(ref build_info)            => {self.info.build_info = Some(build_info.clone());}
+                    GRBLFeedback::Options(ref build_options)         => {self.info.build_options = Some(build_options.clone());}
+                    GRBLFeedback::SettingGroup(ref group)            => {self.info.extended_settings.add_group(group.clone());}
+                    GRBLFeedback::SettingDescription(ref descriptor) => {self.info.extended_settings.describe(descriptor.clone());}
+                    GRBLFeedback::RestoringSpindle => {
+                        self.machine_status.restoring_spindle = true;
+                        self.status_changed = true;
                     }
-                    Rule::push_message => {
-                        let msg = msg.into_inner().next()?;
-                        match msg.as_rule() {
-                            Rule::status_message => {
-                                let msg_str = msg.as_str();
-                                for item in msg.into_inner() {
-                                    match item.as_rule() {
-                                        Rule::mstate       => {
-                                            let inner = item.into_inner().next().unwrap();
-                                            let last = inner.as_str().chars().last().unwrap();
-                                            match inner.as_rule() {
-                                                Rule::idle  => {self.machine_status.state = GRBLState::Idle}
-                                                Rule::run   => {self.machine_status.state = GRBLState::Run}
-                                                Rule::hold  => {self.machine_status.state = GRBLState::Hold(last == '1')}
-                                                Rule::jog   => {self.machine_status.state = GRBLState::Jog}
-                                                Rule::alarm => {self.machine_status.state = GRBLState::Alarm}
-                                                Rule::door  => {self.machine_status.state = GRBLState::Door(last as u8 - b'0')}
-                                                Rule::check => {self.machine_status.state = GRBLState::Check}
-                                                Rule::home  => {self.machine_status.state = GRBLState::Home}
-                                                Rule::sleep => {self.machine_status.state = GRBLState::Sleep}
-                                                _ => unreachable!()
-                                            }
-                                        }
-                                        Rule::mpos         => {
-                                            let mut inner = item.into_inner();
-                                            let x = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            let y = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            let z = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-
-                                            self.machine_status.machine_position = [x,y,z];
-                                        }
-                                        Rule::wpos         => {
-                                            let mut inner = item.into_inner();
-                                            let x = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            let y = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            let z = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-
-                                            let [wx,wy,wz] = self.machine_status.work_offset;
-
-                                            self.machine_status.machine_position = [x+wx,y+wy,z+wz];
-                                        }
-                                        Rule::wco          => {
-                                            let mut inner = item.into_inner();
-                                            let x = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            let y = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            let z = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            
-                                            self.machine_status.work_offset = [x,y,z];
-                                        }
-                                        Rule::buffer_state => {
-                                            let mut inner = item.into_inner();
-                                            let blocks = inner.next().unwrap().as_str().parse::<u32>().unwrap();
-                                            let bytes = inner.next().unwrap().as_str().parse::<u32>().unwrap();
-
-                                            self.machine_status.buffer_free_blocks = blocks;
-                                            self.machine_status.buffer_free_bytes = bytes;
-                                        }
-                                        Rule::line_number  => {
-                                            let mut inner = item.into_inner();
-                                            let n = inner.next().unwrap().as_str().parse::<u32>().unwrap();
-
-                                            self.machine_status.line_number = n;
-                                        }
-                                        Rule::feed         => {
-                                            let mut inner = item.into_inner();
-                                            let feed = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-
-                                            self.machine_status.feed = feed;
-                                        }
-                                        Rule::feed_and_speed => {
-                                            let mut inner = item.into_inner();
-                                            let feed = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-                                            let speed = inner.next().unwrap().as_str().parse::<f32>().unwrap();
-
-                                            self.machine_status.feed = feed;
-                                            self.machine_status.speed = speed;
-                                        }
-                                        Rule::inputs       => {}
-                                        Rule::overrides    => {
-                                            let mut inner = item.into_inner();
-                                            let f = inner.next().unwrap().as_str().parse::<u32>().unwrap();
-                                            let r = inner.next().unwrap().as_str().parse::<u32>().unwrap();
-                                            let s = inner.next().unwrap().as_str().parse::<u32>().unwrap();
-
-                                            self.machine_status.override_feed = f;
-                                            self.machine_status.override_rapid = r;
-                                            self.machine_status.override_speed = s;
-                                        }
-                                        Rule::accessories  => {
-
-                                            self.machine_status.spindle_cw = false;
-                                            self.machine_status.spindle_ccw = false;
-                                            self.machine_status.flood_coolant = false;
-                                            self.machine_status.mist_coolant = false;
-
-                                            for acc in msg_str.chars().skip(2) {
-                                                match acc {
-                                                    'S' => {self.machine_status.spindle_cw = true;}
-                                                    'C' => {self.machine_status.spindle_ccw = true;}
-                                                    'F' => {self.machine_status.flood_coolant = true;}
-                                                    'M' => {self.machine_status.mist_coolant = true;}
-                                                    _ => {}
-                                                }
-                                            }
-                                        }
-                                        _ => unreachable!()
-                                    }
-                                }
-                            }
-                            Rule::feedback_message => {}
-                            Rule::alarm_message => {
-                                let last = msg.as_str().chars().last().unwrap() as u8;
-                                self.alarm = Some(last - b'0');
-                            }
-                            Rule::startup_line => {
-                                //log
-                                println!("received GBRL startup.");
-                            }
-                            Rule::welcome_message => {
-                                println!("Received GRBL welcome message.")
-                            }
-                            Rule::settings_message => {
-                                let mut inner = msg.into_inner();
-                                if let (Some(s), Some(v)) = (inner.next(), inner.next()) {
-                                    if let Some(ref mut settings) = self.settings {
-                                        if let Ok(s) = s.as_str().parse::<u8>() {
-                                            settings.parse_setting(s, v.as_str());
-                                        }
-                                    }
-                                }
-                            }
-                            _ => unreachable!()
+                    GRBLFeedback::ResetToContinue => {
+                        self.machine_status.reset_required = true;
+                        self.status_changed = true;
+                    }
+                    GRBLFeedback::Firmware(ref firmware) => {
+                        // grblHAL can be configured to announce itself as plain GRBL, but `$I` always tells
+                        if firmware.eq_ignore_ascii_case("grblHAL") {
+                            self.set_dialect(GRBLDialect::GrblHAL);
                         }
                     }
                     _ => {}
                 }
-                
-                return Some(consumed);
+                self.info_changed = true;
+            }
+            GRBLMessage::AlarmMessage(alarm) => {
+                log::warn!("{}", alarm);
+                self.machine_status.alarm = Some(alarm);
+                self.machine_status.state = MachineState::Alarm;
+                self.status_changed = true;
+            }
+            GRBLMessage::StartupLine{ref line, ref result} => {
+                self.info.startup_results.push((line.clone(), result.clone()));
+                self.info_changed = true;
+            }
+            GRBLMessage::StartupBlock{index, ref line} => {
+                if let Some(block) = self.info.startup_blocks.get_mut(index) {
+                    *block = Some(line.clone());
+                    self.info_changed = true;
+                }
+            }
+            GRBLMessage::WelcomeMessage{ref version, dialect} => {
+                log::info!("received {:?} welcome message.", dialect);
+
+                self.version = Some(version.clone());
+                self.reset = true;
+
+                // GRBL was reset, so anything still in flight has been discarded
+                self.pending.clear();
+                self.ready = true;
+
+                // the alarm stays until it is unlocked, but the reset it asked for is done
+                self.machine_status.reset_required = false;
+                self.status_changed = true;
+
+                // the startup blocks are run right after the welcome message
+                self.info.startup_results.clear();
+                self.info_changed = true;
+
+                // the parser state is reset too, so fetch it and the stored offsets again
+                let _ = self.send_command(GRBLCommand::QueryParserState);
+                let _ = self.send_command(GRBLCommand::QueryGCodeParameters);
+
+                // the build options tell which features GRBL has and how large its buffer is
+                let _ = self.send_command(GRBLCommand::QueryBuildInfo);
+
+                self.set_dialect(dialect);
+            }
+            GRBLMessage::SettingsMessage{setting, ref value} => {
+                // grblHAL has settings that don't fit `GRBLSettings`, so keep every value as text too
+                self.info.extended_settings.values.insert(setting, value.clone());
+                self.info_changed = true;
+
+                let settings = self.settings.get_or_insert_with(GRBLSettings::default);
+                if let Ok(s) = u8::try_from(setting) {
+                    if let Err(e) = settings.parse_setting(s, value) {
+                        log::warn!("invalid value for setting ${}: {:?}: {}", setting, value, e);
+                    }
+                }
+                self.settings_changed = true;
+            }
+            GRBLMessage::Unrecognized(ref text) => {
+                log::warn!("received unrecognized message: {:?}", text);
             }
         }
-        None
+
+        // there may be no subscribers, which is fine
+        let _ = self.messages.send(msg.clone());
+
+        Some(msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind, Read, Write};
+    use std::time::{Duration, Instant};
+
+    use super::*;
+
+    /// A connection talking to a virtual controller.
+    struct VirtualSession {
+        connection : GRBLConnection,
+        transport : LoopbackTransport,
     }
 
+    impl VirtualSession {
+        fn open() -> Self {
+            let (messages, _) = broadcast::channel(1024);
+            let mut session = Self {
+                connection : GRBLConnection::new(messages, Arc::new(Mutex::new(TrafficLog::new()))),
+                transport : spawn_virtual_grbl(),
+            };
+
+            session.pump_until(|c| c.version.is_some() && c.pending.is_empty());
+            session.connection.completed.clear();
+            session
+        }
+
+        /// Exchanges bytes with the controller until `done` holds, failing after a few seconds.
+        fn pump_until(&mut self, done : impl Fn(&GRBLConnection) -> bool) {
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let mut buf = [0; 1024];
+
+            while !done(&self.connection) {
+                assert!(Instant::now() < deadline, "timed out waiting for the virtual controller");
+
+                let output = self.connection.take_output();
+                if !output.is_empty() {
+                    self.transport.write_all(&output).unwrap();
+                }
+
+                match self.transport.read(&mut buf) {
+                    Ok(n) => self.connection.receive(&buf[..n]),
+                    Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                    Err(e) => panic!("virtual controller closed: {}", e),
+                }
+            }
+        }
 
-}
\ No newline at end of file
+        /// Sends a line and returns the response to it. Lines that change the offsets are followed
+        /// by the queries that fetch them again, which are answered too.
+        fn send(&mut self, line : &str) -> Result<(), GRBLError> {
+            self.connection.send_message(format!("{}\n", line)).unwrap();
+            self.pump_until(|c| c.pending.is_empty());
+
+            let (_, result) = self.connection.completed.iter().rev()
+                .find(|(sent, _)| sent.message.trim_end() == line)
+                .unwrap();
+            *result
+        }
+
+        fn status(&mut self) -> GRBLStatus {
+            self.connection.status_received = false;
+            self.connection.execute_realtime_command(GRBLRealtimeCommand::StatusQuery);
+            self.pump_until(|c| c.status_received);
+            self.connection.machine_status.clone()
+        }
+    }
+
+    #[test]
+    fn offset_changes_match_whole_codes() {
+        assert!(changes_offsets("G10 L20 P1 X0\n"));
+        assert!(changes_offsets("g0 g55 x1\n"));
+        assert!(changes_offsets("G 92 X0\n"));
+        assert!(changes_offsets("G92.1\n"));
+        assert!(!changes_offsets("G540\n"));
+        assert!(!changes_offsets("G1 X10 (G92)\n"));
+        assert!(!changes_offsets("G1 X10 ; G54\n"));
+        assert!(!changes_offsets("$J=G91 G21 X10 F100\n"));
+        assert!(!changes_offsets("$#\n"));
+    }
+
+    #[test]
+    fn virtual_controller_answers_lines() {
+        let mut session = VirtualSession::open();
+
+        assert_eq!(session.status().state, MachineState::Idle);
+        assert_eq!(session.send("G21 G90"), Ok(()));
+        assert!(session.send("G0 X").is_err());
+        assert!(session.connection.error);
+        assert_eq!(session.send("G1 X1 F100"), Ok(()));
+    }
+
+    #[test]
+    fn virtual_controller_reports_settings() {
+        let mut session = VirtualSession::open();
+        session.connection.settings = None;
+
+        assert_eq!(session.send("$$"), Ok(()));
+
+        let settings = session.connection.settings.as_ref().unwrap();
+        assert_eq!(settings.step_pulse_micros, 10);
+        assert_eq!(settings.max_rate[0], 500.0);
+    }
+
+    #[test]
+    fn virtual_controller_checks_without_moving() {
+        let mut session = VirtualSession::open();
+
+        assert_eq!(session.send("$C"), Ok(()));
+        assert_eq!(session.status().state, MachineState::Check);
+
+        assert_eq!(session.send("G0 X10 Y10"), Ok(()));
+        let status = session.status();
+        assert_eq!(status.state, MachineState::Check);
+        assert_eq!(status.machine_position[0], 0.0);
+        assert_eq!(status.machine_position[1], 0.0);
+    }
+
+    #[test]
+    fn virtual_controller_keeps_offsets() {
+        let mut session = VirtualSession::open();
+
+        assert_eq!(session.send("G10 L2 P2 X-10 Y-20"), Ok(()));
+        assert_eq!(session.send("G55"), Ok(()));
+        assert_eq!(session.send("G92 X5"), Ok(()));
+
+        assert_eq!(session.send("$#"), Ok(()));
+        let offsets = session.connection.info.offsets;
+        assert_eq!(offsets.work_coordinates[0][0], 0.0);
+        assert_eq!(offsets.work_coordinates[1][0], -10.0);
+        assert_eq!(offsets.work_coordinates[1][1], -20.0);
+        assert_eq!(offsets.g92[0], 5.0);
+
+        assert_eq!(session.send("$G"), Ok(()));
+        assert_eq!(session.connection.info.parser_state.unwrap().coordinate_system, 1);
+
+        let status = session.status();
+        assert_eq!(status.work_offset[0], -5.0);
+        assert_eq!(status.work_offset[1], -20.0);
+    }
+
+    #[test]
+    fn virtual_controller_limits_every_axis() {
+        let mut session = VirtualSession::open();
+
+        assert_eq!(session.send("$133=90"), Ok(()));
+        assert_eq!(session.send("$20=1"), Ok(()));
+
+        assert_eq!(session.send("$J=G91 A-10 F100"), Ok(()));
+        assert_eq!(session.send("$J=G91 A20 F100"), Err(GRBLError::TravelExceeded));
+    }
+}
@@ -0,0 +1,70 @@
+/*!
+ * This file contains the dialects of the GRBL protocol, and the settings
+ * enumeration of grblHAL. grblHAL describes each of its settings in response
+ * to `$ES`, e.g.
+ *
+ *     [SETTING:0|1|Step pulse time|microseconds|6|#0.0|2.0|]
+ *
+ * with the fields id, group, name, unit, data type, format, min and max, so
+ * the settings editor doesn't need to know them in advance.
+ */
+
+use crate::controller::{ExtendedSettingDescriptor, ExtendedSettingType, SettingGroup};
+
+/// The firmware on the other end of the connection.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum GRBLDialect {
+    #[default]
+    Grbl,
+    /// grblHAL, detected from the welcome message or from `[FIRMWARE:grblHAL]` in the `$I` response
+    GrblHAL,
+}
+
+impl ExtendedSettingType {
+    fn from_code(code : u32) -> Option<Self> {
+        Some(match code {
+            0 => Self::Boolean,
+            1 => Self::Bitfield,
+            2 => Self::ExclusiveBitfield,
+            3 => Self::RadioButtons,
+            4 => Self::AxisMask,
+            5 => Self::Integer,
+            6 => Self::Decimal,
+            7 => Self::String,
+            8 => Self::Password,
+            9 => Self::IPv4,
+            _ => return None,
+        })
+    }
+}
+
+impl ExtendedSettingDescriptor {
+    /// Parses the text of a `[SETTING:...]` message following the `SETTING:` prefix.
+    pub fn parse(s : &str) -> Option<Self> {
+        let mut fields = s.split('|');
+        let mut next = || fields.next().unwrap_or("").trim();
+
+        let setting = next().parse::<u32>().ok()?;
+        let group = next().parse::<u32>().ok()?;
+        let name = next().to_string();
+        let unit = next().to_string();
+        let kind = ExtendedSettingType::from_code(next().parse::<u32>().ok()?)?;
+        let format = next().to_string();
+        let min = next().parse::<f64>().ok();
+        let max = next().parse::<f64>().ok();
+
+        Some(Self {setting, group, name, unit, kind, format, min, max})
+    }
+}
+
+impl SettingGroup {
+    /// Parses the text of a `[SETTINGGROUP:...]` message following the `SETTINGGROUP:` prefix.
+    pub fn parse(s : &str) -> Option<Self> {
+        let mut fields = s.split('|');
+        Some(Self {
+            group : fields.next()?.trim().parse::<u32>().ok()?,
+            parent : fields.next()?.trim().parse::<u32>().ok()?,
+            name : fields.next()?.trim().to_string(),
+        })
+    }
+}
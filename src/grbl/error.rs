@@ -1,18 +1,283 @@
+use crate::controller::AlarmRecovery;
 
-#[derive(Debug, Clone)]
+/// An error code sent by GRBL in an `error:x` response.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum GRBLError {
-    
+    ExpectedCommandLetter,
+    BadNumberFormat,
+    InvalidStatement,
+    NegativeValue,
+    SettingDisabled,
+    SettingStepPulseMin,
+    SettingReadFail,
+    IdleError,
+    SystemGcLock,
+    SoftLimitError,
+    Overflow,
+    MaxStepRateExceeded,
+    CheckDoor,
+    LineLengthExceeded,
+    TravelExceeded,
+    InvalidJogCommand,
+    SettingDisabledLaser,
+    GcodeUnsupportedCommand,
+    GcodeModalGroupViolation,
+    GcodeUndefinedFeedRate,
+    GcodeCommandValueNotInteger,
+    GcodeAxisCommandConflict,
+    GcodeWordRepeated,
+    GcodeNoAxisWords,
+    GcodeInvalidLineNumber,
+    GcodeValueWordMissing,
+    GcodeUnsupportedCoordSys,
+    GcodeG53InvalidMotionMode,
+    GcodeAxisWordsExist,
+    GcodeNoAxisWordsInPlane,
+    GcodeInvalidTarget,
+    GcodeArcRadiusError,
+    GcodeNoOffsetsInPlane,
+    GcodeUnusedWords,
+    GcodeG43DynamicAxisError,
+    GcodeMaxValueExceeded,
+    Unknown(u8),
+}
+
+impl GRBLError {
+    const ALL : [GRBLError; 36] = [
+        GRBLError::ExpectedCommandLetter,
+        GRBLError::BadNumberFormat,
+        GRBLError::InvalidStatement,
+        GRBLError::NegativeValue,
+        GRBLError::SettingDisabled,
+        GRBLError::SettingStepPulseMin,
+        GRBLError::SettingReadFail,
+        GRBLError::IdleError,
+        GRBLError::SystemGcLock,
+        GRBLError::SoftLimitError,
+        GRBLError::Overflow,
+        GRBLError::MaxStepRateExceeded,
+        GRBLError::CheckDoor,
+        GRBLError::LineLengthExceeded,
+        GRBLError::TravelExceeded,
+        GRBLError::InvalidJogCommand,
+        GRBLError::SettingDisabledLaser,
+        GRBLError::GcodeUnsupportedCommand,
+        GRBLError::GcodeModalGroupViolation,
+        GRBLError::GcodeUndefinedFeedRate,
+        GRBLError::GcodeCommandValueNotInteger,
+        GRBLError::GcodeAxisCommandConflict,
+        GRBLError::GcodeWordRepeated,
+        GRBLError::GcodeNoAxisWords,
+        GRBLError::GcodeInvalidLineNumber,
+        GRBLError::GcodeValueWordMissing,
+        GRBLError::GcodeUnsupportedCoordSys,
+        GRBLError::GcodeG53InvalidMotionMode,
+        GRBLError::GcodeAxisWordsExist,
+        GRBLError::GcodeNoAxisWordsInPlane,
+        GRBLError::GcodeInvalidTarget,
+        GRBLError::GcodeArcRadiusError,
+        GRBLError::GcodeNoOffsetsInPlane,
+        GRBLError::GcodeUnusedWords,
+        GRBLError::GcodeG43DynamicAxisError,
+        GRBLError::GcodeMaxValueExceeded,
+    ];
+
+    pub fn from_code(code : u8) -> Self {
+        Self::ALL.iter()
+            .copied()
+            .find(|e| e.code() == code)
+            .unwrap_or(GRBLError::Unknown(code))
+    }
+
+    /// Returns the error code, the short description and the long description of the error,
+    /// as listed in the GRBL 1.1 interface documentation.
+    fn info(&self) -> (u8, &'static str, &'static str) {
+        match *self {
+            GRBLError::ExpectedCommandLetter       => ( 1, "Expected command letter",         "G-code words consist of a letter and a value. Letter was not found."),
+            GRBLError::BadNumberFormat             => ( 2, "Bad number format",               "Missing the expected G-code word value or numeric value format is not valid."),
+            GRBLError::InvalidStatement            => ( 3, "Invalid statement",               "Grbl '$' system command was not recognized or supported."),
+            GRBLError::NegativeValue               => ( 4, "Value < 0",                       "Negative value received for an expected positive value."),
+            GRBLError::SettingDisabled             => ( 5, "Setting disabled",                "Homing cycle failure. Homing is not enabled via settings."),
+            GRBLError::SettingStepPulseMin         => ( 6, "Value < 3 usec",                  "Minimum step pulse time must be greater than 3usec."),
+            GRBLError::SettingReadFail             => ( 7, "EEPROM read fail. Using defaults", "An EEPROM read failed. Auto-restoring affected EEPROM to default values."),
+            GRBLError::IdleError                   => ( 8, "Not idle",                        "Grbl '$' command cannot be used unless Grbl is IDLE. Ensures smooth operation during a job."),
+            GRBLError::SystemGcLock                => ( 9, "G-code lock",                     "G-code commands are locked out during alarm or jog state."),
+            GRBLError::SoftLimitError              => (10, "Homing not enabled",              "Soft limits cannot be enabled without homing also enabled."),
+            GRBLError::Overflow                    => (11, "Line overflow",                   "Max characters per line exceeded. Received command line was not executed."),
+            GRBLError::MaxStepRateExceeded         => (12, "Step rate > 30kHz",               "Grbl '$' setting value cause the step rate to exceed the maximum supported."),
+            GRBLError::CheckDoor                   => (13, "Check Door",                      "Safety door detected as opened and door state initiated."),
+            GRBLError::LineLengthExceeded          => (14, "Line length exceeded",            "Build info or startup line exceeded EEPROM line length limit. Line not stored."),
+            GRBLError::TravelExceeded              => (15, "Travel exceeded",                 "Jog target exceeds machine travel. Jog command has been ignored."),
+            GRBLError::InvalidJogCommand           => (16, "Invalid jog command",             "Jog command has no '=' or contains prohibited g-code."),
+            GRBLError::SettingDisabledLaser        => (17, "Setting disabled",                "Laser mode requires PWM output."),
+            GRBLError::GcodeUnsupportedCommand     => (20, "Unsupported command",             "Unsupported or invalid g-code command found in block."),
+            GRBLError::GcodeModalGroupViolation    => (21, "Modal group violation",           "More than one g-code command from same modal group found in block."),
+            GRBLError::GcodeUndefinedFeedRate      => (22, "Undefined feed rate",             "Feed rate has not yet been set or is undefined."),
+            GRBLError::GcodeCommandValueNotInteger => (23, "Invalid gcode ID:23",             "G-code command in block requires an integer value."),
+            GRBLError::GcodeAxisCommandConflict    => (24, "Invalid gcode ID:24",             "More than one g-code command that requires axis words found in block."),
+            GRBLError::GcodeWordRepeated           => (25, "Invalid gcode ID:25",             "Repeated g-code word found in block."),
+            GRBLError::GcodeNoAxisWords            => (26, "Invalid gcode ID:26",             "No axis words found in block for g-code command or current modal state which requires them."),
+            GRBLError::GcodeInvalidLineNumber      => (27, "Invalid gcode ID:27",             "Line number value is invalid."),
+            GRBLError::GcodeValueWordMissing       => (28, "Invalid gcode ID:28",             "G-code command is missing a required value word."),
+            GRBLError::GcodeUnsupportedCoordSys    => (29, "Invalid gcode ID:29",             "G59.x work coordinate systems are not supported."),
+            GRBLError::GcodeG53InvalidMotionMode   => (30, "Invalid gcode ID:30",             "G53 only allowed with G0 and G1 motion modes."),
+            GRBLError::GcodeAxisWordsExist         => (31, "Invalid gcode ID:31",             "Axis words found in block when no command or current modal state uses them."),
+            GRBLError::GcodeNoAxisWordsInPlane     => (32, "Invalid gcode ID:32",             "G2 and G3 arcs require at least one in-plane axis word."),
+            GRBLError::GcodeInvalidTarget          => (33, "Invalid gcode ID:33",             "Motion command target is invalid."),
+            GRBLError::GcodeArcRadiusError         => (34, "Invalid gcode ID:34",             "Arc radius value is invalid."),
+            GRBLError::GcodeNoOffsetsInPlane       => (35, "Invalid gcode ID:35",             "G2 and G3 arcs require at least one in-plane offset word."),
+            GRBLError::GcodeUnusedWords            => (36, "Invalid gcode ID:36",             "Unused value words found in block."),
+            GRBLError::GcodeG43DynamicAxisError    => (37, "Invalid gcode ID:37",             "G43.1 dynamic tool length offset is not assigned to configured tool length axis."),
+            GRBLError::GcodeMaxValueExceeded       => (38, "Invalid gcode ID:38",             "Tool number greater than max supported value."),
+            GRBLError::Unknown(code)               => (code, "Unknown error",                 "GRBL reported an error code that is not documented for GRBL 1.1."),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        self.info().0
+    }
+
+    pub fn short_description(&self) -> &'static str {
+        self.info().1
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.info().2
+    }
+}
+
+/// An alarm code sent by GRBL in an `ALARM:x` message.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GRBLAlarm {
+    HardLimit,
+    SoftLimit,
+    AbortCycle,
+    ProbeFailInitial,
+    ProbeFailContact,
+    HomingFailReset,
+    HomingFailDoor,
+    HomingFailPulloff,
+    HomingFailApproach,
+    HomingFailDualApproach,
+    // grblHAL only
+    HomingRequired,
+    LimitsEngaged,
+    ProbeProtect,
+    SpindleAtSpeed,
+    HomingFailAutoSquaring,
+    SelftestFailed,
+    MotorFault,
+    Unknown(u8),
+}
+
+impl GRBLAlarm {
+    const ALL : [GRBLAlarm; 17] = [
+        GRBLAlarm::HardLimit,
+        GRBLAlarm::SoftLimit,
+        GRBLAlarm::AbortCycle,
+        GRBLAlarm::ProbeFailInitial,
+        GRBLAlarm::ProbeFailContact,
+        GRBLAlarm::HomingFailReset,
+        GRBLAlarm::HomingFailDoor,
+        GRBLAlarm::HomingFailPulloff,
+        GRBLAlarm::HomingFailApproach,
+        GRBLAlarm::HomingFailDualApproach,
+        GRBLAlarm::HomingRequired,
+        GRBLAlarm::LimitsEngaged,
+        GRBLAlarm::ProbeProtect,
+        GRBLAlarm::SpindleAtSpeed,
+        GRBLAlarm::HomingFailAutoSquaring,
+        GRBLAlarm::SelftestFailed,
+        GRBLAlarm::MotorFault,
+    ];
+
+    pub fn from_code(code : u8) -> Self {
+        Self::ALL.iter()
+            .copied()
+            .find(|a| a.code() == code)
+            .unwrap_or(GRBLAlarm::Unknown(code))
+    }
+
+    /// Returns the alarm code, the short description and the long description of the alarm,
+    /// as listed in the GRBL 1.1 interface documentation.
+    fn info(&self) -> (u8, &'static str, &'static str) {
+        match *self {
+            GRBLAlarm::HardLimit              => ( 1, "Hard limit",         "Hard limit has been triggered. Machine position is likely lost due to sudden halt. Re-homing is highly recommended."),
+            GRBLAlarm::SoftLimit              => ( 2, "Soft limit",         "Soft limit alarm. G-code motion target exceeds machine travel. Machine position retained. Alarm may be safely unlocked."),
+            GRBLAlarm::AbortCycle             => ( 3, "Abort during cycle", "Reset while in motion. Machine position is likely lost due to sudden halt. Re-homing is highly recommended."),
+            GRBLAlarm::ProbeFailInitial       => ( 4, "Probe fail",         "Probe fail. Probe is not in the expected initial state before starting probe cycle when G38.2 and G38.3 is not triggered and G38.4 and G38.5 is triggered."),
+            GRBLAlarm::ProbeFailContact       => ( 5, "Probe fail",         "Probe fail. Probe did not contact the workpiece within the programmed travel for G38.2 and G38.4."),
+            GRBLAlarm::HomingFailReset        => ( 6, "Homing fail",        "Homing fail. The active homing cycle was reset."),
+            GRBLAlarm::HomingFailDoor         => ( 7, "Homing fail",        "Homing fail. Safety door was opened during homing cycle."),
+            GRBLAlarm::HomingFailPulloff      => ( 8, "Homing fail",        "Homing fail. Pull off travel failed to clear limit switch. Try increasing pull-off setting or check wiring."),
+            GRBLAlarm::HomingFailApproach     => ( 9, "Homing fail",        "Homing fail. Could not find limit switch within search distances. Try increasing max travel, decreasing pull-off distance, or check wiring."),
+            GRBLAlarm::HomingFailDualApproach => (10, "Homing fail",        "Homing fail. Second dual axis limit switch failed to trigger within configured search distance after first. Try increasing trigger fail distance or check wiring."),
+            GRBLAlarm::HomingRequired         => (11, "Homing required",    "Homing is required before motion commands are accepted."),
+            GRBLAlarm::LimitsEngaged          => (12, "Limit engaged",      "A limit switch was engaged when the machine was reset or powered up. Clear the switch before unlocking."),
+            GRBLAlarm::ProbeProtect           => (13, "Probe protection",   "The probe was triggered during a motion that wasn't a probing cycle."),
+            GRBLAlarm::SpindleAtSpeed         => (14, "Spindle at speed",   "The spindle did not reach the programmed speed in time."),
+            GRBLAlarm::HomingFailAutoSquaring => (15, "Homing fail",        "Homing fail. Could not find the second limit switch of an auto squared axis within search distances."),
+            GRBLAlarm::SelftestFailed         => (16, "Selftest failed",    "The power on selftest failed. A hard reset is required."),
+            GRBLAlarm::MotorFault             => (17, "Motor fault",        "A motor driver reported a fault. Check the driver and the wiring before resetting."),
+            GRBLAlarm::Unknown(code)          => (code, "Unknown alarm",    "GRBL reported an alarm code that is not documented for GRBL 1.1."),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        self.info().0
+    }
+
+    pub fn short_description(&self) -> &'static str {
+        self.info().1
+    }
+
+    pub fn description(&self) -> &'static str {
+        self.info().2
+    }
 }
 
 use std::fmt;
 
 impl fmt::Display for GRBLError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        
-        Ok(())
+        write!(f, "error:{} {}", self.code(), self.short_description())
     }
 }
 
 impl std::error::Error for GRBLError {
 
 }
+
+impl GRBLAlarm {
+    /// The actions that clear the alarm, the recommended one first. Some alarms also need a
+    /// soft reset first, which GRBL asks for with a `[MSG:Reset to continue]` message.
+    pub fn recovery(&self) -> &'static [AlarmRecovery] {
+        match *self {
+            // the position was lost or was never found, so the machine has to be homed
+            GRBLAlarm::HardLimit              |
+            GRBLAlarm::AbortCycle             |
+            GRBLAlarm::HomingRequired         |
+            GRBLAlarm::HomingFailReset        |
+            GRBLAlarm::HomingFailDoor         |
+            GRBLAlarm::HomingFailPulloff      |
+            GRBLAlarm::HomingFailApproach     |
+            GRBLAlarm::HomingFailDualApproach |
+            GRBLAlarm::HomingFailAutoSquaring => &[AlarmRecovery::Home, AlarmRecovery::Unlock],
+            GRBLAlarm::SoftLimit              |
+            GRBLAlarm::ProbeFailInitial       |
+            GRBLAlarm::ProbeFailContact       |
+            GRBLAlarm::LimitsEngaged          |
+            GRBLAlarm::ProbeProtect           |
+            GRBLAlarm::SpindleAtSpeed         => &[AlarmRecovery::Unlock, AlarmRecovery::Home],
+            GRBLAlarm::SelftestFailed         |
+            GRBLAlarm::MotorFault             |
+            GRBLAlarm::Unknown(_)             => &[AlarmRecovery::SoftReset, AlarmRecovery::Unlock],
+        }
+    }
+}
+
+impl fmt::Display for GRBLAlarm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ALARM:{} {}", self.code(), self.short_description())
+    }
+}
@@ -1,32 +1,80 @@
 /*!
  * This file contains the logic for sending GCode and other commands to GRBL.
- * The GCodeTaskHandle acts as an interface to the sender
- * 
+ * The GCodeTaskHandle acts as an interface to the sender, which runs as a task
+ * on the async runtime and only wakes up when there is something to do.
  * 
  */
 
+use std::collections::VecDeque;
+use std::iter::Peekable;
 use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
-use std::sync::{Arc, Mutex, mpsc::*};
-use std::thread::JoinHandle;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 
-use crate::simulation::GcodeProgram;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Handle;
+use tokio::sync::{broadcast, mpsc, watch};
+use tokio::task::JoinHandle;
+
+use crate::controller::{AxisValues, ConnectionState, GCodeParserState, MachineState, MachineStatus, ParserDistanceMode, ParserMotionMode, ParserSpindleState, ParserUnits, PollRates, ProgramProgress, StatusHistory, StreamingMode, ToolChange, ToolChangeSettings, ToolChangeStage, TrafficLog};
+use crate::preprocess::Pipeline;
+use crate::simulation::{GcodeProgram, SPINDLE_SPIN_UP_SECONDS};
+
+use super::{AsyncTransport, GRBLCommand, GRBLConnection, GRBLDialect, GRBLError, GRBLInfo, GRBLMessage, GRBLRealtimeCommand, GRBLSettings, GRBLStatus, SessionRecorder, find_tool_change, open_async_transport, validate_startup_block};
+
+/// The number of messages a subscriber can fall behind before it starts missing messages.
+pub const MESSAGE_CHANNEL_CAPACITY : usize = 1024;
+
+/// Size of GRBL's serial receive buffer, in bytes, used until GRBL reports its own.
+pub const GRBL_RX_BUFFER_SIZE : usize = 128;
 
-use super::{GRBLCommand, GRBLConnection, GRBLRealtimeCommand, GRBLState, GRBLStatus};
+/// The largest line number GRBL accepts in an N word.
+pub const MAX_LINE_NUMBER : usize = 9_999_999;
 
+/// How long to wait between attempts to re-open the port.
+pub const RECONNECT_INTERVAL : Duration = Duration::from_secs(1);
+
+/// How long to wait for the welcome message after opening the port before resetting GRBL.
+pub const WELCOME_TIMEOUT : Duration = Duration::from_millis(2500);
+
+/// An error response from GRBL, together with the line that caused it.
+#[derive(Debug, Clone)]
+pub struct GRBLErrorReport {
+    pub error : GRBLError,
+    /// the line as it was sent to GRBL
+    pub line : String,
+    /// index of the line in the program, if it was sent as part of a program
+    pub program_line : Option<usize>,
+}
 
 pub struct GCodeTaskHandle {
-    pub grbl : Arc<Mutex<GRBLStatus>>,
-    pub sender : Sender<GCodeTaskMessage>,
+    /// the last status report
+    pub status : watch::Receiver<GRBLStatus>,
+    pub info : Arc<Mutex<GRBLInfo>>,
+    pub settings : Arc<Mutex<Option<GRBLSettings>>>,
+    pub sender : mpsc::UnboundedSender<GCodeTaskMessage>,
     pub paused : Arc<AtomicBool>,
     pub has_gcode : Arc<AtomicBool>,
     pub gcode_line : Arc<AtomicU64>,
+    pub errors : Arc<Mutex<Vec<GRBLErrorReport>>>,
+    pub messages : broadcast::Sender<GRBLMessage>,
+    pub state : watch::Receiver<ConnectionState>,
+    /// every line sent to and received from GRBL, kept across reconnects
+    pub traffic : Arc<Mutex<TrafficLog>>,
+    /// every status report received, kept across reconnects
+    pub status_history : Arc<Mutex<StatusHistory>>,
+    /// why the last program was stopped before its end, e.g. by an alarm
+    pub program_stopped : Arc<Mutex<Option<String>>>,
+    /// the progress of the last program started, not counting validation
+    pub progress : Arc<Mutex<Option<ProgramProgress>>>,
+    /// the tool change the program is stopped for
+    pub tool_change : Arc<Mutex<Option<ToolChange>>>,
     pub join : JoinHandle<()>,
 }
 
 impl GCodeTaskHandle {
     pub fn start_program(&self, program : GcodeProgram) -> bool {
-        if !self.has_gcode.load(Ordering::SeqCst) {
+        if self.is_ready() && !self.has_gcode.load(Ordering::SeqCst) {
             self.sender.send(GCodeTaskMessage::StartProgram(program)).unwrap();
             true
         } else {
@@ -34,8 +82,19 @@ impl GCodeTaskHandle {
         }
     }
 
+    /// Starts streaming `program` from the line with index `start`, after sending `lead_in`,
+    /// which restores the state the machine would be in at that line.
+    pub fn start_program_from(&self, program : GcodeProgram, start : usize, lead_in : Vec<String>) -> bool {
+        if self.is_ready() && !self.has_gcode.load(Ordering::SeqCst) {
+            self.sender.send(GCodeTaskMessage::StartProgramFrom{program, start, lead_in}).unwrap();
+            true
+        } else {
+            false
+        }
+    }
+
     pub fn validate_program(&self, program : GcodeProgram) -> bool {
-        if !self.has_gcode.load(Ordering::SeqCst) {
+        if self.is_ready() && !self.has_gcode.load(Ordering::SeqCst) {
             self.sender.send(GCodeTaskMessage::ValidateProgram(program)).unwrap();
             true
         } else {
@@ -52,7 +111,7 @@ impl GCodeTaskHandle {
     }
 
     pub fn send_command(&self, cmd : GRBLCommand) -> bool {
-        if !self.has_gcode.load(Ordering::SeqCst) {
+        if self.is_ready() && !self.has_gcode.load(Ordering::SeqCst) {
             self.sender.send(GCodeTaskMessage::SendCommand(cmd)).unwrap();
             true
         } else {
@@ -61,7 +120,7 @@ impl GCodeTaskHandle {
     }
 
     pub fn send_string(&self, mut cmd : String) -> bool {
-        if !self.has_gcode.load(Ordering::SeqCst) {
+        if self.is_ready() && !self.has_gcode.load(Ordering::SeqCst) {
 
             cmd += "\r\n";
 
@@ -72,6 +131,50 @@ impl GCodeTaskHandle {
         }
     }
 
+    /// Writes each setting in turn, waiting for GRBL to acknowledge one before sending the next,
+    /// then fetches the settings again so that `get_settings` reflects what GRBL stored.
+    pub fn write_settings(&self, commands : Vec<GRBLCommand>) -> bool {
+        if self.is_ready() && !self.has_gcode.load(Ordering::SeqCst) {
+            for cmd in commands {
+                self.sender.send(GCodeTaskMessage::SendCommand(cmd)).unwrap();
+            }
+            self.sender.send(GCodeTaskMessage::SendCommand(GRBLCommand::QuerySettings)).unwrap();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Checks and stores a startup block, then fetches the startup blocks again.
+    /// An empty line clears the block.
+    pub fn write_startup_block(&self, index : usize, line : &str) -> Result<(), String> {
+        let line = validate_startup_block(line)?;
+
+        if self.has_gcode.load(Ordering::SeqCst) {
+            return Err(String::from("cannot change startup blocks while a program is running"));
+        }
+
+        if !self.is_ready() {
+            return Err(String::from("GRBL is not ready"));
+        }
+
+        self.sender.send(GCodeTaskMessage::SendCommand(GRBLCommand::SetStartupBlock{index : index as u16, line})).unwrap();
+        self.sender.send(GCodeTaskMessage::SendCommand(GRBLCommand::QueryStartupBlcoks)).unwrap();
+        Ok(())
+    }
+
+    /// Starts writing every byte exchanged with GRBL to `path`, replacing any recording in progress.
+    /// The recording continues across reconnects.
+    pub fn start_recording(&self, path : &std::path::Path) -> std::io::Result<()> {
+        let recorder = SessionRecorder::create(path)?;
+        self.sender.send(GCodeTaskMessage::StartRecording(recorder)).unwrap();
+        Ok(())
+    }
+
+    pub fn stop_recording(&self) {
+        self.sender.send(GCodeTaskMessage::StopRecording).unwrap();
+    }
+
     pub fn pause_gcode(&self) {
 
         self.paused.store(true, Ordering::Relaxed);
@@ -80,150 +183,986 @@ impl GCodeTaskHandle {
     pub fn unpause_gcode(&self) {
 
         self.paused.store(false, Ordering::Relaxed);
+        self.sender.send(GCodeTaskMessage::Resume).unwrap();
+    }
+
+    pub fn set_streaming_mode(&self, mode : StreamingMode) {
+        self.sender.send(GCodeTaskMessage::SetStreamingMode(mode)).unwrap();
+    }
+
+    pub fn set_poll_rates(&self, rates : PollRates) {
+        self.sender.send(GCodeTaskMessage::SetPollRates(rates)).unwrap();
+    }
+
+    /// Sets the passes the programs started from now on go through before they are sent.
+    pub fn set_pipeline(&self, pipeline : Pipeline) {
+        self.sender.send(GCodeTaskMessage::SetPipeline(pipeline)).unwrap();
+    }
+
+    /// Numbers the lines of the programs started from now on, so that GRBL reports the line it executes.
+    pub fn set_line_numbering(&self, enabled : bool) {
+        self.sender.send(GCodeTaskMessage::SetLineNumbering(enabled)).unwrap();
+    }
+
+    pub fn set_tool_change_settings(&self, settings : ToolChangeSettings) {
+        self.sender.send(GCodeTaskMessage::SetToolChangeSettings(settings)).unwrap();
+    }
+
+    /// Continues the program once the new tool is in, measuring its length first if the settings say so.
+    pub fn continue_tool_change(&self) {
+        self.sender.send(GCodeTaskMessage::ContinueToolChange).unwrap();
     }
 
     pub fn stop(self) {
         self.sender.send(GCodeTaskMessage::Stop).unwrap();
     }
 
+    pub fn connection_state(&self) -> ConnectionState {
+        self.state.borrow().clone()
+    }
+
+    /// Returns true if GRBL has announced itself and accepts commands.
+    pub fn is_ready(&self) -> bool {
+        *self.state.borrow() == ConnectionState::Ready
+    }
+
     pub fn get_machine_status(&self) -> GRBLStatus {
-        self.grbl.lock().unwrap().clone()
+        self.status.borrow().clone()
+    }
+
+    /// Returns the parser state, offsets, probe result and build info last reported by GRBL.
+    pub fn get_info(&self) -> GRBLInfo {
+        self.info.lock().unwrap().clone()
+    }
+
+    /// Returns the settings last reported by GRBL in response to `$$`, if any.
+    pub fn get_settings(&self) -> Option<GRBLSettings> {
+        *self.settings.lock().unwrap()
+    }
+
+    /// Returns why the last program was stopped before its end, if it was.
+    pub fn get_program_stopped(&self) -> Option<String> {
+        self.program_stopped.lock().unwrap().clone()
+    }
+
+    pub fn get_progress(&self) -> Option<ProgramProgress> {
+        self.progress.lock().unwrap().clone()
+    }
+
+    pub fn get_tool_change(&self) -> Option<ToolChange> {
+        self.tool_change.lock().unwrap().clone()
+    }
+
+    /// Returns every error reported by GRBL since the last call to `clear_errors`.
+    pub fn get_errors(&self) -> Vec<GRBLErrorReport> {
+        self.errors.lock().unwrap().clone()
+    }
+
+    pub fn clear_errors(&self) {
+        self.errors.lock().unwrap().clear();
+    }
+
+    /// Subscribes to the messages received from GRBL. Each receiver gets every message
+    /// received after it subscribed.
+    pub fn subscribe(&self) -> broadcast::Receiver<GRBLMessage> {
+        self.messages.subscribe()
     }
 }
 
 pub enum GCodeTaskMessage {
     StartProgram(GcodeProgram),
+    StartProgramFrom {
+        program : GcodeProgram,
+        start : usize,
+        lead_in : Vec<String>,
+    },
     ValidateProgram(GcodeProgram),
     StopProgram,
     RealtimeCommand(GRBLRealtimeCommand),
     SendCommand(GRBLCommand),
     SendString(String),
+    SetStreamingMode(StreamingMode),
+    SetPollRates(PollRates),
+    SetLineNumbering(bool),
+    SetPipeline(Pipeline),
+    SetToolChangeSettings(ToolChangeSettings),
+    /// the new tool is in
+    ContinueToolChange,
+    StartRecording(SessionRecorder),
+    StopRecording,
+    /// continue streaming after the program was unpaused
+    Resume,
     Stop,
 }
 
-pub fn start_gcode_sender_task(path : String, baud_rate : u32) -> GCodeTaskHandle {
+pub fn start_gcode_sender_task(endpoint : String, baud_rate : u32, runtime : &Handle) -> GCodeTaskHandle {
 
-    let (tx,rx) = channel::<GCodeTaskMessage>();
+    let (tx, rx) = mpsc::unbounded_channel::<GCodeTaskMessage>();
     let paused = Arc::new(AtomicBool::new(false));
     let has_gcode = Arc::new(AtomicBool::new(false));
     let gcode_line = Arc::new(AtomicU64::new(0));
+    let errors = Arc::new(Mutex::new(vec![]));
 
-    let mut validating = false;
+    let (messages, _) = broadcast::channel(MESSAGE_CHANNEL_CAPACITY);
 
-    let grbl_status = Arc::new(Mutex::new(GRBLStatus::default()));
-    let join = {
-        let grbl_status = grbl_status.clone();
-        let paused = paused.clone();
-        let gcode_line = gcode_line.clone();
-        let has_gcode = has_gcode.clone();
-        std::thread::spawn(move || {
-            let mut grbl = GRBLConnection::open(&path, baud_rate).unwrap();
+    let (state_sender, state) = watch::channel(ConnectionState::Connecting);
+    let (status_sender, status) = watch::channel(GRBLStatus::default());
 
-            let mut gcode_iter = None;
+    let grbl_info = Arc::new(Mutex::new(GRBLInfo::default()));
+    let grbl_settings = Arc::new(Mutex::new(None));
+    let traffic = Arc::new(Mutex::new(TrafficLog::new()));
+    let status_history = Arc::new(Mutex::new(StatusHistory::new()));
+    let program_stopped = Arc::new(Mutex::new(None));
+    let progress = Arc::new(Mutex::new(None));
+    let tool_change = Arc::new(Mutex::new(None));
 
-            let mut last_status = Instant::now();
+    let task = SenderTask {
+        endpoint,
+        baud_rate,
+        connection : None,
+        state : ConnectionState::Connecting,
+        state_sender,
+        status_sender,
+        info : grbl_info.clone(),
+        settings : grbl_settings.clone(),
+        messages : messages.clone(),
+        traffic : traffic.clone(),
+        status_history : status_history.clone(),
+        program_stopped : program_stopped.clone(),
+        progress : progress.clone(),
+        tool_change : tool_change.clone(),
+        paused : paused.clone(),
+        has_gcode : has_gcode.clone(),
+        gcode_line : gcode_line.clone(),
+        errors : errors.clone(),
+        gcode_iter : None,
+        validating : false,
+        streaming_mode : StreamingMode::default(),
+        poll_rates : PollRates::default(),
+        line_numbering : false,
+        pipeline : Pipeline::default(),
+        tool_change_settings : ToolChangeSettings::default(),
+        tool_change_queue : VecDeque::new(),
+        tool_change_modal : None,
+        tool_reference : None,
+        reference_work_z : None,
+        command_queue : VecDeque::new(),
+        last_status : Instant::now(),
+        attempt : 0,
+        next_attempt : Instant::now(),
+        opened_at : Instant::now(),
+        reset_sent : false,
+        restore_info : None,
+        recorder : None,
+        lost : None,
+    };
 
-            loop {
+    let join = runtime.spawn(task.run(rx));
 
-                if gcode_iter.is_none() {
-                    gcode_line.store(0, Ordering::Relaxed);
-                }
+    GCodeTaskHandle {
+        status,
+        info : grbl_info,
+        settings : grbl_settings,
+        sender: tx,
+        paused,
+        has_gcode,
+        join,
+        gcode_line,
+        errors,
+        messages,
+        state,
+        traffic,
+        status_history,
+        program_stopped,
+        progress,
+        tool_change,
+    }
+}
 
-                if last_status.elapsed() > Duration::from_millis(500) {
+/// Reads from the stream, or waits forever if there is none.
+async fn read_stream(stream : &mut Option<Box<dyn AsyncTransport>>, buf : &mut [u8]) -> std::io::Result<usize> {
+    match stream {
+        Some(stream) => stream.read(buf).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The state of the sender task, which runs on the async runtime.
+struct SenderTask {
+    endpoint : String,
+    baud_rate : u32,
+    /// the protocol state, present while the transport is open
+    connection : Option<GRBLConnection>,
+    state : ConnectionState,
+    state_sender : watch::Sender<ConnectionState>,
+    status_sender : watch::Sender<GRBLStatus>,
+    info : Arc<Mutex<GRBLInfo>>,
+    settings : Arc<Mutex<Option<GRBLSettings>>>,
+    messages : broadcast::Sender<GRBLMessage>,
+    traffic : Arc<Mutex<TrafficLog>>,
+    status_history : Arc<Mutex<StatusHistory>>,
+    program_stopped : Arc<Mutex<Option<String>>>,
+    progress : Arc<Mutex<Option<ProgramProgress>>>,
+    tool_change : Arc<Mutex<Option<ToolChange>>>,
+    paused : Arc<AtomicBool>,
+    has_gcode : Arc<AtomicBool>,
+    gcode_line : Arc<AtomicU64>,
+    errors : Arc<Mutex<Vec<GRBLErrorReport>>>,
+    /// the lines left to send, with their index in the program
+    gcode_iter : Option<Peekable<std::vec::IntoIter<(usize, String)>>>,
+    validating : bool,
+    streaming_mode : StreamingMode,
+    poll_rates : PollRates,
+    /// puts the line number of each program line in front of it as an N word
+    line_numbering : bool,
+    /// the passes programs go through before they are sent
+    pipeline : Pipeline,
+    tool_change_settings : ToolChangeSettings,
+    /// the lines of the tool change left to send, which are sent one at a time
+    tool_change_queue : VecDeque<String>,
+    /// the modal state when the tool change started, which has the spindle and coolant to restart
+    tool_change_modal : Option<GCodeParserState>,
+    /// the probed Z of the tool that set work Z, less the tool length offset it had, in machine
+    /// coordinates. the tools measured after it are offset against it
+    tool_reference : Option<f32>,
+    /// the work Z offset the reference tool was measured with
+    reference_work_z : Option<f32>,
+    /// commands are sent one at a time, so that e.g. EEPROM writes never overflow GRBL's buffer
+    command_queue : VecDeque<String>,
+    last_status : Instant,
+    attempt : u32,
+    next_attempt : Instant,
+    opened_at : Instant,
+    reset_sent : bool,
+    /// what GRBL reported before the connection was lost, restored once it is ready again
+    restore_info : Option<GRBLInfo>,
+    /// the recording in progress while there is no connection to write it
+    recorder : Option<SessionRecorder>,
+    /// set when the transport fails, with the reason
+    lost : Option<String>,
+}
+
+impl SenderTask {
+    async fn run(mut self, mut rx : mpsc::UnboundedReceiver<GCodeTaskMessage>) {
 
-                    grbl.execute_realtime_command(GRBLRealtimeCommand::StatusQuery);
+        let mut stream : Option<Box<dyn AsyncTransport>> = None;
+        let mut buf = [0; 1024];
 
-                    last_status = Instant::now();
+        loop {
+
+            if stream.is_none() && Instant::now() >= self.next_attempt {
+                match open_async_transport(&self.endpoint, self.baud_rate).await {
+                    Ok(s) => {
+                        stream = Some(s);
+                        self.opened();
+                    }
+                    Err(e) => {
+                        self.attempt += 1;
+                        self.next_attempt = Instant::now() + RECONNECT_INTERVAL;
+                        self.set_state(ConnectionState::Reconnecting{attempt : self.attempt, reason : e.to_string()});
+                    }
                 }
+            }
+
+            // nothing happens until a message arrives, GRBL sends something, or a timer expires
+            let wakeup = self.next_wakeup();
 
-                if let Ok(msg) = rx.recv_timeout(Duration::from_millis(1)) {
+            tokio::select! {
+                msg = rx.recv() => {
                     match msg {
-                        GCodeTaskMessage::StartProgram(prog) => {
-                            gcode_line.store(0, Ordering::Relaxed);
-                            gcode_iter = Some(prog.lines.into_iter());
+                        Some(GCodeTaskMessage::Stop) | None => return,
+                        Some(msg) => self.handle_task_message(msg),
+                    }
+                }
+                read = read_stream(&mut stream, &mut buf) => {
+                    match read {
+                        Ok(0) => self.lost = Some(String::from("the connection was closed")),
+                        Ok(n) => {
+                            if let Some(grbl) = self.connection.as_mut() {
+                                grbl.receive(&buf[..n]);
+                            }
                         }
-                        GCodeTaskMessage::ValidateProgram(prog) => {
-                            validating = true;
+                        Err(e) => self.lost = Some(e.to_string()),
+                    }
+                }
+                _ = tokio::time::sleep_until(wakeup.into()) => {}
+            }
 
-                            gcode_line.store(0, Ordering::Relaxed);
-                            if grbl.machine_status.state != GRBLState::Check {
-                                grbl.send_message(String::from_utf8(GRBLCommand::CheckGCodeMode.to_bytes()).unwrap()).unwrap();
-                            }
+            self.update();
 
-                            gcode_iter = Some(prog.lines.into_iter());
+            if let (Some(s), Some(grbl)) = (stream.as_mut(), self.connection.as_mut()) {
+                let output = grbl.take_output();
+                if !output.is_empty() {
+                    if let Err(e) = s.write_all(&output).await {
+                        self.lost = Some(e.to_string());
+                    }
+                }
+            }
 
-                        }
-                        GCodeTaskMessage::StopProgram => {
-                            println!("stopped program");
-                            gcode_iter = None;
-                            has_gcode.store(false, Ordering::Relaxed);
-                        }
-                        GCodeTaskMessage::RealtimeCommand(rtcmd) => {
-                            grbl.execute_realtime_command(rtcmd);
-                        }
-                        GCodeTaskMessage::SendCommand(cmd) => {
-                            grbl.send_message(String::from_utf8(cmd.to_bytes()).unwrap()).unwrap();
-                        }
-                        GCodeTaskMessage::Stop => {
-                            return;
-                        }
-                        GCodeTaskMessage::SendString(s) => {
-                            grbl.send_message(s).unwrap();
-                        }
+            if let Some(reason) = self.lost.take() {
+                stream = None;
+                self.connection_lost(reason);
+            }
+        }
+    }
+
+    fn set_state(&mut self, state : ConnectionState) {
+        self.state = state;
+        let _ = self.state_sender.send(self.state.clone());
+    }
+
+    fn opened(&mut self) {
+        let mut grbl = GRBLConnection::new(self.messages.clone(), self.traffic.clone());
+        grbl.recorder = self.recorder.take();
+        self.connection = Some(grbl);
+        self.opened_at = Instant::now();
+        self.reset_sent = false;
+        self.set_state(ConnectionState::WaitingForWelcome);
+    }
+
+    /// The time at which the task has to act even if nothing is received.
+    fn next_wakeup(&self) -> Instant {
+        match self.state {
+            _ if self.connection.is_none() => self.next_attempt,
+            ConnectionState::WaitingForWelcome if !self.reset_sent => self.opened_at + WELCOME_TIMEOUT,
+            ConnectionState::Ready => self.last_status + self.status_interval(),
+            _ => Instant::now() + self.poll_rates.idle,
+        }
+    }
+
+    /// How often GRBL is asked for a status report, faster while the machine is moving.
+    fn status_interval(&self) -> Duration {
+        let state = self.connection.as_ref().map(|grbl| grbl.machine_status.state).unwrap_or_default();
+        self.poll_rates.interval(state, self.gcode_iter.is_some())
+    }
+
+    fn handle_task_message(&mut self, msg : GCodeTaskMessage) {
+
+        match msg {
+            GCodeTaskMessage::SetStreamingMode(mode) => {
+                self.streaming_mode = mode;
+                return;
+            }
+            GCodeTaskMessage::SetPollRates(rates) => {
+                self.poll_rates = rates;
+                return;
+            }
+            GCodeTaskMessage::SetLineNumbering(enabled) => {
+                self.line_numbering = enabled;
+                return;
+            }
+            GCodeTaskMessage::SetPipeline(pipeline) => {
+                self.pipeline = pipeline;
+                return;
+            }
+            GCodeTaskMessage::SetToolChangeSettings(settings) => {
+                self.tool_change_settings = settings;
+                return;
+            }
+            GCodeTaskMessage::StopProgram => {
+                log::info!("stopped program");
+                stop_progress(&self.progress);
+                self.cancel_tool_change();
+                self.gcode_iter = None;
+                self.has_gcode.store(false, Ordering::Relaxed);
+                return;
+            }
+            GCodeTaskMessage::StartRecording(recorder) => {
+                match self.connection.as_mut() {
+                    Some(grbl) => grbl.recorder = Some(recorder),
+                    None => self.recorder = Some(recorder),
+                }
+                return;
+            }
+            GCodeTaskMessage::StopRecording => {
+                if let Some(grbl) = self.connection.as_mut() {
+                    grbl.recorder = None;
+                }
+                self.recorder = None;
+                return;
+            }
+            // waking up is all that is needed to continue streaming
+            GCodeTaskMessage::Resume => return,
+            _ => {}
+        }
+
+        let grbl = match self.connection.as_mut() {
+            Some(grbl) => grbl,
+            None => {
+                log::warn!("not connected, ignoring command.");
+                return;
+            }
+        };
+
+        if self.state != ConnectionState::Ready {
+            log::warn!("GRBL is not ready, ignoring command.");
+            return;
+        }
+
+        match msg {
+            GCodeTaskMessage::StartProgram(prog) => {
+                let lines = match preprocess(&self.pipeline, grbl.info.dialect, &prog.lines) {
+                    Ok(lines) => lines,
+                    Err(e) => return self.refuse_program(&e),
+                };
+
+                grbl.error = false;
+                self.tool_reference = None;
+                *self.program_stopped.lock().unwrap() = None;
+                self.gcode_line.store(0, Ordering::Relaxed);
+                *self.progress.lock().unwrap() = Some(ProgramProgress::new(0, prog.lines.len(), self.line_numbering));
+                self.gcode_iter = Some(numbered_lines(lines, self.line_numbering));
+            }
+            GCodeTaskMessage::StartProgramFrom{program, start, lead_in} => {
+                let lines = match preprocess(&self.pipeline, grbl.info.dialect, &program.lines) {
+                    Ok(lines) => lines,
+                    Err(e) => return self.refuse_program(&e),
+                };
+
+                grbl.error = false;
+                self.tool_reference = None;
+                *self.program_stopped.lock().unwrap() = None;
+                log::info!("starting program from line {}", start + 1);
+                *self.progress.lock().unwrap() = Some(ProgramProgress::new(start, program.lines.len(), self.line_numbering));
+
+                // the lead-in is reported as the line before the one it leads into, which has already run
+                let lines = lead_in.into_iter()
+                    .map(|line| (start.saturating_sub(1), line))
+                    .chain(lines.into_iter().filter(|&(i, _)| i >= start))
+                    .collect::<Vec<_>>();
+
+                self.gcode_line.store(start as u64, Ordering::Relaxed);
+                self.gcode_iter = Some(numbered_lines(lines, self.line_numbering));
+            }
+            GCodeTaskMessage::ValidateProgram(prog) => {
+                let lines = match preprocess(&self.pipeline, grbl.info.dialect, &prog.lines) {
+                    Ok(lines) => lines,
+                    Err(e) => return self.refuse_program(&e),
+                };
+
+                self.validating = true;
+                grbl.error = false;
+                *self.program_stopped.lock().unwrap() = None;
+
+                self.gcode_line.store(0, Ordering::Relaxed);
+                if grbl.machine_status.state != MachineState::Check {
+                    grbl.send_command(GRBLCommand::CheckGCodeMode).unwrap();
+                }
+
+                self.gcode_iter = Some(numbered_lines(lines, false));
+            }
+            GCodeTaskMessage::RealtimeCommand(rtcmd) => {
+                grbl.execute_realtime_command(rtcmd);
+            }
+            GCodeTaskMessage::SendCommand(cmd) => {
+                self.command_queue.push_back(String::from_utf8(cmd.to_bytes()).unwrap());
+            }
+            GCodeTaskMessage::SendString(s) => {
+                self.command_queue.push_back(s);
+            }
+            GCodeTaskMessage::ContinueToolChange => {
+                let mut tool_change = self.tool_change.lock().unwrap();
+                let change = match tool_change.as_mut() {
+                    Some(change) if change.stage == ToolChangeStage::WaitingForTool => change,
+                    _ => return,
+                };
+
+                if self.tool_change_settings.measure && self.tool_reference.is_none() {
+                    log::warn!("not measuring the new tool, as there is no reference tool to measure it against");
+                }
+
+                if self.tool_change_settings.measure && self.tool_reference.is_some() {
+                    let relative = self.tool_change_modal.map_or(false, |p| p.distance_mode == ParserDistanceMode::Incremental);
+                    self.tool_change_queue.extend(self.tool_change_settings.probe_commands(relative));
+                    change.stage = ToolChangeStage::Measuring;
+                } else {
+                    self.tool_change_queue.extend(tool_change_restore(self.tool_change_modal.as_ref()));
+                    change.stage = ToolChangeStage::Resuming;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Advances the protocol after a message, a response or a timer, and publishes the result.
+    fn update(&mut self) {
+
+        if self.gcode_iter.is_none() {
+            self.gcode_line.store(0, Ordering::Relaxed);
+        }
+
+        // GRBL discards the rest of the program on an alarm or a reset, so stop sending it.
+        // a reset from Idle or a hold raises no alarm, only the welcome message shows it
+        let alarm = self.connection.as_ref().and_then(|grbl| grbl.machine_status.alarm);
+        let reset = self.connection.as_mut().map_or(false, |grbl| std::mem::take(&mut grbl.reset));
+        if self.gcode_iter.is_some() {
+            if let Some(alarm) = alarm {
+                self.interrupt_program(&alarm.to_string());
+            } else if reset {
+                self.interrupt_program("a reset");
+            }
+        }
+
+        // a tool change that can't be read would reach GRBL as it is, and GRBL would go on without it
+        let unreadable = match self.gcode_iter.as_mut().and_then(|i| i.peek()) {
+            Some((i, line)) if !self.validating => find_tool_change(line, self.tool_change_settings.on_tool_select).err().map(|e| (*i, e)),
+            _ => None,
+        };
+        if let Some((i, e)) = unreadable {
+            log::error!("line {}: {}", i + 1, e);
+            self.interrupt_program(&format!("an unreadable tool change on line {}", i + 1));
+        }
+
+        let grbl = match self.connection.as_mut() {
+            Some(grbl) => grbl,
+            None => return,
+        };
+
+        if self.state == ConnectionState::WaitingForWelcome {
+            if grbl.version.is_some() {
+                self.state = ConnectionState::Ready;
+                let _ = self.state_sender.send(self.state.clone());
+                self.attempt = 0;
+            } else if !self.reset_sent && self.opened_at.elapsed() > WELCOME_TIMEOUT {
+                // boards that don't reset when the port is opened only announce themselves after a reset
+                grbl.execute_realtime_command(GRBLRealtimeCommand::SoftReset);
+                self.reset_sent = true;
+            }
+        }
+
+        let ready = self.state == ConnectionState::Ready;
+
+        let status_interval = self.poll_rates.interval(grbl.machine_status.state, self.gcode_iter.is_some());
+        if ready && self.last_status.elapsed() >= status_interval {
+            grbl.execute_realtime_command(GRBLRealtimeCommand::StatusQuery);
+            self.last_status = Instant::now();
+        }
+
+        if ready && grbl.status_received {
+            if let Some(info) = self.restore_info.take() {
+                for cmd in restore_commands(&info, grbl.machine_status.machine_position).into_iter().rev() {
+                    self.command_queue.push_front(cmd);
+                }
+            }
+        }
+
+        if self.validating && grbl.error {
+            self.gcode_iter = None;
+            grbl.send_command(GRBLCommand::CheckGCodeMode).unwrap();
+            self.validating = false;
+        }
+
+        if ready && self.gcode_iter.is_none() && grbl.ready {
+            if let Some(cmd) = self.command_queue.pop_front() {
+                grbl.send_message(cmd).unwrap();
+            }
+        }
+
+        if ready && grbl.ready {
+            if let Some(line) = self.tool_change_queue.pop_front() {
+                grbl.send_internal_line(line + "\n").unwrap();
+            }
+        }
+
+        // the reference only holds for the work Z it was measured with
+        if self.tool_reference.is_some() && work_z(&grbl.info) != self.reference_work_z {
+            log::info!("work Z offset changed, the reference tool has to be measured again");
+            self.tool_reference = None;
+        }
+
+        // each step of the tool change ends once GRBL has acknowledged all of its lines.
+        // a failed probe raises an alarm, which cancels the tool change
+        if self.tool_change_queue.is_empty() && grbl.pending.is_empty() {
+            let mut tool_change = self.tool_change.lock().unwrap();
+            match tool_change.as_mut().map(|change| (change.stage, change)) {
+                Some((ToolChangeStage::ReadingState, change)) => {
+                    // `$G` was answered after the lines before the tool change, so the modal
+                    // state is theirs, and not that of the last status report
+                    self.tool_change_modal = grbl.info.parser_state;
+                    self.tool_change_queue.extend(self.tool_change_settings.move_commands());
+
+                    // the tool in the spindle set work Z, so it is measured before it is taken out
+                    change.stage = if self.tool_change_settings.measure && self.tool_reference.is_none() {
+                        let relative = self.tool_change_modal.map_or(false, |p| p.distance_mode == ParserDistanceMode::Incremental);
+                        self.tool_change_queue.extend(self.tool_change_settings.probe_commands(relative));
+                        ToolChangeStage::MeasuringReference
+                    } else {
+                        ToolChangeStage::Moving
+                    };
+                }
+                Some((ToolChangeStage::Moving, change)) => {
+                    change.stage = ToolChangeStage::WaitingForTool;
+                }
+                Some((ToolChangeStage::MeasuringReference, change)) => {
+                    if let Some(probe) = grbl.info.probe.filter(|p| p.success) {
+                        let reference = probe.position[2] - grbl.info.offsets.tool_length_offset;
+                        log::info!("reference tool at Z {:.3}", reference);
+                        self.tool_reference = Some(reference);
+                        self.reference_work_z = work_z(&grbl.info);
+                        change.has_reference = true;
+                    }
+                    change.stage = ToolChangeStage::WaitingForTool;
+                }
+                Some((ToolChangeStage::Measuring, change)) => {
+                    if let (Some(probe), Some(reference)) = (grbl.info.probe.filter(|p| p.success), self.tool_reference) {
+                        let offset = probe.position[2] - reference;
+                        log::info!("tool length offset {:.3}", offset);
+                        self.tool_change_queue.push_back(format!("G21 G43.1 Z{:.3}", offset));
+                        change.offset = Some(offset);
                     }
+                    self.tool_change_queue.extend(tool_change_restore(self.tool_change_modal.as_ref()));
+                    change.stage = ToolChangeStage::Resuming;
+                }
+                Some((ToolChangeStage::Resuming, _)) => {
+                    *tool_change = None;
                 }
+                _ => {}
+            }
+        }
+
+        let changing_tool = self.tool_change.lock().unwrap().is_some();
 
-                has_gcode.store(gcode_iter.is_some(), Ordering::Relaxed);
+        if ready && !changing_tool && !self.paused.load(Ordering::SeqCst) {
 
-                let grbl_ready = grbl.ready;
-                let grbl_error = grbl.error;
+            // send as many lines as the streaming protocol allows
+            loop {
+                let can_send = match (self.streaming_mode, self.gcode_iter.as_mut().map(|i| i.peek())) {
+                    (_, None) => false,
+                    (_, Some(None)) => true,
+                    (StreamingMode::SendResponse, Some(Some(_))) => grbl.ready,
+                    (StreamingMode::CharacterCounting, Some(Some((_, line)))) => {
+                        let len = if line.ends_with("\n") {line.len()} else {line.len() + 1};
+                        // a line longer than the whole buffer can only be sent on its own
+                        grbl.pending.is_empty() || grbl.pending_bytes() + len <= grbl.info.rx_buffer_size()
+                    }
+                };
 
-                if validating && grbl_error {
-                    gcode_iter = None;
-                    grbl.send_message(String::from_utf8(GRBLCommand::CheckGCodeMode.to_bytes()).unwrap()).unwrap();
-                    validating = false;
+                if !can_send {
+                    break;
                 }
 
-                if !paused.load(Ordering::SeqCst) && grbl_ready {
+                // the lines before a tool change have to be done before the machine moves away
+                let tool_change = match self.gcode_iter.as_mut().and_then(|i| i.peek()) {
+                    Some((_, line)) if !self.validating => find_tool_change(line, self.tool_change_settings.on_tool_select),
+                    _ => Ok(None),
+                };
 
-                    match gcode_iter.as_mut().map(|i| i.next()) {
-                        Some(Some(mut line)) =>  {
+                // the program is stopped at the next update
+                if tool_change.is_err() {
+                    break;
+                }
 
-                            if !line.ends_with("\n") {
-                                line += "\n";
-                            }
+                if let Ok(Some((tool, rest))) = tool_change {
+                    if !grbl.pending.is_empty() {
+                        break;
+                    }
+
+                    let (i, _) = self.gcode_iter.as_mut().unwrap().next().unwrap();
+                    log::info!("tool change to {:?} at line {}", tool, i + 1);
+
+                    self.tool_change_modal = None;
+                    self.tool_change_queue.push_back(String::from("$G"));
+
+                    *self.tool_change.lock().unwrap() = Some(ToolChange {
+                        tool,
+                        line : i,
+                        stage : ToolChangeStage::ReadingState,
+                        offset : None,
+                        has_reference : self.tool_reference.is_some(),
+                    });
+
+                    // GRBL answers an empty line too, which keeps the progress counting
+                    grbl.send_program_line(rest + "\n", i).unwrap();
+                    self.gcode_line.store(i as u64 + 1, Ordering::Relaxed);
+                    break;
+                }
+
+                match self.gcode_iter.as_mut().map(|i| i.next()) {
+                    Some(Some((i, mut line))) =>  {
 
-                            grbl.send_message(line).unwrap();
-                            gcode_line.fetch_add(1, Ordering::Relaxed);
+                        if !line.ends_with("\n") {
+                            line += "\n";
                         }
-                        Some(None) => {
-                            gcode_iter = None;
 
-                            if validating {
+                        grbl.send_program_line(line, i).unwrap();
+                        self.gcode_line.store(i as u64 + 1, Ordering::Relaxed);
+                    }
+                    Some(None) => {
+                        self.gcode_iter = None;
 
-                                grbl.send_message(String::from_utf8(GRBLCommand::CheckGCodeMode.to_bytes()).unwrap()).unwrap();
-                                validating = false;
-                            }
+                        if self.validating {
+                            grbl.send_command(GRBLCommand::CheckGCodeMode).unwrap();
+                            self.validating = false;
                         }
-                        None => {}
                     }
+                    None => {}
                 }
+            }
+        }
 
-                if grbl.poll().is_err() {
-                    break;
-                }
+        self.has_gcode.store(self.gcode_iter.is_some(), Ordering::Relaxed);
+
+        for (line, result) in grbl.completed.drain(..) {
+            if let (Some(i), Some(progress)) = (line.program_line, self.progress.lock().unwrap().as_mut()) {
+                progress.acknowledged = progress.acknowledged.max(i + 1);
+            }
+
+            if let Err(error) = result {
+                self.errors.lock().unwrap().push(GRBLErrorReport {
+                    error,
+                    line : line.message.trim_end().to_string(),
+                    program_line : line.program_line,
+                });
+            }
+        }
+
+        // the program is done once everything sent is acknowledged, even if preprocessing dropped its last lines
+        if self.gcode_iter.is_none() && grbl.pending.is_empty() {
+            if let Some(progress) = self.progress.lock().unwrap().as_mut().filter(|p| p.finished.is_none()) {
+                progress.acknowledged = progress.total_lines;
+                progress.finished = Some(Instant::now());
+            }
+        }
+
+        if grbl.status_changed {
+            // the program waits in a hold, or while the machine idles until the sender carries on
+            let holding = self.tool_change.lock().unwrap().is_some() || self.paused.load(Ordering::SeqCst);
+            let waiting = match grbl.machine_status.state {
+                MachineState::Hold(_) | MachineState::Door(_) => true,
+                MachineState::Idle => holding,
+                _ => false,
+            };
+            if let Some(progress) = self.progress.lock().unwrap().as_mut() {
+                progress.set_waiting(waiting);
+            }
+
+            let _ = self.status_sender.send(grbl.machine_status.clone());
+            self.status_history.lock().unwrap().push(MachineStatus::from(&grbl.machine_status));
+            grbl.status_changed = false;
+        }
 
-                *grbl_status.lock().unwrap() = grbl.machine_status.clone();
+        if grbl.info_changed {
+            *self.info.lock().unwrap() = grbl.info.clone();
+            grbl.info_changed = false;
+        }
+
+        if grbl.settings_changed {
+            *self.settings.lock().unwrap() = grbl.settings;
+            grbl.settings_changed = false;
+        }
+    }
+
+    fn connection_lost(&mut self, reason : String) {
+        log::warn!("connection lost: {}", reason);
+
+        if let Some(mut grbl) = self.connection.take() {
+            // keep the state from before an earlier loss if it was never restored
+            if self.restore_info.is_none() && grbl.info.parser_state.is_some() {
+                self.restore_info = Some(grbl.info.clone());
             }
-        })
+
+            self.recorder = grbl.recorder.take();
+        }
+
+        // whatever was in flight is gone, and GRBL has most likely been reset
+        if self.gcode_iter.is_some() {
+            self.interrupt_program("the lost connection");
+        }
+        self.gcode_iter = None;
+        self.validating = false;
+        self.cancel_tool_change();
+        self.command_queue.clear();
+        self.has_gcode.store(false, Ordering::Relaxed);
+
+        self.next_attempt = Instant::now() + RECONNECT_INTERVAL;
+        self.set_state(ConnectionState::Lost(reason));
+    }
+
+    /// Stops sending the program because of `cause`, e.g. an alarm, and keeps the reason for the UI.
+    /// Doesn't start a program, because it can't be sent as it is.
+    fn refuse_program(&self, reason : &str) {
+        log::warn!("program not started: {}", reason);
+        *self.program_stopped.lock().unwrap() = Some(format!("Not started, {}", reason));
+    }
+
+    fn interrupt_program(&mut self, cause : &str) {
+        let line = self.gcode_line.load(Ordering::Relaxed);
+        log::warn!("program stopped by {} after {} lines", cause, line);
+        *self.program_stopped.lock().unwrap() = Some(format!("Stopped by {} after line {}", cause, line));
+        stop_progress(&self.progress);
+
+        self.gcode_iter = None;
+        self.validating = false;
+        self.paused.store(false, Ordering::Relaxed);
+        self.cancel_tool_change();
+    }
+
+    fn cancel_tool_change(&mut self) {
+        *self.tool_change.lock().unwrap() = None;
+        self.tool_change_queue.clear();
+        self.tool_change_modal = None;
+    }
+}
+
+/// The Z offset of the active work coordinate system and G92 together, which work Z is set with.
+fn work_z(info : &GRBLInfo) -> Option<f32> {
+    let coordinate_system = info.parser_state.as_ref()?.coordinate_system as usize;
+    let wcs = info.offsets.work_coordinates.get(coordinate_system)?.as_slice().get(2).cloned()?;
+    let g92 = info.offsets.g92.as_slice().get(2).cloned().unwrap_or(0.0);
+    Some(wcs + g92)
+}
+
+/// Returns the commands that turn the spindle and coolant back on as they are in `modal`, and
+/// restore its motion mode, feed rate and units, which the tool change commands changed.
+fn tool_change_restore(modal : Option<&GCodeParserState>) -> Vec<String> {
+    let modal = match modal {
+        Some(modal) => modal,
+        None => {
+            log::warn!("the modal state before the tool change is unknown, the spindle and coolant stay off");
+            return vec![];
+        }
     };
 
-    GCodeTaskHandle {
-        grbl : grbl_status,
-        sender: tx,
-        paused,
-        has_gcode,
-        join,
-        gcode_line,
+    let mut commands = vec![];
+
+    match modal.spindle {
+        ParserSpindleState::CW  => commands.push(format!("M3 S{:.0}", modal.speed)),
+        ParserSpindleState::CCW => commands.push(format!("M4 S{:.0}", modal.speed)),
+        ParserSpindleState::Off => {}
+    }
+    if modal.spindle != ParserSpindleState::Off {
+        commands.push(format!("G4 P{}", SPINDLE_SPIN_UP_SECONDS));
+    }
+    if modal.flood_coolant { commands.push(String::from("M8")); }
+    if modal.mist_coolant  { commands.push(String::from("M7")); }
+
+    // arcs and probes can't be selected without moving, so they are canceled, and a line that
+    // only has coordinates fails instead of moving in the wrong mode. GRBL reports the feed rate
+    // in millimeters, so it is set before the units
+    let motion = match modal.motion_mode {
+        ParserMotionMode::Rapid  => "G0",
+        ParserMotionMode::Linear => "G1",
+        _                        => "G80",
+    };
+    commands.push(format!("{} F{}", motion, modal.feed));
+
+    if modal.units == ParserUnits::Inches {
+        commands.push(String::from("G20"));
     }
-}
\ No newline at end of file
+
+    commands
+}
+
+/// Freezes the elapsed time of the program, if it is still running.
+fn stop_progress(progress : &Mutex<Option<ProgramProgress>>) {
+    if let Some(progress) = progress.lock().unwrap().as_mut() {
+        progress.finished.get_or_insert_with(Instant::now);
+    }
+}
+
+/// Runs the lines of a program through `pipeline` for `dialect`, pairing every line sent with the
+/// index of the line it came from.
+fn preprocess(pipeline : &Pipeline, dialect : GRBLDialect, lines : &[String]) -> Result<Vec<(usize, String)>, String> {
+    let processed = pipeline.run_for(lines, dialect)?;
+
+    for warning in processed.warnings.iter() {
+        log::warn!("line {}: {}", warning.line + 1, warning.message);
+    }
+
+    Ok(processed.lines)
+}
+
+/// Puts the line number in front of each line of a program as an N word if `line_numbering` is true.
+fn numbered_lines(lines : Vec<(usize, String)>, line_numbering : bool) -> Peekable<std::vec::IntoIter<(usize, String)>> {
+    lines.into_iter()
+        .map(|(i, line)| (i, if line_numbering {with_line_number(&line, i + 1)} else {line}))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .peekable()
+}
+
+/// Puts `n` in front of `line` as its N word, replacing the one it has. Empty lines, system
+/// commands and numbers GRBL can't take are left alone.
+pub fn with_line_number(line : &str, n : usize) -> String {
+    let trimmed = line.trim_start();
+
+    if n > MAX_LINE_NUMBER || trimmed.trim_end().is_empty() || trimmed.starts_with(&['$', '%'][..]) {
+        return line.to_string();
+    }
+
+    let rest = match trimmed.strip_prefix(&['N', 'n'][..]) {
+        Some(rest) => rest.trim_start_matches(|c : char| c.is_ascii_digit()).trim_start(),
+        None => trimmed,
+    };
+
+    format!("N{} {}", n, rest)
+}
+
+/// Returns the commands that put a freshly reset GRBL back into the coordinate system described
+/// by `info`. The G92 offset isn't kept across a reset, so it is set again from the current position.
+fn restore_commands(info : &GRBLInfo, machine_position : AxisValues) -> Vec<String> {
+    let mut commands = vec![];
+
+    let coordinate_system = match info.parser_state {
+        Some(ref parser_state) => parser_state.coordinate_system as usize,
+        None => return commands,
+    };
+
+    commands.push(format!("G{}\n", 54 + coordinate_system));
+
+    let g92 = info.offsets.g92;
+
+    if g92.as_slice().iter().any(|&v| v != 0.0) {
+        let wcs = info.offsets.work_coordinates[coordinate_system];
+        let position = machine_position - wcs - g92;
+        let words = position.named()
+            .map(|(axis, value)| format!("{}{:.4}", axis, value))
+            .collect::<String>();
+        commands.push(format!("G92{}\n", words));
+    }
+
+    commands
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tool_change_restores_the_modal_state() {
+        let modal = GCodeParserState {
+            motion_mode : ParserMotionMode::Linear,
+            units : ParserUnits::Inches,
+            spindle : ParserSpindleState::CW,
+            flood_coolant : true,
+            feed : 250.0,
+            speed : 12000.0,
+            ..GCodeParserState::default()
+        };
+
+        assert_eq!(tool_change_restore(Some(&modal)), [
+            String::from("M3 S12000"),
+            format!("G4 P{}", SPINDLE_SPIN_UP_SECONDS),
+            String::from("M8"),
+            String::from("G1 F250"),
+            String::from("G20"),
+        ]);
+    }
+
+    #[test]
+    fn tool_change_cancels_an_arc() {
+        let modal = GCodeParserState {
+            motion_mode : ParserMotionMode::ArcCW,
+            feed : 100.0,
+            ..GCodeParserState::default()
+        };
+
+        assert_eq!(tool_change_restore(Some(&modal)), ["G80 F100"]);
+    }
+
+    #[test]
+    fn tool_change_moves_in_millimeters() {
+        let settings = ToolChangeSettings::default();
+        assert_eq!(settings.move_commands()[0], "G21");
+        assert_eq!(settings.probe_commands(false)[0], "G21");
+    }
+}
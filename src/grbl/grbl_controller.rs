@@ -0,0 +1,346 @@
+/*!
+ * This file contains the `Controller` implementation for GRBL and grblHAL,
+ * which translates the generic actions into GRBL's commands, and GRBL's
+ * status and feedback into the generic types.
+ */
+
+use std::collections::BTreeMap;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::Ordering;
+
+use tokio::runtime::Handle;
+
+use crate::controller::{Alarm, Controller, ControllerError, ControllerInfo, ErrorReport, Features, MachineStatus, OverrideStep, RapidOverride, RealtimeAction, StartupBlocks, ToolChange, ToolChangeSettings};
+use crate::preprocess::{Pipeline, Preprocessed};
+use crate::simulation::GcodeProgram;
+
+use super::*;
+
+/// Connects to GRBL at `endpoint`.
+pub fn connect_grbl(endpoint : String, baud_rate : u32, runtime : &Handle) -> Box<dyn Controller> {
+    Box::new(start_gcode_sender_task(endpoint, baud_rate, runtime))
+}
+
+impl From<RealtimeAction> for GRBLRealtimeCommand {
+    fn from(action : RealtimeAction) -> Self {
+        match action {
+            RealtimeAction::CycleStart                                => GRBLRealtimeCommand::CycleStartOrResume,
+            RealtimeAction::FeedHold                                  => GRBLRealtimeCommand::FeedHold,
+            RealtimeAction::Reset                                     => GRBLRealtimeCommand::SoftReset,
+            RealtimeAction::SafetyDoor                                => GRBLRealtimeCommand::SafetyDoor,
+            RealtimeAction::JogCancel                                 => GRBLRealtimeCommand::JogCancel,
+            RealtimeAction::FeedOverride(OverrideStep::Reset)         => GRBLRealtimeCommand::FeedOverrideReset,
+            RealtimeAction::FeedOverride(OverrideStep::Increase10)    => GRBLRealtimeCommand::FeedOverrideInc10,
+            RealtimeAction::FeedOverride(OverrideStep::Decrease10)    => GRBLRealtimeCommand::FeedOverrideDec10,
+            RealtimeAction::FeedOverride(OverrideStep::Increase1)     => GRBLRealtimeCommand::FeedOverrideInc01,
+            RealtimeAction::FeedOverride(OverrideStep::Decrease1)     => GRBLRealtimeCommand::FeedOverrideDec01,
+            RealtimeAction::SpindleOverride(OverrideStep::Reset)      => GRBLRealtimeCommand::SpindleOverrideReset,
+            RealtimeAction::SpindleOverride(OverrideStep::Increase10) => GRBLRealtimeCommand::SpindleOverrideInc10,
+            RealtimeAction::SpindleOverride(OverrideStep::Decrease10) => GRBLRealtimeCommand::SpindleOverrideDec10,
+            RealtimeAction::SpindleOverride(OverrideStep::Increase1)  => GRBLRealtimeCommand::SpindleOverrideInc01,
+            RealtimeAction::SpindleOverride(OverrideStep::Decrease1)  => GRBLRealtimeCommand::SpindleOverrideDec01,
+            RealtimeAction::RapidOverride(RapidOverride::Full)        => GRBLRealtimeCommand::RapidOverrideFull,
+            RealtimeAction::RapidOverride(RapidOverride::Half)        => GRBLRealtimeCommand::RapidOverrideHalf,
+            RealtimeAction::RapidOverride(RapidOverride::Quarter)     => GRBLRealtimeCommand::RapidOverrideQuarter,
+            RealtimeAction::SpindleStop                               => GRBLRealtimeCommand::SpindleToggle,
+            RealtimeAction::FloodToggle                               => GRBLRealtimeCommand::FloodToggle,
+            RealtimeAction::MistToggle                                => GRBLRealtimeCommand::MistToggle,
+        }
+    }
+}
+
+impl From<&GRBLStatus> for MachineStatus {
+    fn from(status : &GRBLStatus) -> Self {
+        Self {
+            machine_position : status.machine_position,
+            work_offset : status.work_offset,
+            state : status.state,
+            buffer_free_blocks : status.buffer_free_blocks,
+            buffer_free_bytes : status.buffer_free_bytes,
+            line_number : status.line_number,
+            feed : status.feed,
+            speed : status.speed,
+            override_feed : status.override_feed,
+            override_speed : status.override_speed,
+            override_rapid : status.override_rapid,
+            spindle_cw : status.spindle_cw,
+            spindle_ccw : status.spindle_ccw,
+            flood_coolant : status.flood_coolant,
+            mist_coolant : status.mist_coolant,
+            inputs : status.inputs,
+            tool : status.tool,
+            homed : status.homed,
+            sd_progress : status.sd_progress.clone(),
+            alarm : status.alarm.map(Alarm::from),
+            reset_required : status.reset_required,
+            restoring_spindle : status.restoring_spindle,
+        }
+    }
+}
+
+impl From<GRBLAlarm> for Alarm {
+    fn from(alarm : GRBLAlarm) -> Self {
+        Self {
+            name : alarm.to_string(),
+            description : alarm.description().to_string(),
+            recovery : alarm.recovery().to_vec(),
+        }
+    }
+}
+
+impl From<GRBLError> for ControllerError {
+    fn from(error : GRBLError) -> Self {
+        Self {
+            name : error.to_string(),
+            description : error.description().to_string(),
+        }
+    }
+}
+
+impl From<GRBLErrorReport> for ErrorReport {
+    fn from(report : GRBLErrorReport) -> Self {
+        Self {
+            error : report.error.into(),
+            line : report.line,
+            program_line : report.program_line,
+        }
+    }
+}
+
+impl From<BuildCapabilities> for Features {
+    fn from(capabilities : BuildCapabilities) -> Self {
+        let mut features = Features::empty();
+        features.set(Features::VARIABLE_SPINDLE, capabilities.contains(BuildCapabilities::VARIABLE_SPINDLE));
+        features.set(Features::LINE_NUMBERS,     capabilities.contains(BuildCapabilities::LINE_NUMBERS));
+        features.set(Features::MIST_COOLANT,     capabilities.contains(BuildCapabilities::MIST_COOLANT));
+        features
+    }
+}
+
+impl From<&GRBLInfo> for ControllerInfo {
+    fn from(info : &GRBLInfo) -> Self {
+        Self {
+            version : info.build_info.as_ref().map(|b| format!("{} {}", b.version, b.build)),
+            options : info.build_options.as_ref().map(|o| o.options.clone()),
+            planner_blocks : info.build_options.as_ref().and_then(|o| o.planner_blocks),
+            rx_buffer_size : info.rx_buffer_size(),
+            features : info.capabilities().map(Features::from),
+            parser_state : info.parser_state,
+            offsets : info.offsets,
+            probe : info.probe,
+        }
+    }
+}
+
+impl Controller for GCodeTaskHandle {
+    fn firmware(&self) -> String {
+        match self.get_info().dialect {
+            GRBLDialect::Grbl    => String::from("GRBL"),
+            GRBLDialect::GrblHAL => String::from("grblHAL"),
+        }
+    }
+
+    fn connection_state(&self) -> ConnectionState {
+        GCodeTaskHandle::connection_state(self)
+    }
+
+    fn is_ready(&self) -> bool {
+        GCodeTaskHandle::is_ready(self)
+    }
+
+    fn stop(self : Box<Self>) {
+        GCodeTaskHandle::stop(*self)
+    }
+
+    fn start_program(&self, program : GcodeProgram) -> bool {
+        GCodeTaskHandle::start_program(self, program)
+    }
+
+    fn start_program_from(&self, program : GcodeProgram, start : usize, lead_in : Vec<String>) -> bool {
+        GCodeTaskHandle::start_program_from(self, program, start, lead_in)
+    }
+
+    fn validate_program(&self, program : GcodeProgram) -> bool {
+        GCodeTaskHandle::validate_program(self, program)
+    }
+
+    fn stop_program(&self) {
+        GCodeTaskHandle::stop_program(self)
+    }
+
+    fn pause_program(&self) {
+        self.pause_gcode()
+    }
+
+    fn resume_program(&self) {
+        self.unpause_gcode()
+    }
+
+    fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn has_program(&self) -> bool {
+        self.has_gcode.load(Ordering::Relaxed)
+    }
+
+    fn program_stopped(&self) -> Option<String> {
+        self.get_program_stopped()
+    }
+
+    fn progress(&self) -> Option<ProgramProgress> {
+        self.get_progress()
+    }
+
+    fn program_line(&self) -> u64 {
+        self.gcode_line.load(Ordering::Relaxed)
+    }
+
+    fn set_streaming_mode(&self, mode : StreamingMode) {
+        GCodeTaskHandle::set_streaming_mode(self, mode)
+    }
+
+    fn set_poll_rates(&self, rates : PollRates) {
+        GCodeTaskHandle::set_poll_rates(self, rates)
+    }
+
+    fn set_pipeline(&self, pipeline : Pipeline) {
+        GCodeTaskHandle::set_pipeline(self, pipeline)
+    }
+
+    fn preprocess(&self, pipeline : &Pipeline, lines : &[String]) -> Result<Preprocessed, String> {
+        pipeline.run_for(lines, self.get_info().dialect)
+    }
+
+    fn set_line_numbering(&self, enabled : bool) {
+        GCodeTaskHandle::set_line_numbering(self, enabled)
+    }
+
+    fn set_tool_change_settings(&self, settings : ToolChangeSettings) {
+        GCodeTaskHandle::set_tool_change_settings(self, settings)
+    }
+
+    fn tool_change(&self) -> Option<ToolChange> {
+        self.get_tool_change()
+    }
+
+    fn continue_tool_change(&self) {
+        GCodeTaskHandle::continue_tool_change(self)
+    }
+
+    fn send_line(&self, line : String) -> bool {
+        self.send_string(line)
+    }
+
+    fn errors(&self) -> Vec<ErrorReport> {
+        self.get_errors().into_iter().map(ErrorReport::from).collect()
+    }
+
+    fn clear_errors(&self) {
+        GCodeTaskHandle::clear_errors(self)
+    }
+
+    fn machine_status(&self) -> MachineStatus {
+        MachineStatus::from(&self.get_machine_status())
+    }
+
+    fn info(&self) -> ControllerInfo {
+        ControllerInfo::from(&self.get_info())
+    }
+
+    fn refresh_info(&self) {
+        self.send_command(GRBLCommand::QueryParserState);
+        self.send_command(GRBLCommand::QueryGCodeParameters);
+    }
+
+    fn realtime(&self, action : RealtimeAction) {
+        self.send_realtime_command(action.into())
+    }
+
+    fn jog(&self, axes : [Option<f32>; MAX_AXES], feed : f32, incremental : bool) -> bool {
+        self.send_command(GRBLCommand::Jog {
+            axes,
+            feed,
+            incremental,
+            machine_coords : false,
+        })
+    }
+
+    fn home(&self) -> bool {
+        self.send_command(GRBLCommand::RunHomingCycle)
+    }
+
+    fn unlock(&self) -> bool {
+        self.send_command(GRBLCommand::KillAlarmLock)
+    }
+
+    fn settings(&self) -> ExtendedSettings {
+        let info = self.get_info();
+        match info.dialect {
+            GRBLDialect::GrblHAL => info.extended_settings,
+            GRBLDialect::Grbl    => self.get_settings().map(|s| s.to_extended_settings()).unwrap_or_default(),
+        }
+    }
+
+    fn fetch_settings(&self) -> bool {
+        if self.get_info().dialect == GRBLDialect::GrblHAL {
+            self.send_command(GRBLCommand::QuerySettingGroups);
+            self.send_command(GRBLCommand::QueryExtendedSettings);
+        }
+        self.send_command(GRBLCommand::QuerySettings)
+    }
+
+    fn write_settings(&self, changes : Vec<(u32, String)>) -> bool {
+        GCodeTaskHandle::write_settings(self, changes.into_iter()
+            .map(|(setting, value)| GRBLCommand::Setting {setting, value})
+            .collect())
+    }
+
+    fn settings_to_file(&self, values : &BTreeMap<u32, String>) -> Option<String> {
+        Some(settings_file_string(values))
+    }
+
+    fn settings_from_file(&self, contents : &str) -> Option<Result<Vec<(u32, String)>, String>> {
+        Some(parse_settings_file(contents))
+    }
+
+    fn startup_blocks(&self) -> Option<StartupBlocks> {
+        let info = self.get_info();
+        Some(StartupBlocks {
+            blocks : info.startup_blocks.to_vec(),
+            results : info.startup_results.into_iter()
+                .map(|(line, result)| (line, result.map_err(ControllerError::from)))
+                .collect(),
+        })
+    }
+
+    fn fetch_startup_blocks(&self) -> bool {
+        self.send_command(GRBLCommand::QueryStartupBlcoks)
+    }
+
+    fn validate_startup_block(&self, line : &str) -> Result<String, String> {
+        validate_startup_block(line)
+    }
+
+    fn write_startup_block(&self, index : usize, line : &str) -> Result<(), String> {
+        GCodeTaskHandle::write_startup_block(self, index, line)
+    }
+
+    fn status_history(&self) -> Arc<Mutex<StatusHistory>> {
+        self.status_history.clone()
+    }
+
+    fn traffic(&self) -> Arc<Mutex<TrafficLog>> {
+        self.traffic.clone()
+    }
+
+    fn start_recording(&self, path : &Path) -> io::Result<()> {
+        GCodeTaskHandle::start_recording(self, path)
+    }
+
+    fn stop_recording(&self) {
+        GCodeTaskHandle::stop_recording(self)
+    }
+}
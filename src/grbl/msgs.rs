@@ -1,6 +1,9 @@
 
 use super::*;
 
+use pest::Parser;
+use pest::iterators::Pair;
+
 #[derive(Parser)]
 #[grammar = "grammars/grbl.pest"]
 pub struct GRBLParser;
@@ -11,9 +14,12 @@ use bitflags::bitflags;
 bitflags! {
     #[derive(Default)]
     pub struct AxisMask : u8 {
-        const X = 0b001;
-        const Y = 0b010;
-        const Z = 0b100;
+        const X = 0b00_0001;
+        const Y = 0b00_0010;
+        const Z = 0b00_0100;
+        const A = 0b00_1000;
+        const B = 0b01_0000;
+        const C = 0b10_0000;
     }
 }
 
@@ -51,6 +57,13 @@ pub enum GRBLRealtimeCommand {
     SpindleToggle = 0x9E,
     FloodToggle = 0xA0,
     MistToggle = 0xA1,
+    // grblHAL only
+    Stop = 0x19,
+    ParserStateReport = 0x83,
+    StatusReportAll = 0x87,
+    OptionalStopToggle = 0x88,
+    SingleBlockToggle = 0x89,
+    ToolChangeAck = 0xA3,
 }
 
 #[derive(Debug, Clone)]
@@ -72,9 +85,8 @@ pub enum GRBLCommand {
     KillAlarmLock,        // "$X"
     RunHomingCycle,       // "$H"
     Jog {                 // "$J="
-        x : Option<f32>,
-        y : Option<f32>,
-        z : Option<f32>,
+        /// the target of each axis that moves, indexed like `AXIS_NAMES`
+        axes : [Option<f32>; MAX_AXES],
         feed : f32,
         incremental : bool,
         machine_coords : bool
@@ -83,6 +95,8 @@ pub enum GRBLCommand {
     ResetGCodeParameter,  // "$RST=#"
     ResetGRBL,            // "$RST=*"
     Sleep,                // "$SLP"
+    QueryExtendedSettings, // "$ES", grblHAL only
+    QuerySettingGroups,   // "$EG", grblHAL only
 }
 
 impl GRBLCommand {
@@ -100,21 +114,26 @@ impl GRBLCommand {
             GRBLCommand::ResetGCodeParameter  => {b"$RST=#\n".to_vec()}
             GRBLCommand::ResetGRBL            => {b"$RST=*\n".to_vec()}
             GRBLCommand::Sleep                => {b"$SLP\n"  .to_vec()}
+            GRBLCommand::QueryExtendedSettings => {b"$ES\n"   .to_vec()}
+            GRBLCommand::QuerySettingGroups   => {b"$EG\n"   .to_vec()}
             GRBLCommand::SetStartupBlock {index,line,} => {
-                format!("$N{}={}", index, line).into_bytes()
+                format!("$N{}={}\n", index, line).into_bytes()
             }
-            GRBLCommand::Jog{x, y, z, feed, incremental, machine_coords,} => {
-                format!("$J={}{}{}{}{}F{:.6}\n",
+            GRBLCommand::Jog{axes, feed, incremental, machine_coords,} => {
+                let targets = AXIS_NAMES.iter()
+                    .zip(axes.iter())
+                    .filter_map(|(name, v)| v.map(|v| format!("{}{:.6}", name, v)))
+                    .collect::<String>();
+
+                format!("$J={}{}{}F{:.6}\n",
                     if machine_coords {"G53"} else {""},
                     if incremental {"G91"} else {"G90"},
-                    x.map(|x| format!("X{:.6}", x)).unwrap_or_default(),
-                    y.map(|y| format!("Y{:.6}", y)).unwrap_or_default(),
-                    z.map(|z| format!("Z{:.6}", z)).unwrap_or_default(),
+                    targets,
                     feed
                 ).into_bytes()
             }
             GRBLCommand::Setting{setting, value,} => {
-                format!("${}={:.6}\n", setting, value).into_bytes()
+                format!("${}={}\n", setting, value).into_bytes()
             }
             _ => unimplemented!()
         }
@@ -122,22 +141,432 @@ impl GRBLCommand {
 }
 
 
+bitflags! {
+    /// Accessory states reported in the `A:` field of a status report.
+    #[derive(Default)]
+    pub struct Accessories : u8 {
+        const SPINDLE_CW    = 0b0001;
+        const SPINDLE_CCW   = 0b0010;
+        const FLOOD_COOLANT = 0b0100;
+        const MIST_COOLANT  = 0b1000;
+    }
+}
+
+bitflags! {
+    /// The compile time options reported by `$I` as letters in an `[OPT:...]` message.
+    #[derive(Default)]
+    pub struct BuildCapabilities : u32 {
+        const VARIABLE_SPINDLE        = 1 << 0;   // V
+        const LINE_NUMBERS            = 1 << 1;   // N
+        const MIST_COOLANT            = 1 << 2;   // M
+        const COREXY                  = 1 << 3;   // C
+        const PARKING                 = 1 << 4;   // P
+        const HOMING_FORCE_ORIGIN     = 1 << 5;   // Z
+        const HOMING_SINGLE_AXIS      = 1 << 6;   // H
+        const TWO_LIMIT_SWITCHES      = 1 << 7;   // T
+        const PROBE_FEED_OVERRIDE     = 1 << 8;   // A
+        const SPINDLE_DIR_AS_ENABLE   = 1 << 9;   // D
+        const SPINDLE_OFF_AT_ZERO     = 1 << 10;  // 0
+        const LIMIT_PIN_DEBOUNCE      = 1 << 11;  // S
+        const PARKING_OVERRIDE        = 1 << 12;  // R
+        const SAFETY_DOOR             = 1 << 13;  // +
+        const NO_RESTORE_ALL          = 1 << 14;  // *
+        const NO_RESTORE_SETTINGS     = 1 << 15;  // $
+        const NO_RESTORE_PARAMETERS   = 1 << 16;  // #
+        const NO_BUILD_INFO_WRITE     = 1 << 17;  // I
+        const NO_SYNC_ON_WRITE        = 1 << 18;  // E
+        const NO_SYNC_ON_OFFSET       = 1 << 19;  // W
+        const NO_HOMING_INIT_LOCK     = 1 << 20;  // L
+        const DUAL_AXIS               = 1 << 21;  // 2
+    }
+}
+
+impl BuildCapabilities {
+    /// Parses the option letters, ignoring the ones that aren't known.
+    pub fn from_codes(codes : &str) -> Self {
+        let mut capabilities = Self::empty();
+        for c in codes.chars() {
+            capabilities |= match c {
+                'V' => Self::VARIABLE_SPINDLE,
+                'N' => Self::LINE_NUMBERS,
+                'M' => Self::MIST_COOLANT,
+                'C' => Self::COREXY,
+                'P' => Self::PARKING,
+                'Z' => Self::HOMING_FORCE_ORIGIN,
+                'H' => Self::HOMING_SINGLE_AXIS,
+                'T' => Self::TWO_LIMIT_SWITCHES,
+                'A' => Self::PROBE_FEED_OVERRIDE,
+                'D' => Self::SPINDLE_DIR_AS_ENABLE,
+                '0' => Self::SPINDLE_OFF_AT_ZERO,
+                'S' => Self::LIMIT_PIN_DEBOUNCE,
+                'R' => Self::PARKING_OVERRIDE,
+                '+' => Self::SAFETY_DOOR,
+                '*' => Self::NO_RESTORE_ALL,
+                '$' => Self::NO_RESTORE_SETTINGS,
+                '#' => Self::NO_RESTORE_PARAMETERS,
+                'I' => Self::NO_BUILD_INFO_WRITE,
+                'E' => Self::NO_SYNC_ON_WRITE,
+                'W' => Self::NO_SYNC_ON_OFFSET,
+                'L' => Self::NO_HOMING_INIT_LOCK,
+                '2' => Self::DUAL_AXIS,
+                _ => Self::empty(),
+            };
+        }
+        capabilities
+    }
+}
+
+/// The fields of a `<...>` status report. GRBL only sends some fields in each report,
+/// so every field is optional.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GRBLStatusReport {
+    pub mstate         : Option<MachineState>,
+    pub mpos           : Option<AxisValues>,
+    pub wpos           : Option<AxisValues>,
+    pub wco            : Option<AxisValues>,
+    pub buffer_state   : Option<(u32, u32)>,
+    pub line_number    : Option<u32>,
+    pub feed           : Option<f32>,
+    pub feed_and_speed : Option<(f32, f32)>,
+    pub inputs         : Option<InputPins>,
+    pub overrides      : Option<(u32, u32, u32)>,
+    pub accessories    : Option<Accessories>,
+    /// grblHAL only: the tool in the spindle
+    pub tool           : Option<u32>,
+    /// grblHAL only: whether the machine has been homed
+    pub homed          : Option<bool>,
+    /// grblHAL only: the progress in percent of a job run from the SD card, and the file name
+    pub sd_progress    : Option<(f32, String)>,
+}
+
+/// The contents of a `[...]` feedback message.
+#[derive(Debug, Clone, PartialEq)]
+pub enum GRBLFeedback {
+    ResetToContinue,
+    Unlock,
+    CautionUnlocked,
+    Enabled,
+    Disabled,
+    CheckDoor,
+    CheckLimits,
+    ProgramEnd,
+    RestoringSpindle,
+    Sleeping,
+    ParserState(GCodeParserState),
+    Help(String),
+    Offset(GCodeOffset, AxisValues),
+    ToolLengthOffset(f32),
+    Probe(ProbeResult),
+    Version(BuildInfo),
+    Options(BuildOptions),
+    /// the firmware reported by grblHAL's `$I`, e.g. `grblHAL`
+    Firmware(String),
+    SettingGroup(SettingGroup),
+    SettingDescription(ExtendedSettingDescriptor),
+    Unknown(String),
+}
+
+/// A single line received from GRBL.
+#[derive(Debug, Clone, PartialEq)]
 pub enum GRBLMessage {
-    StatusMessage {
-        mstate         : Option<GRBLState>,
-        mpos           : Option<[f64; 3]>,
-        wpos           : Option<[f64; 3]>,
-        wco            : Option<bool>,
-        buffer_state   : Option<bool>,
-        line_number    : Option<bool>,
-        feed           : Option<bool>,
-        feed_and_speed : Option<bool>,
-        inputs         : Option<bool>,
-        overrides      : Option<bool>,
+    Ok,
+    Error(GRBLError),
+    StatusMessage(GRBLStatusReport),
+    FeedbackMessage(GRBLFeedback),
+    AlarmMessage(GRBLAlarm),
+    StartupLine {
+        line : String,
+        result : Result<(), GRBLError>,
+    },
+    WelcomeMessage {
+        version : String,
+        dialect : GRBLDialect,
+    },
+    SettingsMessage {
+        setting : u32,
+        value : String,
+    },
+    /// A stored startup block, as reported by `$N`.
+    StartupBlock {
+        index : usize,
+        line : String,
     },
-    FeedbackMessage,
-    AlarmMessage,
-    StartupLine,
-    WelcomeMessage,
-    SettingsMessage,
+    Unrecognized(String),
+}
+
+fn parse_floats<const N : usize>(pair : Pair<Rule>) -> [f32; N] {
+    let mut values = [0.0; N];
+    for (v, p) in values.iter_mut().zip(pair.into_inner()) {
+        *v = p.as_str().parse::<f32>().unwrap();
+    }
+    values
+}
+
+/// Parses the coordinates of a position or offset, one per axis.
+fn parse_axis_values(pair : Pair<Rule>) -> AxisValues {
+    let values = pair.into_inner()
+        .filter(|p| p.as_rule() == Rule::float)
+        .map(|p| p.as_str().parse::<f32>().unwrap())
+        .collect::<Vec<_>>();
+    AxisValues::new(&values)
+}
+
+fn parse_uints<const N : usize>(pair : Pair<Rule>) -> [u32; N] {
+    let mut values = [0; N];
+    for (v, p) in values.iter_mut().zip(pair.into_inner()) {
+        *v = p.as_str().parse::<f32>().unwrap() as u32;
+    }
+    values
+}
+
+impl GRBLMessage {
+    /// Parses one line received from GRBL. Returns `None` if the line is empty or incomplete.
+    pub fn parse(s : &str) -> Option<GRBLMessage> {
+
+        let msg = GRBLParser::parse(Rule::line, s).ok()?.next()?;
+
+        let message = match msg.as_rule() {
+            Rule::response_message => {
+                Self::parse_response(msg.into_inner().next()?)
+            }
+            Rule::push_message => {
+                let msg = msg.into_inner().next()?;
+                match msg.as_rule() {
+                    Rule::status_message => {
+                        let mut report = GRBLStatusReport::default();
+                        for item in msg.into_inner() {
+                            match item.as_rule() {
+                                Rule::mstate       => {
+                                    let inner = item.into_inner().next().unwrap();
+                                    let last = inner.as_str().chars().last().unwrap();
+                                    report.mstate = Some(match inner.as_rule() {
+                                        Rule::idle  => MachineState::Idle,
+                                        Rule::run   => MachineState::Run,
+                                        Rule::hold  => MachineState::Hold(last == '1'),
+                                        Rule::jog   => MachineState::Jog,
+                                        Rule::alarm => MachineState::Alarm,
+                                        Rule::door  => MachineState::Door(last as u8 - b'0'),
+                                        Rule::check => MachineState::Check,
+                                        Rule::home  => MachineState::Home,
+                                        Rule::sleep => MachineState::Sleep,
+                                        Rule::tool  => MachineState::Tool,
+                                        _ => unreachable!()
+                                    });
+                                }
+                                Rule::mpos           => {report.mpos = Some(parse_axis_values(item));}
+                                Rule::wpos           => {report.wpos = Some(parse_axis_values(item));}
+                                Rule::wco            => {report.wco  = Some(parse_axis_values(item));}
+                                Rule::buffer_state   => {
+                                    let [blocks, bytes] = parse_uints(item);
+                                    report.buffer_state = Some((blocks, bytes));
+                                }
+                                Rule::line_number    => {
+                                    let [n] = parse_uints(item);
+                                    report.line_number = Some(n);
+                                }
+                                Rule::feed           => {
+                                    let [feed] = parse_floats(item);
+                                    report.feed = Some(feed);
+                                }
+                                Rule::feed_and_speed => {
+                                    let [feed, speed] = parse_floats(item);
+                                    report.feed_and_speed = Some((feed, speed));
+                                }
+                                Rule::inputs         => {
+                                    let mut pins = InputPins::empty();
+                                    for pin in item.as_str().chars().skip(3) {
+                                        match pin {
+                                            'X' => {pins |= InputPins::X_LIMIT;}
+                                            'Y' => {pins |= InputPins::Y_LIMIT;}
+                                            'Z' => {pins |= InputPins::Z_LIMIT;}
+                                            'A' => {pins |= InputPins::A_LIMIT;}
+                                            'B' => {pins |= InputPins::B_LIMIT;}
+                                            'C' => {pins |= InputPins::C_LIMIT;}
+                                            'P' => {pins |= InputPins::PROBE;}
+                                            'D' => {pins |= InputPins::DOOR;}
+                                            'H' => {pins |= InputPins::HOLD;}
+                                            'R' => {pins |= InputPins::SOFT_RESET;}
+                                            'S' => {pins |= InputPins::CYCLE_START;}
+                                            _ => {}
+                                        }
+                                    }
+                                    report.inputs = Some(pins);
+                                }
+                                Rule::overrides      => {
+                                    let [f, r, s] = parse_uints(item);
+                                    report.overrides = Some((f, r, s));
+                                }
+                                Rule::accessories    => {
+                                    let mut accessories = Accessories::empty();
+                                    for acc in item.as_str().chars().skip(2) {
+                                        match acc {
+                                            'S' => {accessories |= Accessories::SPINDLE_CW;}
+                                            'C' => {accessories |= Accessories::SPINDLE_CCW;}
+                                            'F' => {accessories |= Accessories::FLOOD_COOLANT;}
+                                            'M' => {accessories |= Accessories::MIST_COOLANT;}
+                                            _ => {}
+                                        }
+                                    }
+                                    report.accessories = Some(accessories);
+                                }
+                                Rule::tool_number    => {
+                                    let [tool] = parse_uints(item);
+                                    report.tool = Some(tool);
+                                }
+                                Rule::homed          => {
+                                    let [homed] = parse_uints(item);
+                                    report.homed = Some(homed != 0);
+                                }
+                                Rule::sd_progress    => {
+                                    let mut inner = item.into_inner();
+                                    let progress = inner.next().unwrap().as_str().parse::<f32>().unwrap();
+                                    let file = inner.next().map(|f| f.as_str().to_string()).unwrap_or_default();
+                                    report.sd_progress = Some((progress, file));
+                                }
+                                Rule::unknown_status_item => {}
+                                _ => unreachable!()
+                            }
+                        }
+                        GRBLMessage::StatusMessage(report)
+                    }
+                    Rule::feedback_message => {
+                        let inner = msg.into_inner().next()?;
+                        let text = inner.as_str();
+                        // the text following the `XXX:` prefix
+                        let value = || text.splitn(2, ':').nth(1).unwrap_or("").to_string();
+                        GRBLMessage::FeedbackMessage(match inner.as_rule() {
+                            Rule::msg_reset_to_continue => GRBLFeedback::ResetToContinue,
+                            Rule::msg_unlock            => GRBLFeedback::Unlock,
+                            Rule::msg_caution           => GRBLFeedback::CautionUnlocked,
+                            Rule::msg_enabled           => GRBLFeedback::Enabled,
+                            Rule::msg_disabled          => GRBLFeedback::Disabled,
+                            Rule::msg_check_door        => GRBLFeedback::CheckDoor,
+                            Rule::msg_check_limits      => GRBLFeedback::CheckLimits,
+                            Rule::msg_program_end       => GRBLFeedback::ProgramEnd,
+                            Rule::msg_restoring_spindle => GRBLFeedback::RestoringSpindle,
+                            Rule::msg_sleeping          => GRBLFeedback::Sleeping,
+                            Rule::gcode_parser_state    => GRBLFeedback::ParserState(GCodeParserState::parse(&value())),
+                            Rule::help_message          => GRBLFeedback::Help(value()),
+                            Rule::data_query_response   => Self::parse_data_query(inner.into_inner().next()?)?,
+                            Rule::msg_version           => {
+                                // the build string follows the version, e.g. `VER:1.1h.20190830:my machine`
+                                let value = value();
+                                let mut parts = value.splitn(2, ':');
+                                GRBLFeedback::Version(BuildInfo {
+                                    version : parts.next().unwrap_or("").to_string(),
+                                    build : parts.next().unwrap_or("").to_string(),
+                                })
+                            }
+                            Rule::msg_options           => {
+                                // the option codes are followed by the planner and rx buffer sizes, e.g. `OPT:V,15,128`
+                                let value = value();
+                                let mut parts = value.split(',');
+                                let options = parts.next().unwrap_or("").to_string();
+                                GRBLFeedback::Options(BuildOptions {
+                                    capabilities : BuildCapabilities::from_codes(&options),
+                                    options,
+                                    planner_blocks : parts.next().and_then(|v| v.parse::<u32>().ok()),
+                                    rx_buffer_size : parts.next().and_then(|v| v.parse::<u32>().ok()),
+                                })
+                            }
+                            Rule::msg_firmware          => GRBLFeedback::Firmware(value()),
+                            Rule::msg_setting_group     => GRBLFeedback::SettingGroup(SettingGroup::parse(&value())?),
+                            Rule::msg_setting_description => GRBLFeedback::SettingDescription(ExtendedSettingDescriptor::parse(&value())?),
+                            Rule::msg_unknown           => GRBLFeedback::Unknown(text.to_string()),
+                            _ => unreachable!()
+                        })
+                    }
+                    Rule::alarm_message => {
+                        GRBLMessage::AlarmMessage(GRBLAlarm::from_code(msg.as_str()["ALARM:".len()..].parse::<u8>().ok()?))
+                    }
+                    Rule::startup_line => {
+                        let mut inner = msg.into_inner();
+                        let line = inner.next()?.as_str().to_string();
+                        let result = match Self::parse_response(inner.next()?.into_inner().next()?) {
+                            GRBLMessage::Error(error) => Err(error),
+                            _ => Ok(()),
+                        };
+                        GRBLMessage::StartupLine {
+                            line,
+                            result,
+                        }
+                    }
+                    Rule::welcome_message => {
+                        let mut inner = msg.into_inner();
+                        let dialect = match inner.next()?.as_str() {
+                            "GrblHAL" => GRBLDialect::GrblHAL,
+                            _         => GRBLDialect::Grbl,
+                        };
+                        GRBLMessage::WelcomeMessage {
+                            version : inner.next()?.as_str().to_string(),
+                            dialect,
+                        }
+                    }
+                    Rule::startup_block => {
+                        let mut inner = msg.into_inner();
+                        GRBLMessage::StartupBlock {
+                            index : inner.next()?.as_str().parse::<usize>().ok()?,
+                            line : inner.next()?.as_str().trim_end().to_string(),
+                        }
+                    }
+                    Rule::settings_message => {
+                        let mut inner = msg.into_inner();
+                        GRBLMessage::SettingsMessage {
+                            setting : inner.next()?.as_str().parse::<u32>().ok()?,
+                            value : inner.next()?.as_str().trim().to_string(),
+                        }
+                    }
+                    _ => unreachable!()
+                }
+            }
+            Rule::unrecognized_message => {
+                let text = msg.as_str().trim_end();
+                if text.is_empty() {
+                    return None;
+                }
+                GRBLMessage::Unrecognized(text.to_string())
+            }
+            _ => unreachable!()
+        };
+
+        Some(message)
+    }
+
+    fn parse_data_query(msg : Pair<Rule>) -> Option<GRBLFeedback> {
+        let feedback = match msg.as_rule() {
+            Rule::coordinate_offset => {
+                let offset = match msg.clone().into_inner().next()?.as_str() {
+                    "G28" => GCodeOffset::G28,
+                    "G30" => GCodeOffset::G30,
+                    "G92" => GCodeOffset::G92,
+                    name  => GCodeOffset::WorkCoordinates(name[1..].parse::<u8>().ok()? - 54),
+                };
+                GRBLFeedback::Offset(offset, parse_axis_values(msg))
+            }
+            Rule::tool_length_offset => {
+                let [tlo] = parse_floats(msg);
+                GRBLFeedback::ToolLengthOffset(tlo)
+            }
+            Rule::probe_result => {
+                let success = msg.as_str().ends_with('1');
+                GRBLFeedback::Probe(ProbeResult {
+                    position : parse_axis_values(msg),
+                    success,
+                })
+            }
+            _ => unreachable!()
+        };
+        Some(feedback)
+    }
+
+    fn parse_response(msg : Pair<Rule>) -> GRBLMessage {
+        match msg.as_rule() {
+            Rule::ok => GRBLMessage::Ok,
+            Rule::error => {
+                let [code] = parse_uints(msg);
+                GRBLMessage::Error(GRBLError::from_code(code as u8))
+            }
+            _ => unreachable!()
+        }
+    }
 }
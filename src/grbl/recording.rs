@@ -0,0 +1,222 @@
+/*!
+ * This file contains the recording of a session with GRBL, and the transport
+ * that plays a recording back.
+ *
+ * A recording is a text file with one chunk of bytes per line, e.g.
+ *
+ *     0.012345 > ?
+ *     0.020001 < <Idle|MPos:0.000,0.000,0.000|FS:0,0>\r\n
+ *
+ * where `>` marks bytes sent to GRBL and `<` bytes received from it. The
+ * bytes are escaped like a Rust byte string, so that any byte survives the
+ * round trip. Recordings are replayed with the `replay://path` endpoint.
+ */
+
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use super::Transport;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecordDirection {
+    Sent,
+    Received,
+}
+
+impl RecordDirection {
+    fn marker(self) -> char {
+        match self {
+            RecordDirection::Sent     => '>',
+            RecordDirection::Received => '<',
+        }
+    }
+}
+
+/// A chunk of bytes read from a recording.
+#[derive(Debug, Clone)]
+pub struct RecordedChunk {
+    /// time since the recording was started
+    pub time : Duration,
+    pub direction : RecordDirection,
+    pub bytes : Vec<u8>,
+}
+
+/// Writes every byte exchanged with GRBL to a file.
+pub struct SessionRecorder {
+    start : Instant,
+    file : BufWriter<File>,
+}
+
+impl SessionRecorder {
+    pub fn create<P : AsRef<Path>>(path : P) -> io::Result<Self> {
+        Ok(Self {
+            start : Instant::now(),
+            file : BufWriter::new(File::create(path)?),
+        })
+    }
+
+    pub fn record(&mut self, direction : RecordDirection, bytes : &[u8]) -> io::Result<()> {
+        if bytes.is_empty() {
+            return Ok(());
+        }
+
+        let escaped = bytes.iter()
+            .flat_map(|&b| std::ascii::escape_default(b))
+            .map(char::from)
+            .collect::<String>();
+
+        writeln!(self.file, "{:.6} {} {}", self.start.elapsed().as_secs_f64(), direction.marker(), escaped)?;
+
+        // flush every chunk, so that the recording is complete even if the program crashes
+        self.file.flush()
+    }
+}
+
+/// Reads a recording written by `SessionRecorder`.
+pub fn read_recording<P : AsRef<Path>>(path : P) -> Result<Vec<RecordedChunk>, Box<dyn Error>> {
+    let mut chunks = vec![];
+
+    for (i, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let mut parts = line.splitn(3, ' ');
+
+        let time = parts.next()
+            .and_then(|t| t.parse::<f64>().ok())
+            .ok_or_else(|| format!("line {}: invalid timestamp", i + 1))?;
+
+        let direction = match parts.next() {
+            Some(">") => RecordDirection::Sent,
+            Some("<") => RecordDirection::Received,
+            _ => return Err(format!("line {}: invalid direction", i + 1).into()),
+        };
+
+        let bytes = unescape(parts.next().unwrap_or(""))
+            .ok_or_else(|| format!("line {}: invalid escape sequence", i + 1))?;
+
+        chunks.push(RecordedChunk {
+            time : Duration::from_secs_f64(time),
+            direction,
+            bytes,
+        });
+    }
+
+    Ok(chunks)
+}
+
+/// Reverses `std::ascii::escape_default`.
+fn unescape(s : &str) -> Option<Vec<u8>> {
+    let mut bytes = vec![];
+    let mut iter = s.bytes();
+
+    while let Some(b) = iter.next() {
+        if b != b'\\' {
+            bytes.push(b);
+            continue;
+        }
+
+        match iter.next()? {
+            b'n' => bytes.push(b'\n'),
+            b'r' => bytes.push(b'\r'),
+            b't' => bytes.push(b'\t'),
+            b'x' => {
+                let hex = [iter.next()?, iter.next()?];
+                bytes.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+            }
+            c => bytes.push(c),
+        }
+    }
+
+    Some(bytes)
+}
+
+/// Plays back the bytes received in a recording, at the pace they were recorded.
+/// Anything written to it is discarded.
+pub struct ReplayTransport {
+    name : String,
+    start : Instant,
+    chunks : VecDeque<RecordedChunk>,
+    read_buffer : Vec<u8>,
+}
+
+impl ReplayTransport {
+    pub fn open(path : &str) -> Result<Self, Box<dyn Error>> {
+        let chunks = read_recording(path)?;
+
+        // start playing at the first received chunk rather than making the user wait for it
+        let offset = chunks.iter()
+            .find(|c| c.direction == RecordDirection::Received)
+            .map(|c| c.time)
+            .unwrap_or_default();
+
+        let chunks = chunks.into_iter()
+            .filter(|c| c.direction == RecordDirection::Received)
+            .map(|c| RecordedChunk {time : c.time - offset, ..c})
+            .collect();
+
+        Ok(Self {
+            name : format!("replay://{}", path),
+            start : Instant::now(),
+            chunks,
+            read_buffer : vec![],
+        })
+    }
+}
+
+impl Read for ReplayTransport {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        let elapsed = self.start.elapsed();
+
+        while self.chunks.front().map(|c| c.time <= elapsed).unwrap_or(false) {
+            let chunk = self.chunks.pop_front().unwrap();
+            self.read_buffer.extend(chunk.bytes);
+
+            if self.chunks.is_empty() {
+                log::info!("replay of {} finished.", self.name);
+            }
+        }
+
+        if self.read_buffer.is_empty() {
+            // the replay stays connected once it has finished, so that the final state can be inspected
+            let wait = self.chunks.front()
+                .map(|c| c.time - elapsed)
+                .unwrap_or(super::TRANSPORT_READ_TIMEOUT)
+                .min(super::TRANSPORT_READ_TIMEOUT);
+            std::thread::sleep(wait);
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        let n = buf.len().min(self.read_buffer.len());
+        buf[..n].copy_from_slice(&self.read_buffer[..n]);
+        self.read_buffer.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for ReplayTransport {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for ReplayTransport {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn split(self : Box<Self>) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        Ok((self, Box::new(io::sink())))
+    }
+}
@@ -0,0 +1,221 @@
+/*!
+ * This file contains the descriptions of GRBL's `$x` settings, used to validate
+ * edited values, and helpers to save and restore a complete set of settings.
+ */
+
+use std::collections::BTreeMap;
+use std::error::Error;
+
+use super::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SettingKind {
+    Boolean,
+    Mask,
+    Integer,
+    Float,
+}
+
+/// Describes a single GRBL setting.
+#[derive(Debug, Clone, Copy)]
+pub struct SettingDescriptor {
+    pub setting : u32,
+    pub name : &'static str,
+    pub unit : &'static str,
+    pub kind : SettingKind,
+    pub min : f32,
+    pub max : f32,
+}
+
+const fn setting(setting : u32, name : &'static str, unit : &'static str, kind : SettingKind, min : f32, max : f32) -> SettingDescriptor {
+    SettingDescriptor {setting, name, unit, kind, min, max}
+}
+
+/// The settings of GRBL 1.1, in the order they are reported by `$$`. The settings of the
+/// A, B and C axes are only reported by controllers with more than three axes.
+pub const GRBL_SETTINGS : [SettingDescriptor; 46] = [
+    setting(0,   "Step pulse time",                 "usec",     SettingKind::Integer, 3.0, 255.0),
+    setting(1,   "Step idle delay",                 "msec",     SettingKind::Integer, 0.0, 255.0),
+    setting(2,   "Step pulse invert",               "mask",     SettingKind::Mask,    0.0, 63.0),
+    setting(3,   "Step direction invert",           "mask",     SettingKind::Mask,    0.0, 63.0),
+    setting(4,   "Invert step enable pin",          "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(5,   "Invert limit pins",               "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(6,   "Invert probe pin",                "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(10,  "Status report options",           "mask",     SettingKind::Mask,    0.0, 3.0),
+    setting(11,  "Junction deviation",              "mm",       SettingKind::Float,   0.0, 1.0),
+    setting(12,  "Arc tolerance",                   "mm",       SettingKind::Float,   0.0, 1.0),
+    setting(13,  "Report in inches",                "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(20,  "Soft limits enable",              "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(21,  "Hard limits enable",              "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(22,  "Homing cycle enable",             "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(23,  "Homing direction invert",         "mask",     SettingKind::Mask,    0.0, 63.0),
+    setting(24,  "Homing locate feed rate",         "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(25,  "Homing search seek rate",         "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(26,  "Homing switch debounce delay",    "msec",     SettingKind::Integer, 0.0, 65_535.0),
+    setting(27,  "Homing switch pull-off distance", "mm",       SettingKind::Float,   0.0, 1_000.0),
+    setting(30,  "Maximum spindle speed",           "RPM",      SettingKind::Float,   0.0, 100_000.0),
+    setting(31,  "Minimum spindle speed",           "RPM",      SettingKind::Float,   0.0, 100_000.0),
+    setting(32,  "Laser-mode enable",               "boolean",  SettingKind::Boolean, 0.0, 1.0),
+    setting(100, "X-axis travel resolution",        "step/mm",  SettingKind::Float,   0.0, 100_000.0),
+    setting(101, "Y-axis travel resolution",        "step/mm",  SettingKind::Float,   0.0, 100_000.0),
+    setting(102, "Z-axis travel resolution",        "step/mm",  SettingKind::Float,   0.0, 100_000.0),
+    setting(103, "A-axis travel resolution",        "step/mm",  SettingKind::Float,   0.0, 100_000.0),
+    setting(104, "B-axis travel resolution",        "step/mm",  SettingKind::Float,   0.0, 100_000.0),
+    setting(105, "C-axis travel resolution",        "step/mm",  SettingKind::Float,   0.0, 100_000.0),
+    setting(110, "X-axis maximum rate",             "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(111, "Y-axis maximum rate",             "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(112, "Z-axis maximum rate",             "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(113, "A-axis maximum rate",             "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(114, "B-axis maximum rate",             "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(115, "C-axis maximum rate",             "mm/min",   SettingKind::Float,   0.0, 100_000.0),
+    setting(120, "X-axis acceleration",             "mm/sec^2", SettingKind::Float,   0.0, 100_000.0),
+    setting(121, "Y-axis acceleration",             "mm/sec^2", SettingKind::Float,   0.0, 100_000.0),
+    setting(122, "Z-axis acceleration",             "mm/sec^2", SettingKind::Float,   0.0, 100_000.0),
+    setting(123, "A-axis acceleration",             "mm/sec^2", SettingKind::Float,   0.0, 100_000.0),
+    setting(124, "B-axis acceleration",             "mm/sec^2", SettingKind::Float,   0.0, 100_000.0),
+    setting(125, "C-axis acceleration",             "mm/sec^2", SettingKind::Float,   0.0, 100_000.0),
+    setting(130, "X-axis maximum travel",           "mm",       SettingKind::Float,   0.0, 100_000.0),
+    setting(131, "Y-axis maximum travel",           "mm",       SettingKind::Float,   0.0, 100_000.0),
+    setting(132, "Z-axis maximum travel",           "mm",       SettingKind::Float,   0.0, 100_000.0),
+    setting(133, "A-axis maximum travel",           "mm",       SettingKind::Float,   0.0, 100_000.0),
+    setting(134, "B-axis maximum travel",           "mm",       SettingKind::Float,   0.0, 100_000.0),
+    setting(135, "C-axis maximum travel",           "mm",       SettingKind::Float,   0.0, 100_000.0),
+];
+
+impl SettingDescriptor {
+    pub fn find(setting : u32) -> Option<&'static SettingDescriptor> {
+        GRBL_SETTINGS.iter().find(|d| d.setting == setting)
+    }
+
+    /// Checks that `value` is valid for this setting, and returns it in the format GRBL expects.
+    pub fn validate(&self, value : &str) -> Result<String, String> {
+        let value = value.trim();
+        let number = value.parse::<f32>()
+            .map_err(|_| format!("${} must be a number", self.setting))?;
+
+        if number < self.min || number > self.max {
+            return Err(format!("${} must be between {} and {} {}", self.setting, self.min, self.max, self.unit));
+        }
+
+        match self.kind {
+            SettingKind::Float => Ok(format!("{}", number)),
+            _ if number.fract() != 0.0 => Err(format!("${} must be a whole number", self.setting)),
+            _ => Ok(format!("{}", number as u32)),
+        }
+    }
+}
+
+impl GRBLSettings {
+    /// Returns the value of a setting, formatted the way it is sent to GRBL.
+    pub fn value(&self, setting : u32) -> Option<String> {
+        self.set_all_command_list().iter()
+            .find_map(|cmd| match cmd {
+                GRBLCommand::Setting{setting : s, value} if *s == setting => Some(value.clone()),
+                _ => None,
+            })
+    }
+
+    /// Returns the commands needed to change the settings in `original` into these settings.
+    pub fn changed_commands(&self, original : &GRBLSettings) -> Vec<GRBLCommand> {
+        self.set_all_command_list().into_iter()
+            .filter(|cmd| match cmd {
+                GRBLCommand::Setting{setting, value} => original.value(*setting).as_ref() != Some(value),
+                _ => false,
+            })
+            .collect()
+    }
+
+    /// Describes the settings the way grblHAL does, so that they can be edited like any other controller's.
+    pub fn to_extended_settings(&self) -> ExtendedSettings {
+        let mut extended = ExtendedSettings::default();
+
+        extended.add_group(SettingGroup {group : 0, parent : 0, name : String::from("General")});
+        extended.add_group(SettingGroup {group : 1, parent : 0, name : String::from("Axes")});
+
+        for d in GRBL_SETTINGS.iter() {
+            let value = match self.value(d.setting) {
+                Some(value) => value,
+                None => continue,
+            };
+
+            let (kind, format) = match (d.kind, d.setting) {
+                (SettingKind::Boolean, _) => (ExtendedSettingType::Boolean, ""),
+                (SettingKind::Mask, 10)   => (ExtendedSettingType::Bitfield, "Machine position,Buffer data"),
+                (SettingKind::Mask, _)    => (ExtendedSettingType::AxisMask, ""),
+                (SettingKind::Integer, _) => (ExtendedSettingType::Integer, ""),
+                (SettingKind::Float, _)   => (ExtendedSettingType::Decimal, ""),
+            };
+
+            extended.describe(ExtendedSettingDescriptor {
+                setting : d.setting,
+                group : if d.setting < 100 {0} else {1},
+                name : d.name.to_string(),
+                unit : d.unit.to_string(),
+                kind,
+                format : format.to_string(),
+                min : Some(d.min as f64),
+                max : Some(d.max as f64),
+            });
+            extended.values.insert(d.setting, value);
+        }
+
+        extended
+    }
+
+    /// Writes the settings in the same `$x=value` format that `$$` uses.
+    pub fn to_file_string(&self) -> String {
+        self.set_all_command_list().iter()
+            .filter_map(|cmd| match cmd {
+                GRBLCommand::Setting{setting, value} => Some(format!("${}={}\n", setting, value)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Applies settings written by `to_file_string`, or saved from the output of `$$`.
+    /// Settings missing from the file are left unchanged.
+    pub fn apply_file_string(&mut self, s : &str) -> Result<(), Box<dyn Error>> {
+        let mut settings = *self;
+
+        for (setting, value) in parse_settings_file(s)? {
+            let descriptor = SettingDescriptor::find(setting)
+                .ok_or_else(|| format!("unknown setting: ${}", setting))?;
+            let value = descriptor.validate(&value)?;
+
+            settings.parse_setting(setting as u8, &value)?;
+        }
+
+        *self = settings;
+        Ok(())
+    }
+}
+
+/// Reads the `$x=value` lines of a settings file, as written by `to_file_string` or `$$`.
+/// The values aren't checked against any descriptor.
+pub fn parse_settings_file(s : &str) -> Result<Vec<(u32, String)>, String> {
+    s.lines()
+        .map(|l| l.trim())
+        .filter(|l| !l.is_empty())
+        .map(|line| {
+            let (setting, value) = line.strip_prefix('$')
+                .and_then(|l| {
+                    let mut parts = l.splitn(2, '=');
+                    Some((parts.next()?, parts.next()?))
+                })
+                .ok_or_else(|| format!("invalid setting line: {:?}", line))?;
+
+            // drop any trailing comment, e.g. `$0=10 (step pulse, usec)`
+            let value = value.split_whitespace().next().unwrap_or("");
+
+            let setting = setting.parse::<u32>().map_err(|_| format!("invalid setting number: ${}", setting))?;
+            Ok((setting, value.to_string()))
+        })
+        .collect()
+}
+
+/// Writes setting values in the `$x=value` format that `$$` uses.
+pub fn settings_file_string(values : &BTreeMap<u32, String>) -> String {
+    values.iter()
+        .map(|(setting, value)| format!("${}={}\n", setting, value))
+        .collect()
+}
@@ -0,0 +1,57 @@
+/*!
+ * This file contains the checks applied to startup blocks (`$N0` and `$N1`)
+ * before they are stored. GRBL runs these lines after every reset, so they
+ * may only set modal state and must never move the machine or start the spindle.
+ */
+
+use pest::Parser;
+
+use crate::gcode::{GCodeParser, Rule};
+
+/// The number of startup blocks GRBL stores.
+pub const STARTUP_BLOCK_COUNT : usize = 2;
+
+/// The longest line GRBL stores in EEPROM, after removing spaces.
+pub const STARTUP_BLOCK_MAX_LEN : usize = 79;
+
+/// Checks that `line` is safe to store as a startup block, and returns it as it should be sent.
+/// An empty line is valid, and clears the block.
+pub fn validate_startup_block(line : &str) -> Result<String, String> {
+    let line = line.trim();
+
+    if line.is_empty() {
+        return Ok(String::new());
+    }
+
+    if line.chars().filter(|c| !c.is_whitespace()).count() > STARTUP_BLOCK_MAX_LEN {
+        return Err(format!("startup blocks are limited to {} characters", STARTUP_BLOCK_MAX_LEN));
+    }
+
+    let source = format!("{}\n", line);
+    let parsed = GCodeParser::parse(Rule::line, &source)
+        .map_err(|_| String::from("not a valid line of G-code"))?
+        .next()
+        .ok_or_else(|| String::from("not a valid line of G-code"))?;
+
+    for word in parsed.into_inner() {
+        let word = word.as_str();
+        let letter = word.chars().next().unwrap_or(' ').to_ascii_uppercase();
+        let number = word[1..].parse::<f32>().unwrap_or(-1.0);
+
+        let forbidden = match letter {
+            // axis and arc words would move the machine after every reset
+            'X' | 'Y' | 'Z' | 'A' | 'B' | 'C' | 'I' | 'J' | 'K' | 'R' | 'P' | 'L' => true,
+            // dwell, offset writes, homing moves, machine coordinate moves and G92 offsets
+            'G' => [4.0, 10.0, 28.0, 28.1, 30.0, 30.1, 53.0, 92.0, 92.1].contains(&number),
+            // program flow, spindle, tool change and coolant commands
+            'M' => [0.0, 1.0, 2.0, 30.0, 3.0, 4.0, 6.0, 7.0, 8.0].contains(&number),
+            _ => false,
+        };
+
+        if forbidden {
+            return Err(format!("{} is not allowed in a startup block", word.to_ascii_uppercase()));
+        }
+    }
+
+    Ok(line.to_string())
+}
@@ -1,29 +1,12 @@
 use super::*;
 
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
-pub enum GRBLState {
-    Idle,
-    Run,
-    Hold(bool),
-    Jog,
-    Alarm,
-    Door(u8),
-    Check,
-    Home,
-    Sleep,
-}
+use std::error::Error;
 
-impl Default for GRBLState {
-    fn default() -> Self {
-        Self::Idle
-    }
-}
-
-#[derive(Debug, Default, Copy, Clone)]
+#[derive(Debug, Default, Clone)]
 pub struct GRBLStatus {
-    pub machine_position : [f32; 3],
-    pub work_offset : [f32; 3],
-    pub state : GRBLState,
+    pub machine_position : AxisValues,
+    pub work_offset : AxisValues,
+    pub state : MachineState,
     pub buffer_free_blocks : u32,
     pub buffer_free_bytes : u32,
     pub line_number : u32,
@@ -36,11 +19,209 @@ pub struct GRBLStatus {
     pub spindle_ccw : bool,
     pub flood_coolant : bool,
     pub mist_coolant : bool,
+    pub inputs : InputPins,
+    /// grblHAL only: the tool in the spindle
+    pub tool : Option<u32>,
+    /// grblHAL only: whether the machine has been homed
+    pub homed : Option<bool>,
+    /// grblHAL only: the progress of a job run from the SD card, and the file name
+    pub sd_progress : Option<(f32, String)>,
+    /// the last alarm, until GRBL leaves the alarm state
+    pub alarm : Option<GRBLAlarm>,
+    /// set when GRBL asks for a soft reset before the alarm can be cleared
+    pub reset_required : bool,
+    /// set from `[MSG:Restoring spindle]` until the machine leaves the hold or door state
+    pub restoring_spindle : bool,
+}
+
+impl GRBLStatus {
+    /// Updates the status with the fields present in a status report.
+    pub fn update(&mut self, report : &GRBLStatusReport) {
+        if let Some(state) = report.mstate {
+            self.state = state;
+
+            // unlocking or homing clears the alarm
+            if state != MachineState::Alarm {
+                self.alarm = None;
+                self.reset_required = false;
+            }
+
+            if !matches!(state, MachineState::Hold(_) | MachineState::Door(_)) {
+                self.restoring_spindle = false;
+            }
+        }
+        if let Some(wco) = report.wco {
+            self.work_offset = wco;
+        }
+        if let Some(mpos) = report.mpos {
+            self.machine_position = mpos;
+        }
+        if let Some(wpos) = report.wpos {
+            self.machine_position = wpos + self.work_offset;
+        }
+        if let Some((blocks, bytes)) = report.buffer_state {
+            self.buffer_free_blocks = blocks;
+            self.buffer_free_bytes = bytes;
+        }
+        // GRBL only reports the line number while it executes a numbered line
+        self.line_number = report.line_number.unwrap_or(0);
+        if let Some(feed) = report.feed {
+            self.feed = feed;
+        }
+        if let Some((feed, speed)) = report.feed_and_speed {
+            self.feed = feed;
+            self.speed = speed;
+        }
+        // the pin field is only sent while at least one pin is triggered
+        self.inputs = report.inputs.unwrap_or_default();
+        if let Some((f, r, s)) = report.overrides {
+            self.override_feed = f;
+            self.override_rapid = r;
+            self.override_speed = s;
+        }
+        // accessories are reported alongside the overrides, and omitted when nothing is on
+        if report.accessories.is_some() || report.overrides.is_some() {
+            let accessories = report.accessories.unwrap_or_default();
+            self.spindle_cw = accessories.contains(Accessories::SPINDLE_CW);
+            self.spindle_ccw = accessories.contains(Accessories::SPINDLE_CCW);
+            self.flood_coolant = accessories.contains(Accessories::FLOOD_COOLANT);
+            self.mist_coolant = accessories.contains(Accessories::MIST_COOLANT);
+        }
+        if let Some(tool) = report.tool {
+            self.tool = Some(tool);
+        }
+        if let Some(homed) = report.homed {
+            self.homed = Some(homed);
+        }
+        // the SD card field is only sent while a job is running from the card
+        self.sd_progress = report.sd_progress.clone();
+    }
+}
+
+
+
+impl GCodeParserState {
+    /// Parses the words of a `[GC:...]` message, e.g. `G0 G54 G17 G21 G90 G94 M5 M9 T0 F0 S0`.
+    pub fn parse(s : &str) -> Self {
+        let mut state = GCodeParserState::default();
+
+        for word in s.split_whitespace() {
+            let (letter, value) = word.split_at(1);
+
+            let mut parts = value.splitn(2, '.');
+            let major = parts.next().and_then(|v| v.parse::<u32>().ok());
+            let minor = parts.next().and_then(|v| v.parse::<u8>().ok()).unwrap_or(0);
+
+            match (letter, major, minor) {
+                ("G", Some(0), _)  => {state.motion_mode = ParserMotionMode::Rapid;}
+                ("G", Some(1), _)  => {state.motion_mode = ParserMotionMode::Linear;}
+                ("G", Some(2), _)  => {state.motion_mode = ParserMotionMode::ArcCW;}
+                ("G", Some(3), _)  => {state.motion_mode = ParserMotionMode::ArcCCW;}
+                ("G", Some(38), m) => {state.motion_mode = ParserMotionMode::Probe(m);}
+                ("G", Some(80), _) => {state.motion_mode = ParserMotionMode::Cancel;}
+                ("G", Some(g @ 54..=59), _) => {state.coordinate_system = (g - 54) as u8;}
+                ("G", Some(17), _) => {state.plane = ParserPlane::XY;}
+                ("G", Some(18), _) => {state.plane = ParserPlane::ZX;}
+                ("G", Some(19), _) => {state.plane = ParserPlane::YZ;}
+                ("G", Some(20), _) => {state.units = ParserUnits::Inches;}
+                ("G", Some(21), _) => {state.units = ParserUnits::Millimeters;}
+                ("G", Some(90), _) => {state.distance_mode = ParserDistanceMode::Absolute;}
+                ("G", Some(91), _) => {state.distance_mode = ParserDistanceMode::Incremental;}
+                ("G", Some(93), _) => {state.feed_rate_mode = ParserFeedRateMode::InverseTime;}
+                ("G", Some(94), _) => {state.feed_rate_mode = ParserFeedRateMode::UnitsPerMinute;}
+                ("G", Some(43), _) => {state.tool_length_offset = true;}
+                ("G", Some(49), _) => {state.tool_length_offset = false;}
+                ("M", Some(0), _)  => {state.program_mode = ParserProgramMode::Pause;}
+                ("M", Some(1), _)  => {state.program_mode = ParserProgramMode::OptionalStop;}
+                ("M", Some(2), _)  => {state.program_mode = ParserProgramMode::End;}
+                ("M", Some(30), _) => {state.program_mode = ParserProgramMode::EndAndReset;}
+                ("M", Some(3), _)  => {state.spindle = ParserSpindleState::CW;}
+                ("M", Some(4), _)  => {state.spindle = ParserSpindleState::CCW;}
+                ("M", Some(5), _)  => {state.spindle = ParserSpindleState::Off;}
+                ("M", Some(7), _)  => {state.mist_coolant = true;}
+                ("M", Some(8), _)  => {state.flood_coolant = true;}
+                ("M", Some(9), _)  => {state.mist_coolant = false; state.flood_coolant = false;}
+                ("T", Some(t), _)  => {state.tool = t;}
+                ("F", _, _)        => {state.feed = value.parse::<f32>().unwrap_or(0.0);}
+                ("S", _, _)        => {state.speed = value.parse::<f32>().unwrap_or(0.0);}
+                _ => {}
+            }
+        }
+
+        state
+    }
+}
+
+/// The stored offsets reported by `$#`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum GCodeOffset {
+    /// 0 for G54 through 5 for G59
+    WorkCoordinates(u8),
+    G28,
+    G30,
+    G92,
+}
+
+impl GCodeOffsets {
+    pub fn set(&mut self, offset : GCodeOffset, value : AxisValues) {
+        match offset {
+            GCodeOffset::WorkCoordinates(i) => {self.work_coordinates[i as usize] = value;}
+            GCodeOffset::G28 => {self.g28 = value;}
+            GCodeOffset::G30 => {self.g30 = value;}
+            GCodeOffset::G92 => {self.g92 = value;}
+        }
+    }
+}
+
+/// The version and build string reported by `$I` in a `[VER:...]` message.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BuildInfo {
+    pub version : String,
+    pub build : String,
+}
+
+/// The compile time options reported by `$I` in an `[OPT:...]` message.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BuildOptions {
+    /// the option codes, e.g. `V` for variable spindle
+    pub options : String,
+    pub capabilities : BuildCapabilities,
+    pub planner_blocks : Option<u32>,
+    pub rx_buffer_size : Option<u32>,
 }
 
+/// Everything learned about GRBL from its feedback messages.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct GRBLInfo {
+    pub parser_state : Option<GCodeParserState>,
+    pub offsets : GCodeOffsets,
+    pub probe : Option<ProbeResult>,
+    pub build_info : Option<BuildInfo>,
+    pub build_options : Option<BuildOptions>,
+    /// the startup blocks reported by `$N`, or `None` if they haven't been fetched
+    pub startup_blocks : [Option<String>; STARTUP_BLOCK_COUNT],
+    /// the startup blocks GRBL ran after it was last reset, and their results
+    pub startup_results : Vec<(String, Result<(), GRBLError>)>,
+    pub dialect : GRBLDialect,
+    /// the settings as described by grblHAL, empty for GRBL
+    pub extended_settings : ExtendedSettings,
+}
 
+impl GRBLInfo {
+    /// The options GRBL was built with, or `None` until `$I` has been answered.
+    pub fn capabilities(&self) -> Option<BuildCapabilities> {
+        self.build_options.as_ref().map(|o| o.capabilities)
+    }
 
-#[derive(Default, Clone, Copy)]
+    /// The size of GRBL's serial receive buffer, as reported by `$I` if it was.
+    pub fn rx_buffer_size(&self) -> usize {
+        self.build_options.as_ref()
+            .and_then(|o| o.rx_buffer_size)
+            .map_or(GRBL_RX_BUFFER_SIZE, |size| size as usize)
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
 pub struct GRBLSettings {
     pub step_pulse_micros       : u16,              // 0    Step pulse time, microseconds
     pub step_idle_millis        : u8,               // 1    Step idle delay, milliseconds
@@ -64,23 +245,22 @@ pub struct GRBLSettings {
     pub spindle_max_speed       : f32,              // 30   Maximum spindle speed, RPM
     pub spindle_min_speed       : f32,              // 31   Minimum spindle speed, RPM
     pub laser_mode              : bool,             // 32   Laser-mode enable, boolean
-    pub steps_per_mm_x          : f32,              // 100  X-axis steps per millimeter
-    pub steps_per_mm_y          : f32,              // 101  Y-axis steps per millimeter
-    pub steps_per_mm_z          : f32,              // 102  Z-axis steps per millimeter
-    pub max_rate_x              : f32,              // 110  X-axis maximum rate, mm/min
-    pub max_rate_y              : f32,              // 111  Y-axis maximum rate, mm/min
-    pub max_rate_z              : f32,              // 112  Z-axis maximum rate, mm/min
-    pub acceleration_x          : f32,              // 120  X-axis acceleration, mm/sec^2
-    pub acceleration_y          : f32,              // 121  Y-axis acceleration, mm/sec^2
-    pub acceleration_z          : f32,              // 122  Z-axis acceleration, mm/sec^2
-    pub max_travel_x            : f32,              // 130  X-axis maximum travel, millimeters
-    pub max_travel_y            : f32,              // 131  Y-axis maximum travel, millimeters
-    pub max_travel_z            : f32,              // 132  Z-axis maximum travel, millimeters
+    pub steps_per_mm            : [f32; MAX_AXES],  // 100+ Axis travel resolution, steps per millimeter
+    pub max_rate                : [f32; MAX_AXES],  // 110+ Axis maximum rate, mm/min
+    pub acceleration            : [f32; MAX_AXES],  // 120+ Axis acceleration, mm/sec^2
+    pub max_travel              : [f32; MAX_AXES],  // 130+ Axis maximum travel, millimeters
+    /// the number of axes GRBL reported settings for, 0 until they have been reported
+    pub axis_count              : usize,
 }
 
 impl GRBLSettings {
-    fn set_all_command_list(&self) -> [GRBLCommand; 34] {
-        [
+    /// The number of axes with settings.
+    pub fn axis_count(&self) -> usize {
+        if self.axis_count == 0 {DEFAULT_AXIS_COUNT} else {self.axis_count}
+    }
+
+    pub fn set_all_command_list(&self) -> Vec<GRBLCommand> {
+        let mut commands = vec![
             GRBLCommand::Setting{setting : 0  , value : format!("{}",    self.step_pulse_micros)},            // u16               0  
             GRBLCommand::Setting{setting : 1  , value : format!("{}",    self.step_idle_millis)},             // u8                1  
             GRBLCommand::Setting{setting : 2  , value : format!("{}",    self.step_invert_mask.bits())},        // AxisMask          2  
@@ -103,98 +283,62 @@ impl GRBLSettings {
             GRBLCommand::Setting{setting : 30 , value : format!("{:.6}", self.spindle_max_speed)},            // f32               30 
             GRBLCommand::Setting{setting : 31 , value : format!("{:.6}", self.spindle_min_speed)},            // f32               31 
             GRBLCommand::Setting{setting : 32 , value : format!("{}",    self.laser_mode as u8)},             // bool              32 
-            GRBLCommand::Setting{setting : 100, value : format!("{:.6}", self.steps_per_mm_x)},               // f32               100
-            GRBLCommand::Setting{setting : 101, value : format!("{:.6}", self.steps_per_mm_y)},               // f32               101
-            GRBLCommand::Setting{setting : 102, value : format!("{:.6}", self.steps_per_mm_z)},               // f32               102
-            GRBLCommand::Setting{setting : 110, value : format!("{:.6}", self.max_rate_x)},                   // f32               110
-            GRBLCommand::Setting{setting : 111, value : format!("{:.6}", self.max_rate_y)},                   // f32               111
-            GRBLCommand::Setting{setting : 112, value : format!("{:.6}", self.max_rate_z)},                   // f32               112
-            GRBLCommand::Setting{setting : 120, value : format!("{:.6}", self.acceleration_x)},               // f32               120
-            GRBLCommand::Setting{setting : 121, value : format!("{:.6}", self.acceleration_y)},               // f32               121
-            GRBLCommand::Setting{setting : 122, value : format!("{:.6}", self.acceleration_z)},               // f32               122
-            GRBLCommand::Setting{setting : 130, value : format!("{:.6}", self.max_travel_x)},                 // f32               130
-            GRBLCommand::Setting{setting : 131, value : format!("{:.6}", self.max_travel_y)},                 // f32               131
-            GRBLCommand::Setting{setting : 132, value : format!("{:.6}", self.max_travel_z)},                 // f32               132
-        ]
-    }
+        ];
 
-    fn set_one_command(&self, index : u8) -> Option<GRBLCommand> {
-        match index {
-             0 => Some(GRBLCommand::Setting{setting : 0  , value : format!("{}",    self.step_pulse_micros)}),            // u16               0  
-             1 => Some(GRBLCommand::Setting{setting : 1  , value : format!("{}",    self.step_idle_millis)}),             // u8                1  
-             2 => Some(GRBLCommand::Setting{setting : 2  , value : format!("{}",    self.step_invert_mask.bits())}),        // AxisMask          2  
-             3 => Some(GRBLCommand::Setting{setting : 3  , value : format!("{}",    self.direction_invert_mask.bits())}),   // AxisMask          3  
-             4 => Some(GRBLCommand::Setting{setting : 4  , value : format!("{}",    self.step_enable_invert as u8)}),     // bool              4  
-             5 => Some(GRBLCommand::Setting{setting : 5  , value : format!("{}",    self.limit_pin_invert as u8)}),       // bool              5  
-             6 => Some(GRBLCommand::Setting{setting : 6  , value : format!("{}",    self.probe_pin_invert as u8)}),       // bool              6  
-             7 => Some(GRBLCommand::Setting{setting : 10 , value : format!("{}",    self.status_report_mask.bits())}),      // StatusReportMask  10 
-             8 => Some(GRBLCommand::Setting{setting : 11 , value : format!("{:.6}", self.junction_deviation)}),           // f32               11 
-             9 => Some(GRBLCommand::Setting{setting : 12 , value : format!("{:.6}", self.arc_tolerance)}),                // f32               12 
-            10 => Some(GRBLCommand::Setting{setting : 13 , value : format!("{}",    self.report_inches as u8)}),          // bool              13 
-            11 => Some(GRBLCommand::Setting{setting : 20 , value : format!("{}",    self.soft_limits_enable as u8)}),     // bool              20 
-            12 => Some(GRBLCommand::Setting{setting : 21 , value : format!("{}",    self.hard_limits_enable as u8)}),     // bool              21 
-            13 => Some(GRBLCommand::Setting{setting : 22 , value : format!("{}",    self.homing_cycle_enable as u8)}),    // bool              22 
-            14 => Some(GRBLCommand::Setting{setting : 23 , value : format!("{}",    self.homing_direction_mask.bits())}),   // AxisMask          23 
-            15 => Some(GRBLCommand::Setting{setting : 24 , value : format!("{:.6}", self.homing_locate_rate)}),           // f32               24 
-            16 => Some(GRBLCommand::Setting{setting : 25 , value : format!("{:.6}", self.homing_search_rate)}),           // f32               25 
-            17 => Some(GRBLCommand::Setting{setting : 26 , value : format!("{}",    self.homing_switch_debounce)}),       // u16               26 
-            18 => Some(GRBLCommand::Setting{setting : 27 , value : format!("{:.6}", self.homing_pulloff_distance)}),      // f32               27 
-            19 => Some(GRBLCommand::Setting{setting : 30 , value : format!("{:.6}", self.spindle_max_speed)}),            // f32               30 
-            20 => Some(GRBLCommand::Setting{setting : 31 , value : format!("{:.6}", self.spindle_min_speed)}),            // f32               31 
-            21 => Some(GRBLCommand::Setting{setting : 32 , value : format!("{}",    self.laser_mode as u8)}),             // bool              32 
-            22 => Some(GRBLCommand::Setting{setting : 100, value : format!("{:.6}", self.steps_per_mm_x)}),               // f32               100
-            23 => Some(GRBLCommand::Setting{setting : 101, value : format!("{:.6}", self.steps_per_mm_y)}),               // f32               101
-            24 => Some(GRBLCommand::Setting{setting : 102, value : format!("{:.6}", self.steps_per_mm_z)}),               // f32               102
-            25 => Some(GRBLCommand::Setting{setting : 110, value : format!("{:.6}", self.max_rate_x)}),                   // f32               110
-            26 => Some(GRBLCommand::Setting{setting : 111, value : format!("{:.6}", self.max_rate_y)}),                   // f32               111
-            27 => Some(GRBLCommand::Setting{setting : 112, value : format!("{:.6}", self.max_rate_z)}),                   // f32               112
-            28 => Some(GRBLCommand::Setting{setting : 120, value : format!("{:.6}", self.acceleration_x)}),               // f32               120
-            29 => Some(GRBLCommand::Setting{setting : 121, value : format!("{:.6}", self.acceleration_y)}),               // f32               121
-            31 => Some(GRBLCommand::Setting{setting : 122, value : format!("{:.6}", self.acceleration_z)}),               // f32               122
-            32 => Some(GRBLCommand::Setting{setting : 130, value : format!("{:.6}", self.max_travel_x)}),                 // f32               130
-            33 => Some(GRBLCommand::Setting{setting : 131, value : format!("{:.6}", self.max_travel_y)}),                 // f32               131
-            34 => Some(GRBLCommand::Setting{setting : 132, value : format!("{:.6}", self.max_travel_z)}),                 // f32               132
-            _ => None,
+        // the per-axis settings are numbered from 100, 110, 120 and 130 in axis order
+        let axis_settings = [(100, &self.steps_per_mm), (110, &self.max_rate), (120, &self.acceleration), (130, &self.max_travel)];
+
+        for (base, values) in axis_settings.iter() {
+            for (axis, value) in values.iter().enumerate().take(self.axis_count()) {
+                commands.push(GRBLCommand::Setting{setting : base + axis as u32, value : format!("{:.6}", value)});
+            }
         }
+
+        commands
     }
 
-    pub fn parse_setting(&mut self, index : u8, value: &str) {
+    pub fn set_one_command(&self, index : u8) -> Option<GRBLCommand> {
+        self.set_all_command_list().into_iter().nth(index as usize)
+    }
+
+    pub fn parse_setting(&mut self, index : u8, value: &str) -> Result<(), Box<dyn Error>> {
         match index {
-            0   => {self.step_pulse_micros       = value.parse::<u16>().unwrap();}
-            1   => {self.step_idle_millis        = value.parse::<u8>().unwrap();}
-            2   => {self.step_invert_mask        = AxisMask::from_bits(value.parse::<u8>().unwrap()).unwrap();}
-            3   => {self.direction_invert_mask   = AxisMask::from_bits(value.parse::<u8>().unwrap()).unwrap();}
-            4   => {self.step_enable_invert      = value.parse::<u8>().unwrap() != 0;}
-            5   => {self.limit_pin_invert        = value.parse::<u8>().unwrap() != 0;}
-            6   => {self.probe_pin_invert        = value.parse::<u8>().unwrap() != 0;}
-            10  => {self.status_report_mask      = StatusReportMask::from_bits(value.parse::<u8>().unwrap()).unwrap();}
-            11  => {self.junction_deviation      = value.parse::<f32>().unwrap();}
-            12  => {self.arc_tolerance           = value.parse::<f32>().unwrap();}
-            13  => {self.report_inches           = value.parse::<u8>().unwrap() != 0;}
-            20  => {self.soft_limits_enable      = value.parse::<u8>().unwrap() != 0;}
-            21  => {self.hard_limits_enable      = value.parse::<u8>().unwrap() != 0;}
-            22  => {self.homing_cycle_enable     = value.parse::<u8>().unwrap() != 0;}
-            23  => {self.homing_direction_mask   = AxisMask::from_bits(value.parse::<u8>().unwrap()).unwrap();}
-            24  => {self.homing_locate_rate      = value.parse::<f32>().unwrap();}
-            25  => {self.homing_search_rate      = value.parse::<f32>().unwrap();}
-            26  => {self.homing_switch_debounce  = value.parse::<f32>().unwrap() as u16;}
-            27  => {self.homing_pulloff_distance = value.parse::<f32>().unwrap();}
-            30  => {self.spindle_max_speed       = value.parse::<f32>().unwrap();}
-            31  => {self.spindle_min_speed       = value.parse::<f32>().unwrap();}
-            32  => {self.laser_mode              = value.parse::<u8>().unwrap() != 0;}
-            100 => {self.steps_per_mm_x          = value.parse::<f32>().unwrap();}
-            101 => {self.steps_per_mm_y          = value.parse::<f32>().unwrap();}
-            102 => {self.steps_per_mm_z          = value.parse::<f32>().unwrap();}
-            110 => {self.max_rate_x              = value.parse::<f32>().unwrap();}
-            111 => {self.max_rate_y              = value.parse::<f32>().unwrap();}
-            112 => {self.max_rate_z              = value.parse::<f32>().unwrap();}
-            120 => {self.acceleration_x          = value.parse::<f32>().unwrap();}
-            121 => {self.acceleration_y          = value.parse::<f32>().unwrap();}
-            122 => {self.acceleration_z          = value.parse::<f32>().unwrap();}
-            130 => {self.max_travel_x            = value.parse::<f32>().unwrap();}
-            131 => {self.max_travel_y            = value.parse::<f32>().unwrap();}
-            132 => {self.max_travel_z            = value.parse::<f32>().unwrap();}
+            0   => {self.step_pulse_micros       = value.parse::<u16>()?;}
+            1   => {self.step_idle_millis        = value.parse::<u8>()?;}
+            2   => {self.step_invert_mask        = AxisMask::from_bits(value.parse::<u8>()?).ok_or("invalid mask")?;}
+            3   => {self.direction_invert_mask   = AxisMask::from_bits(value.parse::<u8>()?).ok_or("invalid mask")?;}
+            4   => {self.step_enable_invert      = value.parse::<u8>()? != 0;}
+            5   => {self.limit_pin_invert        = value.parse::<u8>()? != 0;}
+            6   => {self.probe_pin_invert        = value.parse::<u8>()? != 0;}
+            10  => {self.status_report_mask      = StatusReportMask::from_bits(value.parse::<u8>()?).ok_or("invalid mask")?;}
+            11  => {self.junction_deviation      = value.parse::<f32>()?;}
+            12  => {self.arc_tolerance           = value.parse::<f32>()?;}
+            13  => {self.report_inches           = value.parse::<u8>()? != 0;}
+            20  => {self.soft_limits_enable      = value.parse::<u8>()? != 0;}
+            21  => {self.hard_limits_enable      = value.parse::<u8>()? != 0;}
+            22  => {self.homing_cycle_enable     = value.parse::<u8>()? != 0;}
+            23  => {self.homing_direction_mask   = AxisMask::from_bits(value.parse::<u8>()?).ok_or("invalid mask")?;}
+            24  => {self.homing_locate_rate      = value.parse::<f32>()?;}
+            25  => {self.homing_search_rate      = value.parse::<f32>()?;}
+            26  => {self.homing_switch_debounce  = value.parse::<f32>()? as u16;}
+            27  => {self.homing_pulloff_distance = value.parse::<f32>()?;}
+            30  => {self.spindle_max_speed       = value.parse::<f32>()?;}
+            31  => {self.spindle_min_speed       = value.parse::<f32>()?;}
+            32  => {self.laser_mode              = value.parse::<u8>()? != 0;}
+            100..=105 | 110..=115 | 120..=125 | 130..=135 => {
+                let axis = index as usize % 10;
+                let values = match index / 10 {
+                    10 => &mut self.steps_per_mm,
+                    11 => &mut self.max_rate,
+                    12 => &mut self.acceleration,
+                    _  => &mut self.max_travel,
+                };
+                values[axis] = value.parse::<f32>()?;
+                self.axis_count = self.axis_count.max(axis + 1);
+            }
             _ => {},
         }
+
+        Ok(())
     }
 }
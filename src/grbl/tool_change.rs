@@ -0,0 +1,102 @@
+/*!
+ * This file contains the manual tool change, which GRBL doesn't support itself.
+ * The sender takes M6 out of the program, stops streaming, and moves the
+ * machine to where the tool can be changed. Once the new tool is in, its
+ * length can be measured with the probe before the program continues.
+ */
+
+use crate::controller::ToolChangeSettings;
+use crate::gcode;
+
+impl ToolChangeSettings {
+    /// Stops the spindle, raises the tool and moves it to the tool change position. The dwell is
+    /// only acknowledged once the machine has stopped. The settings are in millimeters, so the
+    /// units are switched to millimeters until `tool_change_restore` switches them back.
+    pub fn move_commands(&self) -> Vec<String> {
+        vec![
+            String::from("G21"),
+            String::from("M5"),
+            String::from("M9"),
+            format!("G53 G0 Z{:.3}", self.position[2]),
+            format!("G53 G0 X{:.3} Y{:.3}", self.position[0], self.position[1]),
+            String::from("G4 P0.01"),
+        ]
+    }
+
+    /// Moves over the tool length sensor, probes down to it and raises the tool again.
+    /// `relative` restores the distance mode of the program afterwards.
+    pub fn probe_commands(&self, relative : bool) -> Vec<String> {
+        vec![
+            String::from("G21"),
+            format!("G53 G0 X{:.3} Y{:.3}", self.probe_position[0], self.probe_position[1]),
+            format!("G91 G38.2 Z{:.3} F{}", -self.probe_distance.abs(), self.probe_feed),
+            String::from(if relative {"G91"} else {"G90"}),
+            format!("G53 G0 Z{:.3}", self.position[2]),
+            String::from("G4 P0.01"),
+        ]
+    }
+}
+
+/// Looks for a tool change on a line of a program. Returns the tool selected by the line, if any,
+/// and the line without M6, or `None` if the line doesn't change tools. Fails if the line has M6
+/// but can't be parsed, as it must not be sent to GRBL as it is.
+pub fn find_tool_change(line : &str, on_tool_select : bool) -> Result<Option<(Option<u32>, String)>, String> {
+    let code = strip_comments(line);
+    let source = format!("{}\n", code.trim_end());
+
+    // the grammar stops at the first thing it doesn't know, so the whole line has to be parsed
+    let parsed = gcode::try_parse(&source).ok()
+        .filter(|parsed| parsed.len() == 1 && parsed[0].line.trim() == code.trim());
+
+    let parsed = match parsed {
+        Some(ref parsed) => &parsed[0],
+        None if mentions_m6(&code) => return Err(format!("cannot read the tool change in {:?}", line.trim_end())),
+        None => return Ok(None),
+    };
+
+    let change = parsed.words.iter().any(|w| matches!(w, ('M', _, 6, 0)));
+    let tool = parsed.value_for('T').map(|t| t as u32);
+
+    if !change && !(on_tool_select && tool.is_some()) {
+        return Ok(None);
+    }
+
+    // GRBL takes T words, but not M6
+    let rest = parsed.words.iter()
+        .filter(|w| !matches!(w, ('M', _, 6, 0)))
+        .map(|&(letter, value, _, _)| format!("{}{}", letter, value))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    Ok(Some((tool, rest)))
+}
+
+/// Removes `(...)` and `;` comments, which the G-code grammar only partly knows.
+fn strip_comments(line : &str) -> String {
+    let mut code = String::new();
+    let mut in_comment = false;
+
+    for c in line.chars() {
+        match c {
+            '(' => in_comment = true,
+            ')' if in_comment => in_comment = false,
+            ';' if !in_comment => break,
+            c if !in_comment => code.push(c),
+            _ => {}
+        }
+    }
+
+    code
+}
+
+/// Returns true if `code` has an M6 word, without parsing the rest of it.
+fn mentions_m6(code : &str) -> bool {
+    let code = code.to_ascii_uppercase().replace(|c : char| c.is_whitespace(), "");
+
+    code.match_indices('M').any(|(i, _)| {
+        let number = code[i + 1..].chars()
+            .take_while(|c| c.is_ascii_digit() || *c == '.')
+            .collect::<String>();
+        number.parse::<f32>().map_or(false, |v| v == 6.0)
+    })
+}
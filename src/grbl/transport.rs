@@ -0,0 +1,305 @@
+/*!
+ * This file contains the transports GRBL can be reached over. The sender task
+ * talks to every transport through an async byte stream. Network endpoints
+ * are opened directly on the runtime, while blocking transports such as
+ * serial ports are moved onto threads of their own and bridged to the
+ * runtime.
+ *
+ * Endpoints are written as:
+ *  - `/dev/ttyUSB0`, `COM3`, or a PTY such as `/dev/pts/4` for serial devices
+ *  - `tcp://host:port` or `telnet://host[:port]` for network boards, e.g. ESP32 WiFi GRBL on port 23
+ *  - `unix:///path/to/socket` for Unix-domain sockets
+ *  - `virtual://` for a virtual controller running in-process
+ *  - `replay:///path/to/recording` to play back a recorded session
+ */
+
+use std::error::Error;
+use std::io::{self, Read, Write};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, RecvTimeoutError, Sender};
+use std::time::Duration;
+
+use serialport::SerialPort;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+
+/// How long a blocking read waits for data before timing out. Reads return as soon as
+/// data arrives, so this only bounds how long a bridge thread takes to notice it should stop.
+pub const TRANSPORT_READ_TIMEOUT : Duration = Duration::from_millis(20);
+
+/// How long to wait for a network endpoint to accept the connection.
+pub const TRANSPORT_CONNECT_TIMEOUT : Duration = Duration::from_secs(3);
+
+/// The telnet port, used when a `telnet://` endpoint doesn't name one.
+pub const TELNET_PORT : u16 = 23;
+
+/// The number of bytes buffered in each direction between a blocking transport and the runtime.
+pub const BRIDGE_BUFFER_SIZE : usize = 4096;
+
+/// A blocking byte stream to GRBL. Reads return `ErrorKind::TimedOut` when no data is available,
+/// and any other error means the connection is gone.
+pub trait Transport : Read + Write + Send {
+    /// The endpoint this transport is connected to.
+    fn name(&self) -> String;
+
+    /// Splits the transport into halves that can be used from different threads.
+    fn split(self : Box<Self>) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)>;
+}
+
+/// An async byte stream to GRBL. Reading 0 bytes means the connection is gone.
+pub trait AsyncTransport : AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<T : AsyncRead + AsyncWrite + Send + Unpin> AsyncTransport for T {}
+
+/// Opens the transport described by `endpoint` on the current runtime.
+/// The baud rate is only used by serial devices.
+pub async fn open_async_transport(endpoint : &str, baud_rate : u32) -> Result<Box<dyn AsyncTransport>, Box<dyn Error + Send + Sync>> {
+
+    if let Some(address) = endpoint.strip_prefix("tcp://") {
+        return connect_tcp(address).await;
+    }
+
+    if let Some(address) = endpoint.strip_prefix("telnet://") {
+        let address = if address.contains(':') {address.to_string()} else {format!("{}:{}", address, TELNET_PORT)};
+        return connect_tcp(&address).await;
+    }
+
+    if let Some(path) = endpoint.strip_prefix("unix://") {
+        return connect_unix(path).await;
+    }
+
+    // opening a serial port blocks, so keep it off the runtime's worker threads
+    let transport = {
+        let endpoint = endpoint.to_string();
+        tokio::task::spawn_blocking(move || open_transport(&endpoint, baud_rate).map_err(|e| e.to_string())).await??
+    };
+
+    Ok(Box::new(bridge_transport(transport)?))
+}
+
+async fn connect_tcp(address : &str) -> Result<Box<dyn AsyncTransport>, Box<dyn Error + Send + Sync>> {
+    let stream = tokio::time::timeout(TRANSPORT_CONNECT_TIMEOUT, tokio::net::TcpStream::connect(address)).await
+        .map_err(|_| format!("timed out connecting to {}", address))??;
+    stream.set_nodelay(true)?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(unix)]
+async fn connect_unix(path : &str) -> Result<Box<dyn AsyncTransport>, Box<dyn Error + Send + Sync>> {
+    Ok(Box::new(tokio::net::UnixStream::connect(path).await?))
+}
+
+#[cfg(not(unix))]
+async fn connect_unix(_path : &str) -> Result<Box<dyn AsyncTransport>, Box<dyn Error + Send + Sync>> {
+    Err("Unix-domain sockets are not supported on this platform".into())
+}
+
+/// Opens one of the blocking transports: a virtual controller, a replay, or a serial device.
+pub fn open_transport(endpoint : &str, baud_rate : u32) -> Result<Box<dyn Transport>, Box<dyn Error>> {
+
+    if let Some(path) = endpoint.strip_prefix("replay://") {
+        return Ok(Box::new(super::ReplayTransport::open(path)?));
+    }
+
+    if endpoint == super::VIRTUAL_ENDPOINT {
+        return Ok(Box::new(super::spawn_virtual_grbl()));
+    }
+
+    Ok(Box::new(SerialTransport::open(endpoint, baud_rate)?))
+}
+
+/// Moves a blocking transport onto a reader and a writer thread, and returns the end of a
+/// stream connected to them. The threads stop, closing the transport, once the stream is dropped.
+/// Must be called from within the runtime.
+pub fn bridge_transport(transport : Box<dyn Transport>) -> io::Result<DuplexStream> {
+
+    let name = transport.name();
+    let (mut reader, mut writer) = transport.split()?;
+
+    let (stream, bridge) = tokio::io::duplex(BRIDGE_BUFFER_SIZE);
+    let (mut from_runtime, mut to_runtime) = tokio::io::split(bridge);
+
+    let runtime = tokio::runtime::Handle::current();
+    let closed = Arc::new(AtomicBool::new(false));
+
+    {
+        let runtime = runtime.clone();
+        let closed = closed.clone();
+        std::thread::spawn(move || {
+            let mut buf = [0; 1024];
+
+            while !closed.load(Ordering::SeqCst) {
+                match reader.read(&mut buf) {
+                    // a closed port or socket reads 0 bytes, every time it is read
+                    Ok(0) => {
+                        log::warn!("{} was closed", name);
+                        break;
+                    }
+                    Ok(n) => {
+                        if runtime.block_on(to_runtime.write_all(&buf[..n])).is_err() {
+                            break;
+                        }
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::TimedOut || e.kind() == io::ErrorKind::WouldBlock => {}
+                    Err(e) => {
+                        log::warn!("failed to read from {}: {}", name, e);
+                        break;
+                    }
+                }
+            }
+
+            // the runtime reads 0 bytes once this end is shut down
+            let _ = runtime.block_on(to_runtime.shutdown());
+        });
+    }
+
+    std::thread::spawn(move || {
+        let mut buf = [0; 1024];
+
+        loop {
+            match runtime.block_on(from_runtime.read(&mut buf)) {
+                Ok(0) | Err(_) => break,
+                Ok(n) => {
+                    if writer.write_all(&buf[..n]).and_then(|_| writer.flush()).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+
+        closed.store(true, Ordering::SeqCst);
+    });
+
+    Ok(stream)
+}
+
+/// A serial port, or anything that behaves like one such as a PTY.
+pub struct SerialTransport {
+    port : Box<dyn SerialPort>,
+}
+
+impl SerialTransport {
+    pub fn open(path : &str, baud_rate : u32) -> Result<Self, Box<dyn Error>> {
+        let port = serialport::new(path, baud_rate)
+            .timeout(TRANSPORT_READ_TIMEOUT)
+            .open()?;
+
+        Ok(Self {port})
+    }
+}
+
+impl Read for SerialTransport {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        self.port.read(buf)
+    }
+}
+
+impl Write for SerialTransport {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        self.port.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.port.flush()
+    }
+}
+
+impl Transport for SerialTransport {
+    fn name(&self) -> String {
+        self.port.name().unwrap_or_default()
+    }
+
+    fn split(self : Box<Self>) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        let writer = self.port.try_clone()?;
+        Ok((Box::new(self.port), Box::new(writer)))
+    }
+}
+
+/// One end of an in-memory connection. Everything written to one end can be read from the other.
+pub struct LoopbackTransport {
+    reader : LoopbackReader,
+    writer : LoopbackWriter,
+}
+
+struct LoopbackReader {
+    receiver : Receiver<Vec<u8>>,
+    read_buffer : Vec<u8>,
+}
+
+struct LoopbackWriter {
+    sender : Sender<Vec<u8>>,
+}
+
+impl LoopbackTransport {
+    /// Creates both ends of a connection.
+    pub fn pair() -> (Self, Self) {
+        let (a_tx, a_rx) = channel();
+        let (b_tx, b_rx) = channel();
+
+        (
+            Self {
+                reader : LoopbackReader {receiver : b_rx, read_buffer : vec![]},
+                writer : LoopbackWriter {sender : a_tx},
+            },
+            Self {
+                reader : LoopbackReader {receiver : a_rx, read_buffer : vec![]},
+                writer : LoopbackWriter {sender : b_tx},
+            },
+        )
+    }
+}
+
+impl Read for LoopbackReader {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        if self.read_buffer.is_empty() {
+            match self.receiver.recv_timeout(TRANSPORT_READ_TIMEOUT) {
+                Ok(data) => self.read_buffer = data,
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => return Err(io::ErrorKind::BrokenPipe.into()),
+            }
+        }
+
+        let n = buf.len().min(self.read_buffer.len());
+        buf[..n].copy_from_slice(&self.read_buffer[..n]);
+        self.read_buffer.drain(..n);
+        Ok(n)
+    }
+}
+
+impl Write for LoopbackWriter {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        self.sender.send(buf.to_vec())
+            .map_err(|_| io::Error::from(io::ErrorKind::BrokenPipe))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Read for LoopbackTransport {
+    fn read(&mut self, buf : &mut [u8]) -> io::Result<usize> {
+        self.reader.read(buf)
+    }
+}
+
+impl Write for LoopbackTransport {
+    fn write(&mut self, buf : &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl Transport for LoopbackTransport {
+    fn name(&self) -> String {
+        String::from("loopback")
+    }
+
+    fn split(self : Box<Self>) -> io::Result<(Box<dyn Read + Send>, Box<dyn Write + Send>)> {
+        Ok((Box::new(self.reader), Box::new(self.writer)))
+    }
+}
@@ -0,0 +1,716 @@
+/*!
+ * This file contains a virtual GRBL controller that runs in-process behind a
+ * loopback transport. It answers with the same bytes GRBL 1.1 sends, and moves
+ * its reported position along the motion computed by `simulation.rs`, so jobs
+ * can be rehearsed and the UI demonstrated without a controller.
+ */
+
+use std::collections::VecDeque;
+use std::io::{ErrorKind, Read, Write};
+use std::time::Instant;
+
+use crate::gcode;
+use crate::simulation::{DistanceMode, MotionMode, MotionPlane, MotionType, SimulationState, SpindleDirection, Vec3};
+
+use super::*;
+
+/// The number of moves the planner holds before the controller stops reading new lines.
+const PLANNER_BLOCKS : usize = 15;
+
+/// The longest line GRBL accepts.
+const MAX_LINE_LENGTH : usize = 80;
+
+/// The settings a virtual controller starts with, which are GRBL's defaults.
+const DEFAULT_SETTINGS : &str = "
+$0=10
+$1=25
+$2=0
+$3=0
+$4=0
+$5=0
+$6=0
+$10=1
+$11=0.010
+$12=0.002
+$13=0
+$20=0
+$21=0
+$22=0
+$23=0
+$24=25.000
+$25=500.000
+$26=250
+$27=1.000
+$30=1000
+$31=0
+$32=0
+$100=250.000
+$101=250.000
+$102=250.000
+$110=500.000
+$111=500.000
+$112=500.000
+$120=10.000
+$121=10.000
+$122=10.000
+$130=200.000
+$131=200.000
+$132=200.000
+";
+
+/// Starts a virtual controller on its own thread and returns the transport connected to it.
+/// The controller stops once the transport is dropped.
+pub fn spawn_virtual_grbl() -> LoopbackTransport {
+    let (client, machine) = LoopbackTransport::pair();
+
+    std::thread::spawn(move || VirtualGRBL::new(machine).run());
+
+    client
+}
+
+/// A straight move through the planner.
+#[derive(Debug, Clone, Copy)]
+struct PlannedMove {
+    /// in machine coordinates
+    target : AxisValues,
+    /// in mm/min
+    rate : f32,
+    jog : bool,
+}
+
+pub struct VirtualGRBL {
+    port : LoopbackTransport,
+    /// bytes of a line that hasn't been terminated yet
+    line_buffer : Vec<u8>,
+    /// complete lines waiting to be executed, like GRBL's serial receive buffer
+    lines : VecDeque<String>,
+    output : String,
+    settings : GRBLSettings,
+    simulation : SimulationState,
+    planner : VecDeque<PlannedMove>,
+    /// the machine position of every configured axis
+    position : AxisValues,
+    /// the coordinate systems set by G10, and the G92 offset of the simulation
+    offsets : GCodeOffsets,
+    /// set by G93, which the simulation doesn't keep track of
+    inverse_time : bool,
+    state : MachineState,
+    check_mode : bool,
+    homing : bool,
+    reset_requested : bool,
+    startup_blocks : [String; STARTUP_BLOCK_COUNT],
+    last_tick : Instant,
+}
+
+impl VirtualGRBL {
+    pub fn new(port : LoopbackTransport) -> Self {
+        let mut settings = GRBLSettings::default();
+        settings.apply_file_string(DEFAULT_SETTINGS).unwrap();
+
+        let mut grbl = Self {
+            port,
+            line_buffer : vec![],
+            lines : VecDeque::new(),
+            output : String::new(),
+            settings,
+            simulation : SimulationState::new(),
+            planner : VecDeque::new(),
+            position : AxisValues::zero(DEFAULT_AXIS_COUNT),
+            offsets : GCodeOffsets::default(),
+            inverse_time : false,
+            state : MachineState::Idle,
+            check_mode : false,
+            homing : false,
+            reset_requested : false,
+            startup_blocks : Default::default(),
+            last_tick : Instant::now(),
+        };
+
+        grbl.reset();
+        grbl
+    }
+
+    /// Runs the controller until the other end of the transport is closed.
+    pub fn run(mut self) {
+        let mut buf = [0; 256];
+
+        loop {
+            match self.port.read(&mut buf) {
+                Ok(n) => {
+                    for &byte in buf[..n].iter() {
+                        self.receive(byte);
+                    }
+                }
+                Err(e) if e.kind() == ErrorKind::TimedOut => {}
+                Err(_) => return,
+            }
+
+            self.execute_lines();
+            self.tick();
+
+            if !self.output.is_empty() {
+                let output = std::mem::take(&mut self.output);
+                if self.port.write_all(output.as_bytes()).is_err() {
+                    return;
+                }
+            }
+        }
+    }
+
+    fn send(&mut self, line : &str) {
+        self.output += line;
+        self.output += "\r\n";
+    }
+
+    fn receive(&mut self, byte : u8) {
+        const STATUS_QUERY : u8 = GRBLRealtimeCommand::StatusQuery as u8;
+        const FEED_HOLD : u8 = GRBLRealtimeCommand::FeedHold as u8;
+        const CYCLE_START : u8 = GRBLRealtimeCommand::CycleStartOrResume as u8;
+        const SOFT_RESET : u8 = GRBLRealtimeCommand::SoftReset as u8;
+        const JOG_CANCEL : u8 = GRBLRealtimeCommand::JogCancel as u8;
+        const SAFETY_DOOR : u8 = GRBLRealtimeCommand::SafetyDoor as u8;
+
+        match byte {
+            STATUS_QUERY => self.send_status_report(),
+            FEED_HOLD => {
+                if matches!(self.state, MachineState::Run | MachineState::Jog) {
+                    self.state = MachineState::Hold(false);
+                }
+            }
+            CYCLE_START => {
+                match self.state {
+                    MachineState::Hold(_) => self.state = MachineState::Run,
+                    // there is no door pin, so the door counts as closed as soon as it was opened
+                    MachineState::Door(0) => {
+                        if self.simulation.spindle_speed > 0.0 {
+                            self.send("[MSG:Restoring spindle]");
+                        }
+                        self.state = MachineState::Run;
+                    }
+                    _ => {}
+                }
+            }
+            SAFETY_DOOR => {
+                if matches!(self.state, MachineState::Idle | MachineState::Run | MachineState::Hold(_)) {
+                    self.state = MachineState::Door(0);
+                }
+            }
+            SOFT_RESET => {
+                if matches!(self.state, MachineState::Run | MachineState::Jog | MachineState::Hold(_) | MachineState::Home) {
+                    self.send(&format!("ALARM:{}", GRBLAlarm::AbortCycle.code()));
+                    self.state = MachineState::Alarm;
+                }
+                self.reset();
+            }
+            JOG_CANCEL => {
+                if self.state == MachineState::Jog {
+                    self.planner.retain(|m| !m.jog);
+                    let offset = work_offset(&self.offsets, &self.simulation);
+                    move_simulation(&mut self.simulation, &self.position, &offset);
+                }
+            }
+            // the other realtime commands are overrides, which the virtual controller ignores
+            0x80..=0xFF => {}
+            b'\r' => {}
+            b'\n' => {
+                let line = String::from_utf8_lossy(&self.line_buffer).trim().to_string();
+                self.line_buffer.clear();
+                self.lines.push_back(line);
+            }
+            _ => self.line_buffer.push(byte),
+        }
+    }
+
+    /// Resets the controller as if it had been power cycled, keeping its position and settings.
+    fn reset(&mut self) {
+        self.lines.clear();
+        self.line_buffer.clear();
+        self.planner.clear();
+        self.check_mode = false;
+        self.homing = false;
+        self.inverse_time = false;
+        self.simulation = SimulationState::new();
+        self.offsets.g92 = AxisValues::default();
+        let offset = work_offset(&self.offsets, &self.simulation);
+        move_simulation(&mut self.simulation, &self.position, &offset);
+
+        self.send("");
+        self.send("Grbl 1.1h ['$' for help]");
+
+        if self.state == MachineState::Alarm || self.settings.homing_cycle_enable {
+            self.state = MachineState::Alarm;
+            self.send("[MSG:'$H'|'$X' to unlock]");
+            return;
+        }
+
+        self.state = MachineState::Idle;
+
+        for i in 0..STARTUP_BLOCK_COUNT {
+            let line = self.startup_blocks[i].clone();
+            if !line.is_empty() {
+                let result = match self.execute_gcode(&line, false) {
+                    Ok(()) => String::from("ok"),
+                    Err(e) => format!("error:{}", e.code()),
+                };
+                self.send(&format!(">{}:{}", line, result));
+            }
+        }
+    }
+
+    fn execute_lines(&mut self) {
+        while !self.homing && self.planner.len() < PLANNER_BLOCKS {
+            let line = match self.lines.pop_front() {
+                Some(line) => line,
+                None => return,
+            };
+
+            let result = if line.len() > MAX_LINE_LENGTH {
+                Err(GRBLError::LineLengthExceeded)
+            } else if line.starts_with('$') {
+                self.execute_system_command(&line)
+            } else {
+                self.execute_gcode(&line, false)
+            };
+
+            match result {
+                // homing answers once the machine is home
+                Ok(()) if self.homing => {}
+                Ok(()) => self.send("ok"),
+                Err(e) => self.send(&format!("error:{}", e.code())),
+            }
+
+            if self.reset_requested {
+                self.reset_requested = false;
+                self.reset();
+            }
+        }
+    }
+
+    fn execute_system_command(&mut self, line : &str) -> Result<(), GRBLError> {
+
+        let moving = !self.planner.is_empty();
+        let locked = self.state == MachineState::Alarm;
+
+        if let Some(jog) = line.strip_prefix("$J=") {
+            return self.execute_gcode(jog, true);
+        }
+
+        match line {
+            "$" => {
+                self.send("[HLP:$$ $# $G $I $N $x=val $Nx=line $J=line $SLP $C $X $H ~ ! ? ctrl-x]");
+            }
+            "$$" => {
+                let settings = self.settings.to_file_string();
+                for setting in settings.lines() {
+                    self.send(setting);
+                }
+            }
+            "$#" => {
+                let count = self.settings.axis_count();
+                let offsets = self.offsets;
+                for (i, coordinates) in offsets.work_coordinates.iter().enumerate() {
+                    self.send(&format!("[G{}:{}]", 54 + i, format_axes(coordinates, count)));
+                }
+                self.send(&format!("[G28:{}]", format_axes(&offsets.g28, count)));
+                self.send(&format!("[G30:{}]", format_axes(&offsets.g30, count)));
+                self.send(&format!("[G92:{}]", format_axes(&offsets.g92, count)));
+                self.send("[TLO:0.000]");
+                self.send(&format!("[PRB:{}:0]", format_axes(&AxisValues::default(), count)));
+            }
+            "$G" => {
+                let sim = &self.simulation;
+                let motion = match sim.motion_mode {
+                    MotionMode::G0 => "G0",
+                    MotionMode::G1 => "G1",
+                    MotionMode::G2 => "G2",
+                    MotionMode::G3 => "G3",
+                };
+                let plane = match sim.motion_plane {
+                    MotionPlane::XY => "G17",
+                    MotionPlane::XZ => "G18",
+                    MotionPlane::YZ => "G19",
+                };
+                let distance = match sim.distance_mode {
+                    DistanceMode::Absolute => "G90",
+                    DistanceMode::Relative => "G91",
+                };
+                let feed_rate_mode = if self.inverse_time {"G93"} else {"G94"};
+                let spindle = match sim.spindle {
+                    SpindleDirection::Clockwise        => "M3",
+                    SpindleDirection::CounterClockwise => "M4",
+                    SpindleDirection::Off              => "M5",
+                };
+                let coolant = match (sim.mist_coolant, sim.flood_coolant) {
+                    (false, false) => "M9",
+                    (true, false)  => "M7",
+                    (false, true)  => "M8",
+                    (true, true)   => "M7 M8",
+                };
+                // the simulation refuses G20, so the units are always millimeters
+                let state = format!("[GC:{} G{} {} G21 {} {} {} {} T{} F{} S{}]",
+                    motion, 54 + sim.work_coord_system, plane, distance, feed_rate_mode, spindle, coolant, sim.tool, sim.feed_rate, sim.spindle_speed);
+                self.send(&state);
+            }
+            "$I" => {
+                self.send("[VER:1.1h.virtual:]");
+                self.send(&format!("[OPT:V,{},{}]", PLANNER_BLOCKS, GRBL_RX_BUFFER_SIZE));
+            }
+            "$N" => {
+                for i in 0..STARTUP_BLOCK_COUNT {
+                    let block = format!("$N{}={}", i, self.startup_blocks[i]);
+                    self.send(&block);
+                }
+            }
+            "$C" => {
+                if moving {
+                    return Err(GRBLError::IdleError);
+                }
+                if self.check_mode {
+                    // leaving check mode resets GRBL, right after the `ok`
+                    self.send("[MSG:Disabled]");
+                    self.reset_requested = true;
+                    return Ok(());
+                }
+                self.check_mode = true;
+                self.state = MachineState::Check;
+                self.send("[MSG:Enabled]");
+            }
+            "$X" => {
+                if locked {
+                    self.state = MachineState::Idle;
+                    self.send("[MSG:Caution: Unlocked]");
+                }
+            }
+            "$H" => {
+                if !self.settings.homing_cycle_enable {
+                    return Err(GRBLError::SettingDisabled);
+                }
+                if moving {
+                    return Err(GRBLError::IdleError);
+                }
+                self.state = MachineState::Home;
+                self.homing = true;
+
+                let home = AxisValues::zero(self.settings.axis_count());
+                let offset = work_offset(&self.offsets, &self.simulation);
+                move_simulation(&mut self.simulation, &home, &offset);
+                self.planner.push_back(PlannedMove {
+                    target : home,
+                    rate : self.settings.homing_search_rate,
+                    jog : false,
+                });
+            }
+            "$SLP" => {
+                self.state = MachineState::Sleep;
+                self.send("[MSG:Sleeping]");
+            }
+            "$RST=$" => {
+                self.settings.apply_file_string(DEFAULT_SETTINGS).unwrap();
+            }
+            "$RST=#" | "$RST=*" => {}
+            _ => {
+                if moving {
+                    return Err(GRBLError::IdleError);
+                }
+
+                let (name, value) = line[1..].split_once('=').ok_or(GRBLError::InvalidStatement)?;
+
+                if let Some(index) = name.strip_prefix('N') {
+                    let index = index.parse::<usize>().map_err(|_| GRBLError::BadNumberFormat)?;
+                    if index >= STARTUP_BLOCK_COUNT {
+                        return Err(GRBLError::InvalidStatement);
+                    }
+                    let block = validate_startup_block(value).map_err(|_| GRBLError::InvalidStatement)?;
+                    self.startup_blocks[index] = block;
+                    return Ok(());
+                }
+
+                let setting = name.parse::<u32>().map_err(|_| GRBLError::InvalidStatement)?;
+                let number = value.parse::<f32>().map_err(|_| GRBLError::BadNumberFormat)?;
+                if number < 0.0 {
+                    return Err(GRBLError::NegativeValue);
+                }
+                let value = SettingDescriptor::find(setting)
+                    .ok_or(GRBLError::InvalidStatement)?
+                    .validate(value)
+                    .map_err(|_| GRBLError::InvalidStatement)?;
+                self.settings.parse_setting(setting as u8, &value).map_err(|_| GRBLError::InvalidStatement)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Simulates one line of G-code, queueing the moves it makes unless in check mode.
+    fn execute_gcode(&mut self, line : &str, jog : bool) -> Result<(), GRBLError> {
+
+        if self.state == MachineState::Alarm {
+            return Err(GRBLError::SystemGcLock);
+        }
+
+        // jogs are always linear moves, and G53 jogs in machine coordinates
+        let machine_coordinates = jog && line.contains("G53");
+        let source = if jog {
+            format!("G1{}\n", line.replace("G53", ""))
+        } else {
+            format!("{}\n", line)
+        };
+
+        let parsed = gcode::try_parse(&source).map_err(|_| GRBLError::ExpectedCommandLetter)?;
+
+        let parsed = match parsed.first() {
+            Some(parsed) => parsed,
+            None if is_blank(line) => return Ok(()),
+            None => return Err(GRBLError::ExpectedCommandLetter),
+        };
+
+        // where the machine is once the planned moves are done
+        let machine = self.machine_position(self.simulation.position, self.simulation.rotary, &work_offset(&self.offsets, &self.simulation));
+
+        let mut simulation = self.simulation.clone();
+
+        // selecting another coordinate system changes the work position, not the machine's
+        if let Some(system) = selected_coordinate_system(parsed).filter(|_| !jog) {
+            simulation.work_coord_system = system;
+        }
+        let offset = if machine_coordinates {AxisValues::zero(self.settings.axis_count())} else {work_offset(&self.offsets, &simulation)};
+        move_simulation(&mut simulation, &machine, &offset);
+
+        let mut path = vec![];
+
+        simulation.step(parsed, &mut path).map_err(|_| GRBLError::GcodeUnsupportedCommand)?;
+
+        if path.iter().any(|p| p.ty == MotionType::Linear) && simulation.feed_rate <= 0.0 {
+            return Err(GRBLError::GcodeUndefinedFeedRate);
+        }
+
+        let mut offsets = self.offsets;
+        if has_code(parsed, 10) {
+            self.set_coordinate_system(parsed, &machine, simulation.work_coord_system, &mut offsets)?;
+        }
+        offsets.g92 = g92_offset(&simulation);
+
+        // a line that doesn't move keeps the machine where it is, even if the offsets changed
+        let offset = if machine_coordinates {offset} else {work_offset(&offsets, &simulation)};
+        if path.is_empty() {
+            move_simulation(&mut simulation, &machine, &offset);
+        }
+
+        let targets = path.iter()
+            .map(|p| (p.ty, self.machine_position(p.pos, p.rotary, &offset)))
+            .collect::<Vec<_>>();
+
+        if self.settings.soft_limits_enable && !targets.iter().all(|(_, target)| self.within_limits(target)) {
+            if jog {
+                return Err(GRBLError::TravelExceeded);
+            }
+            self.send(&format!("ALARM:{}", GRBLAlarm::SoftLimit.code()));
+            self.state = MachineState::Alarm;
+            self.planner.clear();
+            return Ok(());
+        }
+
+        if jog {
+            // jogging doesn't change the modal state
+            let end = targets.last().map_or(machine, |&(_, target)| target);
+            let offset = work_offset(&self.offsets, &self.simulation);
+            move_simulation(&mut self.simulation, &end, &offset);
+        } else {
+            self.simulation = simulation;
+            self.offsets.g92 = offsets.g92;
+            // the coordinate systems are stored by G10, which check mode doesn't do
+            if !self.check_mode {
+                self.offsets.work_coordinates = offsets.work_coordinates;
+            }
+            if has_code(parsed, 93) {
+                self.inverse_time = true;
+            }
+            if has_code(parsed, 94) {
+                self.inverse_time = false;
+            }
+        }
+
+        if self.check_mode {
+            return Ok(());
+        }
+
+        let rapid_rate = self.settings.max_rate[..self.settings.axis_count()].iter().copied().fold(f32::INFINITY, f32::min);
+
+        for (ty, target) in targets {
+            self.planner.push_back(PlannedMove {
+                target,
+                rate : match ty {
+                    MotionType::Rapid => rapid_rate,
+                    MotionType::Linear => self.simulation.feed_rate.min(rapid_rate),
+                },
+                jog,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Stores a coordinate system from a G10 L2 or L20 line, the latter making `machine` the
+    /// given position in that coordinate system.
+    fn set_coordinate_system(&self, line : &gcode::GCodeLine, machine : &AxisValues, active : usize, offsets : &mut GCodeOffsets) -> Result<(), GRBLError> {
+        let l = line.value_for('L').ok_or(GRBLError::GcodeValueWordMissing)?;
+        let p = line.value_for('P').ok_or(GRBLError::GcodeValueWordMissing)?;
+
+        // P0 is the active coordinate system
+        let system = match p as usize {
+            _ if p.fract() != 0.0 => return Err(GRBLError::GcodeCommandValueNotInteger),
+            0 => active,
+            p @ 1..=6 => p - 1,
+            _ => return Err(GRBLError::GcodeUnsupportedCoordSys),
+        };
+
+        let coordinates = &mut offsets.work_coordinates[system];
+        for (axis, &name) in AXIS_NAMES.iter().enumerate().take(self.settings.axis_count()) {
+            if let Some(value) = line.value_for(name) {
+                coordinates[axis] = match l as u32 {
+                    2 => value,
+                    20 => machine[axis] - offsets.g92[axis] - value,
+                    _ => return Err(GRBLError::GcodeUnsupportedCommand),
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The machine position of every configured axis, from a position of the simulation.
+    fn machine_position(&self, position : Vec3, rotary : [f32; 3], offset : &AxisValues) -> AxisValues {
+        let work = [position.x, position.y, position.z, rotary[0], rotary[1], rotary[2]];
+
+        let mut machine = AxisValues::zero(self.settings.axis_count());
+        for axis in 0..machine.len() {
+            machine[axis] = work[axis] + offset[axis];
+        }
+        machine
+    }
+
+    fn within_limits(&self, machine : &AxisValues) -> bool {
+        let travel = &self.settings.max_travel;
+        (0..self.settings.axis_count()).all(|i| machine[i] <= 0.0 && machine[i] >= -travel[i])
+    }
+
+    /// Moves the position along the planned moves by the time elapsed since the last tick.
+    fn tick(&mut self) {
+        let dt = self.last_tick.elapsed().as_secs_f32();
+        self.last_tick = Instant::now();
+
+        let moving = matches!(self.state, MachineState::Idle | MachineState::Run | MachineState::Jog | MachineState::Home);
+
+        if moving {
+            let mut time = dt;
+
+            while let Some(planned) = self.planner.front().copied() {
+                let to_target = planned.target - self.position;
+                let distance = to_target.as_slice().iter().map(|d| d * d).sum::<f32>().sqrt();
+                let reach = planned.rate / 60.0 * time;
+
+                if reach < distance {
+                    for axis in 0..to_target.len() {
+                        self.position[axis] += to_target[axis] * (reach / distance);
+                    }
+                    break;
+                }
+
+                self.position = planned.target;
+                self.planner.pop_front();
+                time -= if planned.rate > 0.0 {distance / (planned.rate / 60.0)} else {time};
+            }
+
+            self.state = match self.planner.front() {
+                _ if self.state == MachineState::Home && !self.planner.is_empty() => MachineState::Home,
+                Some(planned) if planned.jog => MachineState::Jog,
+                Some(_) => MachineState::Run,
+                None => MachineState::Idle,
+            };
+        }
+
+        if self.homing && self.planner.is_empty() {
+            self.homing = false;
+            self.send("ok");
+        }
+    }
+
+    fn send_status_report(&mut self) {
+        let state = match self.state {
+            MachineState::Idle => String::from("Idle"),
+            MachineState::Run => String::from("Run"),
+            MachineState::Hold(in_progress) => format!("Hold:{}", if in_progress {1} else {0}),
+            MachineState::Jog => String::from("Jog"),
+            MachineState::Alarm => String::from("Alarm"),
+            MachineState::Door(n) => format!("Door:{}", n),
+            MachineState::Check => String::from("Check"),
+            MachineState::Home => String::from("Home"),
+            MachineState::Sleep => String::from("Sleep"),
+            MachineState::Tool => String::from("Tool"),
+        };
+
+        let feed = self.planner.front().map(|m| m.rate).unwrap_or(0.0);
+        let rx_free = GRBL_RX_BUFFER_SIZE.saturating_sub(self.lines.iter().map(|l| l.len() + 1).sum());
+
+        let count = self.settings.axis_count();
+        let report = format!("<{}|MPos:{}|Bf:{},{}|FS:{},{}|WCO:{}>",
+            state,
+            format_axes(&self.position, count),
+            PLANNER_BLOCKS - self.planner.len().min(PLANNER_BLOCKS), rx_free,
+            feed as u32, self.simulation.spindle_speed as u32,
+            format_axes(&work_offset(&self.offsets, &self.simulation), count),
+        );
+        self.send(&report);
+    }
+}
+
+/// Returns true if the line holds nothing but whitespace and comments.
+fn is_blank(line : &str) -> bool {
+    let mut in_comment = false;
+    for c in line.chars() {
+        match c {
+            '(' => in_comment = true,
+            ')' => in_comment = false,
+            ';' if !in_comment => return true,
+            c if !in_comment && !c.is_whitespace() => return false,
+            _ => {}
+        }
+    }
+    true
+}
+
+/// Formats the first `count` axes the way GRBL reports positions, e.g. `0.000,10.000,-5.000`.
+fn format_axes(values : &AxisValues, count : usize) -> String {
+    (0..count).map(|axis| format!("{:.3}", values[axis])).collect::<Vec<_>>().join(",")
+}
+
+/// The offset from machine to work coordinates in the coordinate system and G92 offset of `simulation`.
+fn work_offset(offsets : &GCodeOffsets, simulation : &SimulationState) -> AxisValues {
+    offsets.work_coordinates[simulation.work_coord_system] + g92_offset(simulation)
+}
+
+fn g92_offset(simulation : &SimulationState) -> AxisValues {
+    let offset = simulation.g92_offset();
+    AxisValues::from([offset.x, offset.y, offset.z])
+}
+
+/// Puts the simulation at `machine`, in the work coordinates `offset` leads to.
+fn move_simulation(simulation : &mut SimulationState, machine : &AxisValues, offset : &AxisValues) {
+    simulation.position = Vec3::new(machine[0] - offset[0], machine[1] - offset[1], machine[2] - offset[2]);
+    for axis in 0..3 {
+        simulation.rotary[axis] = machine[3 + axis] - offset[3 + axis];
+    }
+}
+
+/// The coordinate system the line selects, 0 for G54 to 5 for G59.
+fn selected_coordinate_system(line : &gcode::GCodeLine) -> Option<usize> {
+    line.words.iter().find_map(|word| match *word {
+        ('G', _, g @ 54..=59, 0) => Some((g - 54) as usize),
+        _ => None,
+    })
+}
+
+/// Returns true if the line has the G code `major`, without a fraction.
+fn has_code(line : &gcode::GCodeLine, major : u32) -> bool {
+    line.words.iter().any(|&(letter, _, m, minor)| letter == 'G' && m == major && minor == 0)
+}
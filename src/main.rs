@@ -24,7 +24,9 @@ macro_rules! im_stringf {
 }
 
 mod grbl;
+mod controller;
 mod simulation;
+mod preprocess;
 mod imgui_renderer;
 mod util;
 mod viewport;
@@ -42,6 +44,12 @@ struct WindowRect {
 
 fn main() {
 
+    simple_logger::SimpleLogger::new()
+        .with_level(log::LevelFilter::Info)
+        .env()
+        .init()
+        .unwrap();
+
     let mut async_runtime = tokio::runtime::Builder::new_multi_thread()
         .enable_all()
         .build()
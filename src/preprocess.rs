@@ -0,0 +1,450 @@
+/*!
+ * This file contains the passes that rewrite a program before it is streamed,
+ * so that it takes less of GRBL's receive buffer and only has words GRBL takes.
+ * Every line the passes produce stays paired with the index of the line it
+ * came from, so that progress and errors still point into the program.
+ */
+
+use crate::grbl::GRBLDialect;
+
+/// The size of GRBL's line buffer, which holds a line without its ending.
+pub const GRBL_LINE_BUFFER_SIZE : usize = 80;
+
+/// The G-codes GRBL supports, written as they are after `format!("{}", value)`.
+const SUPPORTED_G : &[&str] = &[
+    "0", "1", "2", "3", "4", "10", "17", "18", "19", "20", "21", "28", "28.1", "30", "30.1",
+    "38.2", "38.3", "38.4", "38.5", "40", "43.1", "49", "53", "54", "55", "56", "57", "58", "59",
+    "61", "80", "90", "91", "91.1", "92", "92.1", "93", "94",
+];
+
+/// The M-codes GRBL supports, and M6, which the sender handles itself.
+const SUPPORTED_M : &[&str] = &["0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "30", "56"];
+
+/// The letters of the words GRBL takes besides G and M.
+const SUPPORTED_LETTERS : &str = "FIJKLNPRSTXYZABC";
+
+/// The G-codes grblHAL supports on top of GRBL's, like splines, canned cycles and spindle sync.
+const GRBLHAL_G : &[&str] = &[
+    "5", "5.1", "7", "8", "33", "43", "43.2", "59.1", "59.2", "59.3", "61.1", "64", "73", "76",
+    "81", "82", "83", "85", "86", "89", "92.2", "92.3", "96", "97", "98", "99",
+];
+
+/// The M-codes grblHAL supports on top of GRBL's, like the overrides and the digital and analog outputs.
+const GRBLHAL_M : &[&str] = &["48", "49", "50", "51", "61", "62", "63", "64", "65", "66", "67", "68"];
+
+/// The letters grblHAL takes on top of GRBL's, for tool offsets and pecks.
+const GRBLHAL_LETTERS : &str = "HQ";
+
+/// The words that are rounded by `RoundCoordinates`.
+const COORDINATE_LETTERS : &str = "XYZABCIJKR";
+
+/// A pass over every line of a program.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PreprocessPass {
+    /// removes `(...)` and `;` comments, and the lines left empty
+    StripComments,
+    /// removes spaces and tabs
+    StripWhitespace,
+    /// upper-cases the letters of the words
+    Uppercase,
+    /// rounds coordinates and arc offsets to `decimals` places
+    RoundCoordinates {
+        decimals : u32,
+    },
+    /// removes the words the firmware doesn't support, with a warning, and refuses the program
+    /// if one of them is a G or M code, as the rest of its line means something else without it
+    DropUnsupported,
+    /// moves the words that don't have to be with the motion to lines of their own,
+    /// to keep lines within `max_length` characters
+    SplitLongLines {
+        max_length : usize,
+    },
+}
+
+impl PreprocessPass {
+    pub fn name(&self) -> &'static str {
+        match self {
+            PreprocessPass::StripComments           => "Strip comments",
+            PreprocessPass::StripWhitespace         => "Strip whitespace",
+            PreprocessPass::Uppercase               => "Uppercase",
+            PreprocessPass::RoundCoordinates{..}    => "Round coordinates",
+            PreprocessPass::DropUnsupported         => "Drop unsupported words",
+            PreprocessPass::SplitLongLines{..}      => "Split long lines",
+        }
+    }
+
+    /// Applies the pass to one line, which becomes any number of lines.
+    /// Fails if the line can't be sent without changing what it does.
+    fn apply(&self, tokens : Vec<Token>, dialect : GRBLDialect, warnings : &mut Vec<String>) -> Result<Vec<Vec<Token>>, String> {
+        Ok(match *self {
+            PreprocessPass::StripComments => {
+                let tokens = trim(tokens.into_iter().filter(|t| !matches!(t, Token::Comment(_))).collect());
+                if tokens.is_empty() {vec![]} else {vec![tokens]}
+            }
+            PreprocessPass::StripWhitespace => {
+                let tokens = tokens.into_iter().filter(|t| !matches!(t, Token::Space(_))).collect::<Vec<_>>();
+                if tokens.is_empty() {vec![]} else {vec![tokens]}
+            }
+            PreprocessPass::Uppercase => {
+                vec![tokens.into_iter()
+                    .map(|t| match t {
+                        Token::Word(letter, number) => Token::Word(letter.to_ascii_uppercase(), number),
+                        t => t,
+                    })
+                    .collect()]
+            }
+            PreprocessPass::RoundCoordinates{decimals} => {
+                vec![tokens.into_iter()
+                    .map(|t| match t {
+                        Token::Word(letter, number) if COORDINATE_LETTERS.contains(letter.to_ascii_uppercase()) => {
+                            let number = number.parse::<f64>().map(|v| round(v, decimals)).unwrap_or(number);
+                            Token::Word(letter, number)
+                        }
+                        t => t,
+                    })
+                    .collect()]
+            }
+            PreprocessPass::DropUnsupported => {
+                let mut kept = vec![];
+                for t in tokens {
+                    match t {
+                        Token::Word(letter, ref number) if !is_supported(letter, number, dialect) => {
+                            if matches!(letter.to_ascii_uppercase(), 'G' | 'M') {
+                                return Err(format!("{}{} isn't supported by the firmware", letter, number));
+                            }
+                            warnings.push(format!("dropped {}{}, which the firmware doesn't support", letter, number));
+                        }
+                        t => kept.push(t),
+                    }
+                }
+                vec![trim(kept)]
+            }
+            PreprocessPass::SplitLongLines{max_length} => {
+                if join(&tokens).len() <= max_length {
+                    return Ok(vec![tokens]);
+                }
+
+                let spaced = tokens.iter().any(|t| matches!(t, Token::Space(_)));
+                let mut before = vec![];
+                let mut after = vec![];
+                let mut motion = vec![];
+
+                for t in tokens {
+                    match t {
+                        Token::Word(letter, ref number) => match split_placement(letter, number) {
+                            Placement::Before => before.push(t),
+                            Placement::After  => after.push(t),
+                            Placement::Motion => motion.push(t),
+                        }
+                        t => motion.push(t),
+                    }
+                }
+
+                let motion = trim(motion);
+                if join(&motion).len() > max_length {
+                    warnings.push(format!("still longer than {} characters after splitting", max_length));
+                }
+
+                vec![spread(before, spaced), motion, spread(after, spaced)].into_iter()
+                    .filter(|line| !line.is_empty())
+                    .collect()
+            }
+        })
+    }
+}
+
+/// A warning about a line, by its index in the program.
+#[derive(Debug, Clone)]
+pub struct PreprocessWarning {
+    pub line : usize,
+    pub message : String,
+}
+
+/// The lines of a program after preprocessing.
+#[derive(Debug, Clone, Default)]
+pub struct Preprocessed {
+    /// each line paired with the index of the line it came from
+    pub lines : Vec<(usize, String)>,
+    pub warnings : Vec<PreprocessWarning>,
+}
+
+/// The passes a program goes through before it is streamed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Pipeline {
+    /// every pass in the order they run, and whether it is enabled
+    pub passes : Vec<(PreprocessPass, bool)>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            passes : vec![
+                (PreprocessPass::StripComments, false),
+                (PreprocessPass::StripWhitespace, false),
+                (PreprocessPass::Uppercase, false),
+                (PreprocessPass::RoundCoordinates{decimals : 4}, false),
+                (PreprocessPass::DropUnsupported, false),
+                (PreprocessPass::SplitLongLines{max_length : GRBL_LINE_BUFFER_SIZE - 1}, false),
+            ],
+        }
+    }
+}
+
+impl Pipeline {
+    pub fn is_enabled(&self) -> bool {
+        self.passes.iter().any(|&(_, enabled)| enabled)
+    }
+
+    /// Runs the enabled passes over every line, for plain GRBL.
+    pub fn run(&self, lines : &[String]) -> Result<Preprocessed, String> {
+        self.run_for(lines, GRBLDialect::Grbl)
+    }
+
+    /// Runs the enabled passes over every line, for a controller running `dialect`.
+    /// System commands and `%` are left alone. Fails with the number of the first line that
+    /// can't be sent without changing what it does.
+    pub fn run_for(&self, lines : &[String], dialect : GRBLDialect) -> Result<Preprocessed, String> {
+        let mut processed = Preprocessed::default();
+
+        for (i, line) in lines.iter().enumerate() {
+            let trimmed = line.trim_start();
+            if !self.is_enabled() || trimmed.starts_with(&['$', '%'][..]) {
+                processed.lines.push((i, line.clone()));
+                continue;
+            }
+
+            let mut warnings = vec![];
+            let mut blocks = vec![tokenize(line)];
+
+            for &(pass, _) in self.passes.iter().filter(|&&(_, enabled)| enabled) {
+                let mut next = vec![];
+                for tokens in blocks {
+                    next.extend(pass.apply(tokens, dialect, &mut warnings).map_err(|e| format!("line {}: {}", i + 1, e))?);
+                }
+                blocks = next;
+            }
+
+            processed.lines.extend(blocks.iter().map(|tokens| (i, join(tokens))));
+            processed.warnings.extend(warnings.into_iter().map(|message| PreprocessWarning {line : i, message}));
+        }
+
+        Ok(processed)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// a letter and the number after it, as written
+    Word(char, String),
+    Comment(String),
+    Space(String),
+    /// anything else, which is kept as it is
+    Other(char),
+}
+
+fn tokenize(line : &str) -> Vec<Token> {
+    let mut tokens = vec![];
+    let mut chars = line.trim_end_matches(&['\r', '\n'][..]).chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '(' => {
+                let mut comment = String::from("(");
+                for c in chars.by_ref() {
+                    comment.push(c);
+                    if c == ')' {
+                        break;
+                    }
+                }
+                tokens.push(Token::Comment(comment));
+            }
+            // a `;` comment runs to the end of the line
+            ';' => tokens.push(Token::Comment(std::iter::once(';').chain(chars.by_ref()).collect())),
+            ' ' | '\t' => {
+                let mut space = c.to_string();
+                while let Some(c) = chars.next_if(|&c| c == ' ' || c == '\t') {
+                    space.push(c);
+                }
+                tokens.push(Token::Space(space));
+            }
+            c if c.is_ascii_alphabetic() => {
+                // GRBL ignores spaces, even between a letter and its number
+                let mut after_spaces = chars.clone();
+                while after_spaces.next_if(|&c| c == ' ' || c == '\t').is_some() {}
+                if after_spaces.peek().map_or(false, |c| c.is_ascii_digit() || ".-+".contains(*c)) {
+                    chars = after_spaces;
+                }
+
+                let mut number = String::new();
+                while let Some(c) = chars.next_if(|&c| c.is_ascii_digit() || c == '.' || c == '-' || c == '+') {
+                    number.push(c);
+                }
+                tokens.push(Token::Word(c, number));
+            }
+            c => tokens.push(Token::Other(c)),
+        }
+    }
+
+    tokens
+}
+
+fn join(tokens : &[Token]) -> String {
+    tokens.iter()
+        .map(|t| match t {
+            Token::Word(letter, number) => format!("{}{}", letter, number),
+            Token::Comment(s) | Token::Space(s) => s.clone(),
+            Token::Other(c) => c.to_string(),
+        })
+        .collect()
+}
+
+/// Removes the spaces at either end of a line.
+fn trim(mut tokens : Vec<Token>) -> Vec<Token> {
+    while let Some(Token::Space(_)) = tokens.last() {
+        tokens.pop();
+    }
+    let start = tokens.iter().position(|t| !matches!(t, Token::Space(_))).unwrap_or(tokens.len());
+    tokens.split_off(start)
+}
+
+/// Puts a space between words, if the line they were taken from had spaces.
+fn spread(words : Vec<Token>, spaced : bool) -> Vec<Token> {
+    if !spaced {
+        return words;
+    }
+
+    let mut tokens = vec![];
+    for (i, word) in words.into_iter().enumerate() {
+        if i > 0 {
+            tokens.push(Token::Space(String::from(" ")));
+        }
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// Rounds `value` to `decimals` places, without trailing zeros.
+fn round(value : f64, decimals : u32) -> String {
+    let rounded = format!("{:.*}", decimals as usize, value);
+    let rounded = if rounded.contains('.') {rounded.trim_end_matches('0').trim_end_matches('.')} else {&rounded[..]};
+
+    match rounded {
+        "-0" => String::from("0"),
+        r => r.to_string(),
+    }
+}
+
+/// The number of a G or M word the way the tables above write it, e.g. `38.2` for `G38.20`.
+fn code(number : &str) -> Option<String> {
+    number.parse::<f32>().ok().map(|v| format!("{}", v))
+}
+
+fn is_supported(letter : char, number : &str, dialect : GRBLDialect) -> bool {
+    let hal = dialect == GRBLDialect::GrblHAL;
+
+    match letter.to_ascii_uppercase() {
+        'G' => code(number).map_or(false, |c| SUPPORTED_G.contains(&&c[..]) || hal && GRBLHAL_G.contains(&&c[..])),
+        'M' => code(number).map_or(false, |c| SUPPORTED_M.contains(&&c[..]) || hal && GRBLHAL_M.contains(&&c[..])),
+        l   => SUPPORTED_LETTERS.contains(l) || hal && GRBLHAL_LETTERS.contains(l),
+    }
+}
+
+/// Where a word goes when a line is split.
+enum Placement {
+    /// takes effect before the motion, so it can go on a line of its own before it
+    Before,
+    /// takes effect after the motion, like the program stops
+    After,
+    /// belongs with the motion or the command of the line
+    Motion,
+}
+
+fn split_placement(letter : char, number : &str) -> Placement {
+    let code = code(number).unwrap_or_default();
+
+    match letter.to_ascii_uppercase() {
+        // F stays, as inverse time mode needs it on every move, and so does the feed rate mode
+        // that gives it its meaning
+        'S' | 'T' => Placement::Before,
+        'M' => match &code[..] {
+            "0" | "1" | "2" | "30" => Placement::After,
+            _                      => Placement::Before,
+        }
+        'G' => match &code[..] {
+            "17" | "18" | "19" | "20" | "21" | "40" | "49" | "54" | "55" | "56" | "57" | "58" | "59" |
+            "61" | "80" | "90" | "91" | "91.1" => Placement::Before,
+            _ => Placement::Motion,
+        }
+        _ => Placement::Motion,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Runs only `passes` over a single line, for a controller running `dialect`.
+    fn run(passes : &[PreprocessPass], line : &str, dialect : GRBLDialect) -> Result<Vec<String>, String> {
+        let pipeline = Pipeline {
+            passes : passes.iter().map(|&pass| (pass, true)).collect(),
+        };
+        let processed = pipeline.run_for(&[String::from(line)], dialect)?;
+        Ok(processed.lines.into_iter()
+            .map(|(i, line)| {
+                assert_eq!(i, 0);
+                line
+            })
+            .collect())
+    }
+
+    #[test]
+    fn strips_semicolon_comments() {
+        let passes = [PreprocessPass::StripComments];
+        assert_eq!(run(&passes, "G1 X1 (to the edge) Y2 ; (not a comment) X3", GRBLDialect::Grbl).unwrap(), ["G1 X1  Y2"]);
+        assert!(run(&passes, "; only a comment", GRBLDialect::Grbl).unwrap().is_empty());
+    }
+
+    #[test]
+    fn keeps_signed_numbers() {
+        let passes = [PreprocessPass::StripWhitespace, PreprocessPass::Uppercase, PreprocessPass::RoundCoordinates{decimals : 3}];
+        assert_eq!(run(&passes, "g1 x-1.23456 y+2.5 z-.5", GRBLDialect::Grbl).unwrap(), ["G1X-1.235Y2.5Z-0.5"]);
+    }
+
+    #[test]
+    fn rounds_negative_zero_to_zero() {
+        let passes = [PreprocessPass::RoundCoordinates{decimals : 4}];
+        assert_eq!(run(&passes, "G1 X-0.00001 Y-0 Z0.00004", GRBLDialect::Grbl).unwrap(), ["G1 X0 Y0 Z0"]);
+    }
+
+    #[test]
+    fn split_keeps_inverse_time_with_its_feed() {
+        let passes = [PreprocessPass::SplitLongLines{max_length : 20}];
+        assert_eq!(
+            run(&passes, "G93 G1 X10 Y20 Z-1 F120 S1000 M3", GRBLDialect::Grbl).unwrap(),
+            ["S1000 M3", "G93 G1 X10 Y20 Z-1 F120"],
+        );
+    }
+
+    #[test]
+    fn split_stops_after_the_motion() {
+        let passes = [PreprocessPass::SplitLongLines{max_length : 8}];
+        assert_eq!(run(&passes, "G1 X10 M0", GRBLDialect::Grbl).unwrap(), ["G1 X10", "M0"]);
+        assert_eq!(run(&passes, "M30 G0 Z5 M9", GRBLDialect::Grbl).unwrap(), ["M9", "G0 Z5", "M30"]);
+    }
+
+    #[test]
+    fn refuses_unsupported_codes_by_dialect() {
+        let passes = [PreprocessPass::DropUnsupported];
+        assert_eq!(run(&passes, "M62 P1", GRBLDialect::Grbl), Err(String::from("line 1: M62 isn't supported by the firmware")));
+        assert_eq!(run(&passes, "G5 X1 Y1 I0 J1 P0 Q1", GRBLDialect::Grbl), Err(String::from("line 1: G5 isn't supported by the firmware")));
+        assert_eq!(run(&passes, "M62 P1", GRBLDialect::GrblHAL).unwrap(), ["M62 P1"]);
+        assert_eq!(run(&passes, "G5 X1 Y1 I0 J1 P0 Q1", GRBLDialect::GrblHAL).unwrap(), ["G5 X1 Y1 I0 J1 P0 Q1"]);
+    }
+
+    #[test]
+    fn drops_unsupported_parameters() {
+        let passes = [PreprocessPass::DropUnsupported];
+        assert_eq!(run(&passes, "G1 X1 E0.5", GRBLDialect::Grbl).unwrap(), ["G1 X1"]);
+        assert_eq!(run(&passes, "G 1 X 2", GRBLDialect::Grbl).unwrap(), ["G1 X2"]);
+    }
+}
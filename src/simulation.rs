@@ -6,6 +6,9 @@ use cgmath::Vector3;
 
 pub type Vec3 = Vector3<f32>;
 
+/// The rotary axes, in the order their positions are stored.
+pub const ROTARY_AXES : [char; 3] = ['A', 'B', 'C'];
+
 use crate::gcode;
 
 
@@ -36,6 +39,8 @@ pub enum MotionType {
 pub struct MotionPoint {
     pub ty : MotionType,
     pub pos : Vector3<f32>,
+    /// the position of the A, B and C axes, in degrees
+    pub rotary : [f32; 3],
     pub time : f32,
 }
 
@@ -44,6 +49,7 @@ impl Default for MotionPoint {
         MotionPoint {
             ty : MotionType::Linear,
             pos : Vector3::zero(),
+            rotary : [0.0; 3],
             time : 0.0,
         }
     }
@@ -54,11 +60,19 @@ pub struct GcodeProgram {
     pub filepath : PathBuf,
     pub lines : Vec<String>,
     pub motionpath : Vec<MotionPoint>,
+    /// the estimated time to run the lines before each index, in seconds, with one more entry than `lines`
+    pub cumulative_times : Vec<f32>,
 }
 
 impl GcodeProgram {
-    pub fn load(path : PathBuf, program : String) -> GcodeProgram {
-        let (segments, lines) = gcode_to_path_segments(&program);
+    /// Parses and simulates a program. Fails if it isn't valid G-code, or uses a word the
+    /// simulation doesn't support, e.g. G20.
+    pub fn load(path : PathBuf, program : String) -> Result<GcodeProgram, String> {
+        let (segments, lines, line_times) = gcode_to_path_segments(&program)?;
+
+        let cumulative_times = std::iter::once(0.0)
+            .chain(line_times.iter().scan(0.0, |total, t| {*total += t; Some(*total)}))
+            .collect::<Vec<_>>();
 
         let mut lp = Vector3::new(0.0, 0.0, 0.0);
         let speed = 400.0;
@@ -74,70 +88,210 @@ impl GcodeProgram {
             })
             .collect::<Vec<_>>();
 
-        GcodeProgram {
+        Ok(GcodeProgram {
             filepath: path,
             lines,
             motionpath,
-        }
+            cumulative_times,
+        })
+    }
+
+    /// The estimated time to run the lines with indices `from..to`, in seconds.
+    pub fn estimated_time(&self, from : usize, to : usize) -> f32 {
+        let time = |i : usize| self.cumulative_times.get(i.min(self.lines.len())).cloned().unwrap_or_default();
+        (time(to) - time(from)).max(0.0)
     }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum MotionMode {
+pub enum MotionMode {
     G0, G1, G2, G3
 }
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum DistanceMode {
+pub enum DistanceMode {
     Absolute, Relative,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum MotionPlane {
+pub enum MotionPlane {
     XY, XZ, YZ
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpindleDirection {
+    Off, Clockwise, CounterClockwise,
+}
+
+/// The modal state of the machine while a program is simulated one line at a time.
 #[derive(Debug, Clone)]
-struct SimulationState {
-    spindle_speed : f32,
-    feed_rate : f32,
-    rapid_rate : f32,
-    motion_mode : MotionMode,
-    distance_mode : DistanceMode,
-    motion_plane : MotionPlane,
-    position : Vec3,
+pub struct SimulationState {
+    pub spindle_speed : f32,
+    pub feed_rate : f32,
+    pub rapid_rate : f32,
+    pub motion_mode : MotionMode,
+    pub distance_mode : DistanceMode,
+    pub motion_plane : MotionPlane,
+    pub position : Vec3,
+    /// the position of the A, B and C axes, in degrees
+    pub rotary : [f32; 3],
+    /// the selected work coordinate system, 0 for G54 to 5 for G59
+    pub work_coord_system : usize,
+    pub spindle : SpindleDirection,
+    pub flood_coolant : bool,
+    pub mist_coolant : bool,
+    /// the tool selected by the last T word
+    pub tool : u32,
+    /// the X, Y and Z axes whose position isn't known, since G28 or G30 moved them to a position
+    /// stored in the controller
+    pub unknown_axes : [bool; 3],
     coord_system : Vec3,
+    /// the G92 offset, which turns the program's coordinates into work coordinates
     coord_offset : Vec3,
+    /// set once the program changes the G92 offset
+    coord_offset_changed : bool,
 }
 
-pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
+const HOME_POSITION : Vec3 = Vec3::new(100., 100., 100.0);
+
+const MIN_ARC_SEGMENT : f32 = 0.1;
+
+/// How long to wait for the spindle to get up to speed when a program is resumed.
+pub const SPINDLE_SPIN_UP_SECONDS : f32 = 2.0;
+
+impl SimulationState {
+    pub fn new() -> Self {
+        SimulationState{
+            spindle_speed : 0.0,
+            feed_rate : 0.0,
+            rapid_rate : 400.0,
+            motion_mode : MotionMode::G0,
+            distance_mode : DistanceMode::Absolute,
+            motion_plane : MotionPlane::XY,
+            position : Vec3::zero(),
+            rotary : [0.0; 3],
+            work_coord_system : 0,
+            spindle : SpindleDirection::Off,
+            flood_coolant : false,
+            mist_coolant : false,
+            tool : 0,
+            unknown_axes : [false; 3],
+            coord_system : Vec3::zero(),
+            coord_offset : Vec3::zero(),
+            coord_offset_changed : false,
+        }
+    }
 
-    let lines = gcode::parse(nc);
+    /// Returns the commands that put the machine back into this state, to resume a program from the
+    /// line that follows. The tool rises to `clearance` first, moves above the position, starts the
+    /// spindle and coolant, and only then goes down at the feed rate. Fails if the position isn't
+    /// known, which it isn't after G28 or G30 until each axis has been moved again.
+    pub fn resume_commands(&self, clearance : f32) -> Result<Vec<String>, String> {
+        if let Some(axis) = self.unknown_axes.iter().position(|&unknown| unknown) {
+            return Err(format!("The {} position isn't known after G28 or G30, start from a line after it has been moved again.", ['X', 'Y', 'Z'][axis]));
+        }
 
-    let mut path = vec![];
+        let mut commands = vec![
+            String::from("G90"),
+            format!("G{}", 54 + self.work_coord_system),
+            String::from(match self.motion_plane {
+                MotionPlane::XY => "G17",
+                MotionPlane::XZ => "G18",
+                MotionPlane::YZ => "G19",
+            }),
+        ];
+
+        // a G92 offset of the program is cleared during the lead-in, which then moves in work
+        // coordinates, and is set again once the tool is in place
+        if self.coord_offset_changed {
+            commands.push(String::from("G92.1"));
+        }
+        let target = self.position + self.coord_offset;
 
-    let home_position = Vec3::new(100., 100., 100.0);
+        let safe_z = clearance.max(target.z);
+        commands.push(format!("G0 Z{:.3}", safe_z));
 
-    const MIN_ARC_SEGMENT : f32 = 0.1;
+        // rotary axes that were never moved are left out, as the machine may not have them
+        let mut lead_in = format!("G0 X{:.3} Y{:.3}", target.x, target.y);
+        for (axis, &name) in ROTARY_AXES.iter().enumerate() {
+            if self.rotary[axis] != 0.0 {
+                lead_in += &format!(" {}{:.3}", name, self.rotary[axis]);
+            }
+        }
+        commands.push(lead_in);
 
-    let mut state = SimulationState{
-        spindle_speed : 0.0,
-        feed_rate : 0.0,
-        rapid_rate : 400.0,
-        motion_mode : MotionMode::G0,
-        distance_mode : DistanceMode::Absolute,
-        motion_plane : MotionPlane::XY,
-        position : Vec3::zero(),
-        coord_system : Vec3::zero(),
-        coord_offset : Vec3::zero(),
-    };
+        match self.spindle {
+            SpindleDirection::Off              => commands.push(String::from("M5")),
+            SpindleDirection::Clockwise        => commands.push(format!("M3 S{}", self.spindle_speed)),
+            SpindleDirection::CounterClockwise => commands.push(format!("M4 S{}", self.spindle_speed)),
+        }
 
-    path.push(MotionPoint{pos : state.position, ..Default::default()});
+        // give the spindle time to get up to speed before it cuts
+        if self.spindle != SpindleDirection::Off {
+            commands.push(format!("G4 P{}", SPINDLE_SPIN_UP_SECONDS));
+        }
 
-    let mut string_lines = vec![];
+        match (self.flood_coolant, self.mist_coolant) {
+            (false, false) => commands.push(String::from("M9")),
+            (flood, mist) => {
+                if flood { commands.push(String::from("M8")); }
+                if mist  { commands.push(String::from("M7")); }
+            }
+        }
 
-    for (_, l) in lines.iter().enumerate() {
+        if self.feed_rate > 0.0 {
+            commands.push(format!("G1 Z{:.3} F{}", target.z, self.feed_rate));
+        } else {
+            commands.push(format!("G0 Z{:.3}", target.z));
+        }
 
-        string_lines.push(l.line.to_string());
+        if self.coord_offset != Vec3::zero() {
+            commands.push(format!("G92 X{:.3} Y{:.3} Z{:.3}", self.position.x, self.position.y, self.position.z));
+        }
+
+        if self.distance_mode == DistanceMode::Relative {
+            commands.push(String::from("G91"));
+        }
+
+        // arcs can't be selected without a move, `resume_program` makes sure the next move selects its own
+        match self.motion_mode {
+            MotionMode::G0 => commands.push(String::from("G0")),
+            MotionMode::G1 => commands.push(String::from("G1")),
+            MotionMode::G2 | MotionMode::G3 => {}
+        }
+
+        Ok(commands)
+    }
+
+    /// The G92 offset of the X, Y and Z axes, which turns the program's coordinates into work coordinates.
+    pub fn g92_offset(&self) -> Vec3 {
+        self.coord_offset
+    }
+
+    /// Moves the rotary axes to the A, B and C words of the line, if it has any.
+    fn move_rotary(&mut self, l : &gcode::GCodeLine) {
+        for (axis, &name) in ROTARY_AXES.iter().enumerate() {
+            if let Some(value) = l.value_for(name) {
+                match self.distance_mode {
+                    DistanceMode::Absolute => self.rotary[axis] = value,
+                    DistanceMode::Relative => self.rotary[axis] += value,
+                }
+            }
+        }
+    }
+
+    /// Applies one line of G-code, appending the points the tool moves through to `path`.
+    /// Returns the word that couldn't be simulated if the line is not supported.
+    pub fn step(&mut self, l : &gcode::GCodeLine, path : &mut Vec<MotionPoint>) -> Result<(), String> {
+
+        let state = self;
+        let home_position = HOME_POSITION;
+
+        // set by the commands that take the axis words as their arguments, so the line doesn't move
+        let mut axis_words_taken = false;
+
+        if let Some(f) = l.value_for('F') { state.feed_rate = f; }
+        if let Some(s) = l.value_for('S') { state.spindle_speed = s; }
+        if let Some(t) = l.value_for('T') { state.tool = t as u32; }
 
         for word in l.words.iter() {
             match word {
@@ -162,7 +316,7 @@ pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
                 g!(4) => {} 
 
                 // Set offset (L2 and L10)
-                g!(10) => {} 
+                g!(10) => {axis_words_taken = true;}
 
                 // plane selection
                 g!(17) => {state.motion_plane = MotionPlane::XY;}
@@ -170,74 +324,99 @@ pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
                 g!(19) => {state.motion_plane = MotionPlane::YZ;}
 
                 // units mode (mm/inch)
-                g!(20) => {unimplemented!("G20")}
+                g!(20) => {return Err(String::from("G20"));}
                 g!(21) => {}
 
-                g!(28) => {
+                // the stored positions are only known to the controller, so the simulation goes to a stand-in
+                g!(28) | g!(30) => {
                     state.position.z = home_position.z;
                     path.push(MotionPoint{pos : state.position, ty: MotionType::Rapid, ..Default::default()});
                     state.position.x = home_position.x;
                     state.position.y = home_position.y;
                     path.push(MotionPoint{pos : state.position, ty: MotionType::Rapid, ..Default::default()});
+                    state.unknown_axes = [true; 3];
+                    axis_words_taken = true;
                 }
-                g!(28, 1) => {unimplemented!("G28.1")}
+                g!(28, 1) => {return Err(String::from("G28.1"));}
 
-                g!(30, 1) => {unimplemented!("G30.1")}
+                g!(30, 1) => {return Err(String::from("G30.1"));}
 
-                g!(38, 2) => {unimplemented!("G38.2")}
-                g!(38, 3) => {unimplemented!("G38.3")}
-                g!(38, 4) => {unimplemented!("G38.4")}
-                g!(38, 5) => {unimplemented!("G38.5")}
+                g!(38, 2) => {return Err(String::from("G38.2"));}
+                g!(38, 3) => {return Err(String::from("G38.3"));}
+                g!(38, 4) => {return Err(String::from("G38.4"));}
+                g!(38, 5) => {return Err(String::from("G38.5"));}
 
                 // cutter radius compensation
                 g!(40) => {}
 
                 // tool length offset
-                g!(49) => {unimplemented!("G49")}
-                g!(43, 1) => {unimplemented!("G43.1")}
+                g!(49) => {return Err(String::from("G49"));}
+                g!(43, 1) => {return Err(String::from("G43.1"));}
 
-                g!(53) => {unimplemented!("G53")}
+                g!(53) => {return Err(String::from("G53"));}
 
                 // coordinate system select
-                g!(54) => {}
-                g!(55) => {}
-                g!(56) => {}
-                g!(57) => {}
-                g!(58) => {}
-                g!(59) => {}
+                g!(54) => {state.work_coord_system = 0;}
+                g!(55) => {state.work_coord_system = 1;}
+                g!(56) => {state.work_coord_system = 2;}
+                g!(57) => {state.work_coord_system = 3;}
+                g!(58) => {state.work_coord_system = 4;}
+                g!(59) => {state.work_coord_system = 5;}
 
 
-                g!(80) => {unimplemented!("G80")}
+                g!(80) => {return Err(String::from("G80"));}
 
                 // distance mode (absolute or relative)
                 g!(90) => {state.distance_mode = DistanceMode::Absolute;}
                 g!(91) => {state.distance_mode = DistanceMode::Relative;}
-                g!(91, 1) => {unimplemented!("G91.1")}
-
-                // 
-                g!(92) => {}
-                g!(92, 1) => {}
+                g!(91, 1) => {return Err(String::from("G91.1"));}
+
+                // coordinate offset, which makes the current position the one given
+                g!(92) => {
+                    for (axis, &name) in ['X', 'Y', 'Z'].iter().enumerate() {
+                        if let Some(value) = l.value_for(name) {
+                            state.coord_offset[axis] += state.position[axis] - value;
+                            state.position[axis] = value;
+                        }
+                    }
+                    state.coord_offset_changed = true;
+                    axis_words_taken = true;
+                }
+                g!(92, 1) => {
+                    state.position += state.coord_offset;
+                    state.coord_offset = Vec3::zero();
+                    state.coord_offset_changed = true;
+                }
 
                 // feedrate mode
                 g!(93) => {}
                 g!(94) => {}
                 g!(95) => {}
 
-                // program mode
+                // program mode, the end of a program turns off the spindle and coolant
                 m!(0) => {}
                 m!(1) => {}
-                m!(2) => {}
-                m!(30) => {}
+                m!(2) | m!(30) => {
+                    state.spindle = SpindleDirection::Off;
+                    state.flood_coolant = false;
+                    state.mist_coolant = false;
+                }
 
                 // spindle state
-                m!(3) => {}
-                m!(4) => {}
-                m!(5) => {}
+                m!(3) => {state.spindle = SpindleDirection::Clockwise;}
+                m!(4) => {state.spindle = SpindleDirection::CounterClockwise;}
+                m!(5) => {state.spindle = SpindleDirection::Off;}
+
+                // tool change, which the sender stops for
+                m!(6) => {}
 
                 // coolant state
-                m!(7) => {}
-                m!(8) => {}
-                m!(9) => {}
+                m!(7) => {state.mist_coolant = true;}
+                m!(8) => {state.flood_coolant = true;}
+                m!(9) => {
+                    state.flood_coolant = false;
+                    state.mist_coolant = false;
+                }
 
                 _ => {
                     
@@ -245,10 +424,23 @@ pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
             }
         }
 
+        if axis_words_taken {
+            return Ok(());
+        }
+
+        if state.distance_mode == DistanceMode::Absolute {
+            for (axis, &name) in ['X', 'Y', 'Z'].iter().enumerate() {
+                if l.value_for(name).is_some() {
+                    state.unknown_axes[axis] = false;
+                }
+            }
+        }
+
         match state.motion_mode {
             MotionMode::G0 | MotionMode::G1 => {
 
                 let start = state.position;
+                let start_rotary = state.rotary;
 
                 if state.distance_mode == DistanceMode::Absolute {
                     if let Some(x) = l.value_for('X') { state.position.x = x; }
@@ -260,12 +452,17 @@ pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
                     if let Some(z) = l.value_for('Z') { state.position.z += z; }
                 }
 
+                state.move_rotary(l);
+
                 let end = state.position;
+                let end_rotary = state.rotary;
                 let ty = if state.motion_mode == MotionMode::G0 {MotionType::Rapid} else {MotionType::Linear};
 
-                path.push(MotionPoint{pos : start * 0.975 + end * 0.025, ty, ..Default::default()});
-                path.push(MotionPoint{pos : start * 0.025 + end * 0.975, ty, ..Default::default()});                
-                path.push(MotionPoint{pos : end,                         ty, ..Default::default()});
+                let rotary = |t : f32| [0, 1, 2].map(|i| start_rotary[i] * (1.0 - t) + end_rotary[i] * t);
+
+                path.push(MotionPoint{pos : start * 0.975 + end * 0.025, rotary : rotary(0.025), ty, ..Default::default()});
+                path.push(MotionPoint{pos : start * 0.025 + end * 0.975, rotary : rotary(0.975), ty, ..Default::default()});                
+                path.push(MotionPoint{pos : end,                         rotary : end_rotary,    ty, ..Default::default()});
             }
             MotionMode::G2 | MotionMode::G3 => {
 
@@ -300,6 +497,7 @@ pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
                 let start_center = swizzle(start_center, plane).truncate();
 
                 let start = swizzle(state.position, plane);
+                let start_rotary = state.rotary;
 
                 let center = start + start_center.extend(0.0);
 
@@ -313,11 +511,16 @@ pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
                     if let Some(z) = l.value_for('Z') { state.position.z += z; }
                 }
 
+                state.move_rotary(l);
+
                 let end = swizzle(state.position, plane);
+                let end_rotary = state.rotary;
 
                 let center_end = (end - center).truncate();
 
-                assert!((start_center.magnitude() - center_end.magnitude()).abs() < 0.01);
+                if (start_center.magnitude() - center_end.magnitude()).abs() >= 0.01 {
+                    return Err(String::from("arc radius mismatch"));
+                }
 
                 let rotations = l.value_for('P').unwrap_or(1.0);
 
@@ -341,22 +544,119 @@ pub fn gcode_to_path_segments(nc : &str) -> (Vec<MotionPoint>, Vec<String>) {
 
                 let total_z = start.z - end.z;
 
-                assert!(segments >= 1.0 && segments < 1_000_000.0);
+                if !(segments >= 1.0 && segments < 1_000_000.0) {
+                    return Err(String::from("arc is too long"));
+                }
 
                 for s in 1..(segments as usize - 1) {
 
                     let s = s as f32;
                     let pos = center + (Matrix2::from_angle(Rad(dir * segment_angle * s)) * center_start).extend(total_z * (s / segments));
+                    let rotary = [0, 1, 2].map(|i| start_rotary[i] + (end_rotary[i] - start_rotary[i]) * (s / segments));
 
-                    path.push(MotionPoint{pos : unswizzle(pos, plane), ..Default::default()});
+                    path.push(MotionPoint{pos : unswizzle(pos, plane), rotary, ..Default::default()});
 
                 }
                 
-                path.push(MotionPoint{pos : state.position, ..Default::default()});
+                path.push(MotionPoint{pos : state.position, rotary : state.rotary, ..Default::default()});
             }
         }
+
+        Ok(())
     }
+}
+
+/// Estimates how long it takes to move through `points` from the first one, in seconds.
+fn estimate_time(points : &[MotionPoint], state : &SimulationState) -> f32 {
+    points.windows(2)
+        .map(|p| {
+            let rate = match p[1].ty {
+                MotionType::Linear if state.feed_rate > 0.0 => state.feed_rate,
+                _ => state.rapid_rate,
+            };
+            (p[1].pos - p[0].pos).magnitude() / (rate / 60.0)
+        })
+        .sum()
+}
+
+/// Returns the path of the program, its lines, and the estimated time to run each line in seconds.
+pub fn gcode_to_path_segments(nc : &str) -> Result<(Vec<MotionPoint>, Vec<String>, Vec<f32>), String> {
+
+    let lines = gcode::try_parse(nc).map_err(|e| e.to_string())?;
+
+    let mut path = vec![];
+
+    let mut state = SimulationState::new();
+
+    path.push(MotionPoint{pos : state.position, ..Default::default()});
+
+    let mut string_lines = vec![];
+    let mut line_times = vec![];
+
+    for (i, l) in lines.iter().enumerate() {
+
+        string_lines.push(l.line.to_string());
+
+        let first = path.len() - 1;
 
+        state.step(l, &mut path).map_err(|word| format!("line {} can't be simulated: {}", i + 1, word))?;
 
-    (path, string_lines)
-}
\ No newline at end of file
+        let dwell = if l.words.iter().any(|w| matches!(w, g!(4))) {l.value_for('P').unwrap_or(0.0)} else {0.0};
+        line_times.push(estimate_time(&path[first..], &state) + dwell);
+    }
+
+
+    Ok((path, string_lines, line_times))
+}
+
+/// Returns the modal state at the start of line `start` of a program, found by simulating every line before it.
+pub fn state_at_line(lines : &[String], start : usize) -> Result<SimulationState, String> {
+
+    let before = lines.iter()
+        .take(start)
+        .map(|l| if l.ends_with('\n') {l.clone()} else {format!("{}\n", l)})
+        .collect::<String>();
+
+    let parsed = gcode::try_parse(&before).map_err(|e| e.to_string())?;
+
+    let mut state = SimulationState::new();
+    let mut path = vec![];
+
+    for (i, l) in parsed.iter().enumerate() {
+        // only the state is needed, not the path
+        path.clear();
+        state.step(l, &mut path).map_err(|word| format!("line {} can't be simulated: {}", i + 1, word))?;
+    }
+
+    Ok(state)
+}
+
+/// Returns the commands that resume a program from line `start`, see `SimulationState::resume_commands`.
+/// Fails if the lines before can't be simulated, or if the first move from `start` continues an arc
+/// without selecting G2 or G3 again, as an arc can't be selected without moving.
+pub fn resume_program(lines : &[String], start : usize, clearance : f32) -> Result<Vec<String>, String> {
+
+    let state = state_at_line(lines, start)?;
+
+    if matches!(state.motion_mode, MotionMode::G2 | MotionMode::G3) {
+        for (i, line) in lines.iter().enumerate().skip(start) {
+            let line = format!("{}\n", line.trim_end());
+            let parsed = gcode::try_parse(&line).map_err(|e| e.to_string())?;
+
+            for l in parsed.iter() {
+                // these take the axis words as their arguments
+                if l.words.iter().any(|w| matches!(w, g!(10) | g!(28) | g!(30) | g!(92))) {
+                    continue;
+                }
+                if l.words.iter().any(|w| matches!(w, g!(0) | g!(1) | g!(2) | g!(3))) {
+                    return state.resume_commands(clearance);
+                }
+                if ['X', 'Y', 'Z', 'A', 'B', 'C'].iter().any(|&name| l.value_for(name).is_some()) {
+                    return Err(format!("Line {} continues an arc, start from the line that begins it.", i + 1));
+                }
+            }
+        }
+    }
+
+    state.resume_commands(clearance)
+}
@@ -1,9 +1,10 @@
 
 use cgmath::*;
 use imgui::ImString;
-use std::time::Instant;
-use crate::{WindowRect, gcode_renderer::GCodeRenderer, grbl::{GCodeTaskHandle, GRBLCommand, start_gcode_sender_task}};
+use std::time::{Duration, Instant};
+use crate::{WindowRect, util::HoursMinutesSeconds, controller::{AlarmRecovery, AXIS_NAMES, AxisValues, ConnectionState, Controller, CONTROLLERS, DEFAULT_AXIS_COUNT, ExtendedSettings, ExtendedSettingType, Features, InputPins, MachineState, MAX_AXES, OverrideStep, ParserUnits, PollRates, RapidOverride, RealtimeAction, StreamingMode, ToolChangeSettings, ToolChangeStage, TrafficDirection, TrafficEntry, VIRTUAL_ENDPOINT}, gcode_renderer::GCodeRenderer};
 use std::sync::Arc;
+use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::sync::atomic::Ordering;
 
@@ -14,11 +15,16 @@ use std::sync::atomic::AtomicBool;
 
 use winit::window::Window;
 
+use crate::preprocess::{Pipeline, PreprocessPass, Preprocessed};
 use crate::simulation::GcodeProgram;
 
 pub struct UIState {
     pub ports                       : Vec<SerialPortInfo>,
-    pub connection                  : Option<(usize, GCodeTaskHandle)>,
+    /// the endpoint that is connected, and the task talking to it
+    pub connection                  : Option<(String, Box<dyn Controller>)>,
+    /// index into `CONTROLLERS` of the firmware to connect to
+    pub controller_i                : usize,
+    pub endpoint_input              : ImString,
     pub dialog_open                 : Arc<AtomicBool>,
     pub open_path                   : Arc<std::sync::Mutex<imgui::ImString>>,
     pub baud_rate_i                 : usize,
@@ -26,9 +32,11 @@ pub struct UIState {
     pub spindle_rpm_setpoint        : i32,
     pub spindle_on                  : bool,
     pub gcode_programs              : Arc<std::sync::Mutex<Vec<GcodeProgram>>>,
+    /// why the programs picked in the import dialog couldn't be loaded
+    pub import_errors               : Arc<std::sync::Mutex<Vec<String>>>,
     pub active_program              : Option<GcodeProgram>,
-    pub machine_coords              : [f32; 3],
-    pub work_coords                 : [f32; 3],
+    pub machine_coords              : [f32; MAX_AXES],
+    pub work_coords                 : [f32; MAX_AXES],
     pub work_coord_system           : usize,
     pub main_loop_start             : Instant,
     pub scale                       : f32,
@@ -42,6 +50,49 @@ pub struct UIState {
     pub previous_frame_end          : Instant,
     pub jog_feed_rate               : f32,
     pub jog_distance                : usize,
+    pub streaming_mode              : StreamingMode,
+    pub poll_rates                  : PollRates,
+    /// whether program lines are sent with N words, so that GRBL reports the line it executes
+    pub line_numbering              : bool,
+    /// the line to start the program from, counting from 1
+    pub start_line                  : i32,
+    /// the height the tool moves at before going down to the start line
+    pub resume_clearance            : f32,
+    pub resume_message              : String,
+    pub tool_change_settings        : ToolChangeSettings,
+    pub settings_window_open        : bool,
+    /// the settings as last reported, which edits are compared against
+    pub extended_settings_baseline  : ExtendedSettings,
+    /// one input per setting described by the controller, by setting number
+    pub extended_settings_inputs    : BTreeMap<u32, ImString>,
+    /// contents of a settings file picked in the restore dialog, waiting to be applied
+    pub settings_file               : Arc<std::sync::Mutex<Option<String>>>,
+    /// why the save or restore dialog failed, waiting to be shown
+    pub settings_file_error         : Arc<std::sync::Mutex<Option<String>>>,
+    pub settings_message            : String,
+    pub startup_window_open         : bool,
+    /// the startup blocks as last reported by the controller
+    pub startup_baseline            : Vec<Option<String>>,
+    pub startup_inputs              : Vec<ImString>,
+    pub startup_message             : String,
+    pub history_window_open         : bool,
+    /// set when the alarm popup is closed, until the alarm clears
+    pub alarm_dismissed             : bool,
+    /// how many seconds of the status history are charted
+    pub history_seconds             : i32,
+    pub console_window_open         : bool,
+    pub console_show_status         : bool,
+    pub console_auto_scroll         : bool,
+    pub console_filter              : ImString,
+    /// the file the session is being recorded to
+    pub recording                   : Option<PathBuf>,
+    /// a file picked in the record dialog, waiting to be recorded to
+    pub recording_file              : Arc<std::sync::Mutex<Option<PathBuf>>>,
+    /// the passes programs go through before they are sent
+    pub pipeline                    : Pipeline,
+    pub preprocess_window_open      : bool,
+    /// the active program after preprocessing, and the pipeline it went through
+    pub preprocess_preview          : Option<(PathBuf, Pipeline, Option<String>, Result<Preprocessed, String>)>,
 }
 
 impl UIState {
@@ -49,8 +100,7 @@ impl UIState {
 
         let ports = serialport::available_ports().expect("No ports found!");
 
-        // let mut connection : Option<(usize, usize)> = None;
-        let connection : Option<(usize, GCodeTaskHandle)> = None;
+        let connection : Option<(String, Box<dyn Controller>)> = None;
 
         let dialog_open = Arc::new(AtomicBool::new(false));
         let open_path = Arc::new(std::sync::Mutex::new(imgui::ImString::new(String::new())));
@@ -64,8 +114,8 @@ impl UIState {
         let gcode_programs = Arc::new(std::sync::Mutex::new(vec![]));
         let active_program : Option<GcodeProgram> = None;
 
-        let machine_coords = [0.0; 3];
-        let work_coords = [0.0; 3];
+        let machine_coords = [0.0; MAX_AXES];
+        let work_coords = [0.0; MAX_AXES];
 
 
         let main_loop_start = Instant::now();
@@ -81,6 +131,8 @@ impl UIState {
         UIState {
             ports,
             connection,
+            endpoint_input : ImString::new(""),
+            controller_i : 0,
             dialog_open,
             open_path,
             baud_rate_i,
@@ -88,6 +140,7 @@ impl UIState {
             spindle_rpm_setpoint,
             spindle_on,
             gcode_programs,
+            import_errors : Arc::new(std::sync::Mutex::new(vec![])),
             active_program,
             machine_coords,
             work_coords,
@@ -104,10 +157,50 @@ impl UIState {
             previous_frame_end : Instant::now(),
             jog_feed_rate : 200.0,
             jog_distance : 2,
+            streaming_mode : StreamingMode::default(),
+            poll_rates : PollRates::default(),
+            line_numbering : false,
+            start_line : 1,
+            resume_clearance : 5.0,
+            resume_message : String::new(),
+            tool_change_settings : ToolChangeSettings::default(),
+            settings_window_open : false,
+            extended_settings_baseline : ExtendedSettings::default(),
+            extended_settings_inputs : BTreeMap::new(),
+            settings_file : Arc::new(std::sync::Mutex::new(None)),
+            settings_file_error : Arc::new(std::sync::Mutex::new(None)),
+            settings_message : String::new(),
+            startup_window_open : false,
+            startup_baseline : vec![],
+            startup_inputs : vec![],
+            startup_message : String::new(),
+            history_window_open : false,
+            alarm_dismissed : false,
+            history_seconds : 60,
+            console_window_open : false,
+            console_show_status : false,
+            console_auto_scroll : true,
+            console_filter : ImString::new(""),
+            recording : None,
+            recording_file : Arc::new(std::sync::Mutex::new(None)),
+            pipeline : Pipeline::default(),
+            preprocess_window_open : false,
+            preprocess_preview : None,
         }
     }
 
 
+    fn connect(&mut self, endpoint : String, async_runtime : &tokio::runtime::Runtime) {
+        let controller = (CONTROLLERS[self.controller_i].connect)(endpoint.clone(), self.baud_rate as u32, async_runtime.handle());
+        controller.set_streaming_mode(self.streaming_mode);
+        controller.set_poll_rates(self.poll_rates);
+        controller.set_line_numbering(self.line_numbering);
+        controller.set_pipeline(self.pipeline.clone());
+        controller.set_tool_change_settings(self.tool_change_settings);
+        self.connection = Some((endpoint, controller));
+        self.recording = None;
+    }
+
     pub fn frame(&mut self, ui : &mut imgui::Ui, async_runtime : &mut tokio::runtime::Runtime, viewport : &crate::viewport::Viewport, line_renderer : &mut GCodeRenderer, win : &Window) {
 
         use imgui::*;
@@ -139,6 +232,18 @@ impl UIState {
                     }
                 }
 
+                MenuItem::new(im_str!("Preprocessing")).build_with_ref(ui, &mut self.preprocess_window_open);
+
+                tok.end(ui);
+            }
+
+            if let Some(tok) = ui.begin_menu(im_str!("Machine"), true) {
+
+                MenuItem::new(im_str!("Settings")).build_with_ref(ui, &mut self.settings_window_open);
+                MenuItem::new(im_str!("Startup Blocks")).build_with_ref(ui, &mut self.startup_window_open);
+                MenuItem::new(im_str!("Console")).build_with_ref(ui, &mut self.console_window_open);
+                MenuItem::new(im_str!("Status History")).build_with_ref(ui, &mut self.history_window_open);
+
                 tok.end(ui);
             }
             tok.end(ui);
@@ -190,6 +295,12 @@ impl UIState {
                 let [ww, wh] = ui.window_content_region_max();
 
 
+                let controller_names = CONTROLLERS.iter().map(|c| ImString::new(c.name)).collect::<Vec<_>>();
+                let controller_names = controller_names.iter().map(|n| n.as_ref()).collect::<Vec<&ImStr>>();
+
+                ComboBox::new(im_str!("Firmware"))
+                    .build_simple_string(ui, &mut self.controller_i, &controller_names);
+
                 let baud_rates = [1200, 2400, 4800, 9600, 19200, 38400, 57600, 115200];
                 let baud_rates_str = [
                     im_str!("1200"), 
@@ -215,28 +326,85 @@ impl UIState {
 
                 ui.separator();
 
-                if let Some((j, _)) = self.connection {
-                    for (i, p) in self.ports.iter().enumerate() {
-                        ui.text(&format!("[{:2}] {:?}", i, p.port_name));
-                        if i == j {
+                let connected = self.connection.as_ref().map(|(endpoint, _)| endpoint.clone());
+
+                // network endpoints aren't in the port list, so show them while connected
+                let mut endpoints = self.ports.iter().map(|p| p.port_name.clone()).collect::<Vec<_>>();
+                endpoints.push(String::from(VIRTUAL_ENDPOINT));
+                if let Some(ref endpoint) = connected {
+                    if !endpoints.contains(endpoint) {
+                        endpoints.push(endpoint.clone());
+                    }
+                }
+
+                for (i, endpoint) in endpoints.iter().enumerate() {
+                    ui.text(&format!("[{:2}] {:?}", i, endpoint));
+                    match connected {
+                        Some(ref c) if c == endpoint => {
+                            let state = self.connection.as_ref().unwrap().1.connection_state();
+                            let firmware = self.connection.as_ref().unwrap().1.firmware();
+                            let info = self.connection.as_ref().unwrap().1.info();
+
                             ui.same_line(ww - 80.0);
-                            if ui.small_button(im_strf!("Disconnect##{}", p.port_name)) {
+                            if ui.small_button(im_strf!("Disconnect##{}", endpoint)) {
                                 core::mem::replace(&mut self.connection, None).unwrap().1.stop();
-                                println!("Disconnected {}", p.port_name);
+                                log::info!("disconnected from {}", endpoint);
+                            }
+
+                            match state {
+                                ConnectionState::Connecting        => ui.text_colored([0.9, 0.9, 0.0, 1.0], "  Connecting..."),
+                                ConnectionState::WaitingForWelcome => ui.text_colored([0.9, 0.9, 0.0, 1.0], "  Waiting for the controller..."),
+                                ConnectionState::Ready             => {
+                                    ui.text_colored([0.2, 1.0, 0.2, 1.0], format!("  Ready ({})", firmware));
+                                    if let Some(ref version) = info.version {
+                                        ui.text_disabled(format!("  Version {}", version));
+                                    }
+                                    if let Some(ref options) = info.options {
+                                        ui.text_disabled(format!("  Options [{}], {} planner blocks, {} byte buffer",
+                                            options,
+                                            info.planner_blocks.map_or(String::from("?"), |b| b.to_string()),
+                                            info.rx_buffer_size,
+                                        ));
+                                    }
+                                }
+                                ConnectionState::Lost(reason)      => ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("  Connection lost: {}", reason)),
+                                ConnectionState::Reconnecting{attempt, reason} => {
+                                    ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("  Reconnecting (attempt {})...", attempt + 1));
+                                    if ui.is_item_hovered() {
+                                        ui.tooltip_text(reason);
+                                    }
+                                }
                             }
                         }
-                    }
-                } else {
-                    for (i, p) in self.ports.iter().enumerate() {
-                        ui.text(&format!("[{:2}] {:?}", i, p.port_name));
-                        ui.same_line(ww - 80.0);
-                        if ui.small_button(im_strf!("Connect##{}", p.port_name)) {
-                            let gcode_task_handle = start_gcode_sender_task(p.port_name.clone(), self.baud_rate as u32);
-                            self.connection = Some((i, gcode_task_handle));
+                        Some(_) => {}
+                        None => {
+                            ui.same_line(ww - 80.0);
+                            if ui.small_button(im_strf!("Connect##{}", endpoint)) {
+                                self.connect(endpoint.clone(), async_runtime);
+                            }
                         }
                     }
                 }
 
+                if connected.is_none() {
+                    ui.separator();
+
+                    // e.g. tcp://192.168.1.50:23, telnet://grbl.local or unix:///tmp/grbl.sock
+                    let width = ui.push_item_width(ww - 80.0);
+                    ui.input_text(im_str!("##Endpoint"), &mut self.endpoint_input)
+                        .resize_buffer(true)
+                        .build();
+                    width.pop(ui);
+                    if ui.is_item_hovered() {
+                        ui.tooltip_text("tcp://host:port, telnet://host or unix:///path");
+                    }
+
+                    ui.same_line(ww - 80.0);
+                    if ui.small_button(im_str!("Connect##Endpoint")) && !self.endpoint_input.to_str().trim().is_empty() {
+                        self.connect(self.endpoint_input.to_str().trim().to_string(), async_runtime);
+                    }
+                }
+
                 ui.separator();
 
                 if ui.button(im_str!("Refresh"), [80.0, 20.0]) {
@@ -260,6 +428,7 @@ impl UIState {
                     if !self.dialog_open.fetch_or(true, Ordering::SeqCst) {
                         let dialog_open = self.dialog_open.clone();
                         let gcode_programs = self.gcode_programs.clone();
+                        let import_errors = self.import_errors.clone();
                         async_runtime.spawn_blocking(move || {
 
                             match nfd::open_file_multiple_dialog(None, None) {
@@ -269,9 +438,14 @@ impl UIState {
                                 },
                                 Ok(nfd::Response::OkayMultiple(paths)) => {
                                     for path in paths {
-                                        let program = std::fs::read_to_string(&path).unwrap();
-                                        let gcode_program = GcodeProgram::load(PathBuf::from(&path), program);
-                                        gcode_programs.lock().unwrap().push(gcode_program);
+                                        let loaded = std::fs::read_to_string(&path)
+                                            .map_err(|e| e.to_string())
+                                            .and_then(|program| GcodeProgram::load(PathBuf::from(&path), program));
+
+                                        match loaded {
+                                            Ok(gcode_program) => gcode_programs.lock().unwrap().push(gcode_program),
+                                            Err(e) => import_errors.lock().unwrap().push(format!("{}: {}", path, e)),
+                                        }
                                     }
                                 }
                                 Ok(nfd::Response::Cancel)              => println!("User canceled"),
@@ -283,6 +457,17 @@ impl UIState {
                     }
                 }
 
+                let mut import_errors = self.import_errors.lock().unwrap();
+                if !import_errors.is_empty() {
+                    for error in import_errors.iter() {
+                        ui.text_colored([1.0, 0.3, 0.3, 1.0], im_strf!("Could not load {}", error));
+                    }
+                    if ui.small_button(im_str!("Clear##Clear Import Errors")) {
+                        import_errors.clear();
+                    }
+                }
+                drop(import_errors);
+
                 ui.separator();
                 
 
@@ -298,7 +483,7 @@ impl UIState {
                         if let Some((_, ref conn)) = self.connection {
                             ui.same_line(ui.window_content_region_width() - 196.0);
 
-                            ui.text(&format!("{:>6} /", conn.gcode_line.load(Ordering::Relaxed)));
+                            ui.text(&format!("{:>6} /", conn.program_line()));
                         }
 
                         ui.same_line(ui.window_content_region_width() - 128.0);
@@ -371,6 +556,91 @@ impl UIState {
 
                 ui.separator();
 
+                let streaming_modes = [StreamingMode::CharacterCounting, StreamingMode::SendResponse];
+                let mut streaming_mode_i = streaming_modes.iter().position(|&m| m == self.streaming_mode).unwrap_or(0);
+
+                if ComboBox::new(im_str!("Streaming")).build_simple_string(ui, &mut streaming_mode_i, &[
+                    im_str!("Character Counting"),
+                    im_str!("Send-Response"),
+                ]) {
+                    self.streaming_mode = streaming_modes[streaming_mode_i];
+
+                    if let Some((_, ref conn)) = self.connection {
+                        conn.set_streaming_mode(self.streaming_mode);
+                    }
+                }
+
+                if ui.checkbox(im_str!("Number Lines"), &mut self.line_numbering) {
+                    if let Some((_, ref conn)) = self.connection {
+                        conn.set_line_numbering(self.line_numbering);
+                    }
+                }
+                if ui.is_item_hovered() {
+                    ui.tooltip_text("send each line with its line number, so that the line being executed can be shown");
+                }
+
+                // line numbers are only reported by controllers built with them
+                if let Some((_, ref conn)) = self.connection {
+                    if self.line_numbering && !conn.info().supports(Features::LINE_NUMBERS) {
+                        ui.same_line(0.0);
+                        ui.text_colored([1.0, 0.8, 0.2, 1.0], "(not reported by this build)");
+                    }
+                }
+
+                // status is polled faster while the machine moves
+                let mut active_ms = self.poll_rates.active.as_millis() as i32;
+                let mut idle_ms = self.poll_rates.idle.as_millis() as i32;
+
+                let width = ui.push_item_width(80.0);
+                let mut changed = ui.input_int(im_str!("Active Poll (ms)"), &mut active_ms).step(10).build();
+                changed |= ui.input_int(im_str!("Idle Poll (ms)"), &mut idle_ms).step(10).build();
+                width.pop(ui);
+
+                if changed {
+                    self.poll_rates = PollRates {
+                        active : Duration::from_millis(active_ms.max(20).min(5000) as u64),
+                        idle : Duration::from_millis(idle_ms.max(20).min(5000) as u64),
+                    };
+
+                    if let Some((_, ref conn)) = self.connection {
+                        conn.set_poll_rates(self.poll_rates);
+                    }
+                }
+
+                // GRBL can't change tools, so the program stops at M6 and waits at this position
+                if CollapsingHeader::new(im_str!("Tool Change")).build(ui) {
+                    let settings = &mut self.tool_change_settings;
+
+                    let width = ui.push_item_width(160.0);
+                    let mut changed = ui.input_float3(im_str!("Position"), &mut settings.position).build();
+                    changed |= ui.input_float2(im_str!("Probe XY"), &mut settings.probe_position).build();
+                    width.pop(ui);
+                    if ui.is_item_hovered() {
+                        ui.tooltip_text("where the tool length sensor is, probed from the Z of the position");
+                    }
+
+                    let width = ui.push_item_width(80.0);
+                    changed |= ui.input_float(im_str!("Probe Distance"), &mut settings.probe_distance).build();
+                    changed |= ui.input_float(im_str!("Probe Feed"), &mut settings.probe_feed).build();
+                    width.pop(ui);
+
+                    changed |= ui.checkbox(im_str!("Stop on T words without M6"), &mut settings.on_tool_select);
+                    changed |= ui.checkbox(im_str!("Measure tool lengths"), &mut settings.measure);
+                    if ui.is_item_hovered() {
+                        ui.tooltip_text("probe the tool that set work Z at the first tool change of a program, then offset every new tool by its difference to it");
+                    }
+                    ui.text_disabled("(machine coordinates)");
+
+                    if changed {
+                        settings.probe_distance = settings.probe_distance.abs();
+                        settings.probe_feed = settings.probe_feed.max(1.0);
+
+                        if let Some((_, ref conn)) = self.connection {
+                            conn.set_tool_change_settings(*settings);
+                        }
+                    }
+                }
+
                 if let Some(ref ap) = self.active_program {
                     if let Some((_, ref conn)) = self.connection {
                         if ui.small_button(im_str!("Validate Program")) {
@@ -379,18 +649,135 @@ impl UIState {
                         if ui.small_button(im_str!("Start Program")) {
                             conn.start_program(ap.clone());
                         }
-                        if !conn.paused.load(Ordering::Relaxed) {
+                        if !conn.is_paused() {
                             if ui.small_button(im_str!("Pause Program")) {
-                                conn.pause_gcode();
+                                conn.pause_program();
                             }
                         } else {
                             if ui.small_button(im_str!("Unpause Program")) {
-                                conn.unpause_gcode();
+                                conn.resume_program();
                             }
                         }
                         if ui.small_button(im_str!("Stop Program")) {
                             conn.stop_program();
                         }
+
+                        if let Some(progress) = conn.progress() {
+                            ui.separator();
+
+                            // `Ln:` is the line being executed when the sender numbered the program, while
+                            // the acknowledged lines may still be waiting in the planner. The numbers a
+                            // program carries itself don't say where the line is in the program.
+                            let executing = if progress.numbered {conn.machine_status().line_number as usize} else {0};
+                            let done_lines = if executing > progress.start_line && executing <= progress.acknowledged {
+                                executing - 1
+                            } else {
+                                progress.acknowledged
+                            };
+
+                            let total = ap.estimated_time(progress.start_line, progress.total_lines);
+                            let done = ap.estimated_time(progress.start_line, done_lines);
+                            let elapsed = progress.elapsed().as_secs_f32();
+                            let running = progress.running_time().as_secs_f32();
+
+                            let fraction = match progress.finished {
+                                Some(_) if progress.acknowledged >= progress.total_lines => 1.0,
+                                _ if total > 0.0 => done / total,
+                                _ => (done_lines - progress.start_line) as f32 / (progress.total_lines - progress.start_line).max(1) as f32,
+                            };
+
+                            // scale the estimate by how much slower or faster the machine has been so far,
+                            // leaving out the time it waited in a hold or for a tool change
+                            let correction = if done > 1.0 && running > 1.0 {running / done} else {1.0};
+                            let remaining = if progress.finished.is_some() {0.0} else {(total - done) * correction};
+
+                            ProgressBar::new(fraction)
+                                .size([-1.0, 0.0])
+                                .overlay_text(im_strf!("{:.1}%", fraction * 100.0))
+                                .build(ui);
+
+                            ui.text(format!("Elapsed {}", HoursMinutesSeconds(elapsed)));
+                            ui.same_line(160.0);
+                            ui.text(format!("Remaining {}", HoursMinutesSeconds(remaining)));
+                            if ui.is_item_hovered() {
+                                ui.tooltip_text(format!("estimated {} for the whole program", HoursMinutesSeconds(total * correction)));
+                            }
+
+                            if progress.finished.is_none() && executing > 0 {
+                                if let Some(line) = ap.lines.get(executing - 1) {
+                                    ui.text(format!("Executing line {}", executing));
+                                    ui.text_disabled(format!("  {}", line.trim_end()));
+                                }
+                            }
+                        }
+
+                        // starting in the middle replays the modal state of the lines before,
+                        // then moves to the start at a safe height
+                        ui.separator();
+
+                        let width = ui.push_item_width(80.0);
+                        ui.input_int(im_str!("From Line"), &mut self.start_line).build();
+                        ui.input_float(im_str!("Clearance Z"), &mut self.resume_clearance).build();
+                        width.pop(ui);
+
+                        self.start_line = self.start_line.max(1).min(ap.lines.len().max(1) as i32);
+                        let start = self.start_line as usize - 1;
+
+                        if let Some(line) = ap.lines.get(start) {
+                            ui.text_disabled(format!("  {}", line.trim_end()));
+                        }
+
+                        if ui.small_button(im_str!("Start From Line")) {
+                            // the simulation only knows millimeters, so the lead-in is in millimeters too
+                            let inches = conn.info().parser_state.map_or(false, |p| p.units == ParserUnits::Inches);
+
+                            match crate::simulation::resume_program(&ap.lines, start, self.resume_clearance) {
+                                Ok(_) if inches => {
+                                    self.resume_message = String::from("Cannot resume a program in inches (G20).");
+                                }
+                                Ok(lead_in) => {
+                                    self.resume_message = if conn.start_program_from(ap.clone(), start, lead_in.clone()) {
+                                        format!("Started from line {} after:\n{}", start + 1, lead_in.join("\n"))
+                                    } else {
+                                        String::from("Cannot start while a program is running.")
+                                    };
+                                }
+                                Err(e) => self.resume_message = e,
+                            }
+                        }
+
+                        if !self.resume_message.is_empty() {
+                            ui.text_wrapped(im_strf!("{}", self.resume_message));
+                        }
+                    }
+                }
+
+                if let Some((_, ref conn)) = self.connection {
+                    if let Some(reason) = conn.program_stopped() {
+                        ui.separator();
+                        ui.text_colored([1.0, 0.8, 0.2, 1.0], reason);
+                    }
+
+                    let errors = conn.errors();
+
+                    if !errors.is_empty() {
+                        ui.separator();
+                        ui.text(format!("Errors ({})", errors.len()));
+                        ui.same_line(ui.window_content_region_width() - 40.0);
+                        if ui.small_button(im_str!("Clear##Clear Errors")) {
+                            conn.clear_errors();
+                        }
+
+                        for report in errors.iter() {
+                            match report.program_line {
+                                Some(n) => ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("Line {}: {}", n + 1, report.error)),
+                                None    => ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("Command: {}", report.error)),
+                            }
+                            if ui.is_item_hovered() {
+                                ui.tooltip_text(&report.error.description);
+                            }
+                            ui.text_disabled(format!("  {}", report.line));
+                        }
                     }
                 }
             });
@@ -419,7 +806,7 @@ impl UIState {
 
                 if ui.small_button(im_str!("Send Command")) || hit_enter {
                     if let Some((_, ref conn)) = self.connection {
-                        conn.send_string(self.command_input.to_string());
+                        conn.send_line(self.command_input.to_string());
                         self.command_history.push(self.command_input.to_string());
                         self.command_input.clear();
                     }
@@ -451,37 +838,123 @@ impl UIState {
 
                 let [ww, wh] = ui.window_content_region_max();
 
-                let machine_status = self.connection.as_ref().map(|conn| conn.1.get_machine_status()).unwrap_or_default();
+                let machine_status = self.connection.as_ref().map(|conn| conn.1.machine_status()).unwrap_or_default();
+
+                // features the controller wasn't built with are hidden, once its options are known
+                let info = self.connection.as_ref().map(|conn| conn.1.info()).unwrap_or_default();
+                let variable_spindle = info.supports(Features::VARIABLE_SPINDLE);
 
 
                 ui.text(format!("Machine State: {:?}", machine_status.state));
+
+                // only reported by grblHAL
+                if let Some(tool) = machine_status.tool {
+                    ui.same_line(0.0);
+                    ui.text(format!("  Tool: T{}", tool));
+                }
+                if let Some(homed) = machine_status.homed {
+                    ui.same_line(0.0);
+                    ui.text(if homed {"  Homed"} else {"  Not homed"});
+                }
+                if let Some((progress, ref file)) = machine_status.sd_progress {
+                    ui.text(format!("SD: {}", file));
+                    ui.same_line(0.0);
+                    ProgressBar::new(progress / 100.0).size([-1.0, 0.0]).build(ui);
+                }
+
+                // a limit pin for every axis the controller has, then the control pins
+                let limits = AXIS_NAMES.iter().take(machine_status.axis_count()).enumerate()
+                    .map(|(axis, name)| (name.to_string(), InputPins::limit(axis), format!("{} limit", name)));
+                let controls = [
+                    ("P", InputPins::PROBE,       "Probe"),
+                    ("D", InputPins::DOOR,        "Safety door"),
+                    ("H", InputPins::HOLD,        "Feed hold"),
+                    ("R", InputPins::SOFT_RESET,  "Soft reset"),
+                    ("S", InputPins::CYCLE_START, "Cycle start"),
+                ];
+                let pins : Vec<_> = limits
+                    .chain(controls.iter().map(|(label, pin, name)| (label.to_string(), *pin, name.to_string())))
+                    .collect();
+
+                ui.text("Pins:");
+                for (i, (label, pin, name)) in pins.iter().enumerate() {
+                    ui.same_line(48.0 + 24.0 * i as f32);
+                    if machine_status.inputs.contains(*pin) {
+                        ui.text_colored([1.0, 0.2, 0.2, 1.0], label);
+                    } else {
+                        ui.text_disabled(label);
+                    }
+                    if ui.is_item_hovered() {
+                        ui.tooltip_text(name);
+                    }
+                }
                 ui.separator();
 
                 match machine_status.state {
-                    crate::grbl::GRBLState::Idle    => {}
-                    crate::grbl::GRBLState::Run     => {}
-                    crate::grbl::GRBLState::Hold(_) => {}
-                    crate::grbl::GRBLState::Jog     => {}
-                    crate::grbl::GRBLState::Alarm   => {}
-                    crate::grbl::GRBLState::Door(_) => {}
-                    crate::grbl::GRBLState::Check   => {}
-                    crate::grbl::GRBLState::Home    => {}
-                    crate::grbl::GRBLState::Sleep   => {}
+                    MachineState::Idle    => {}
+                    MachineState::Run     => {}
+                    MachineState::Hold(_) => {
+                        let color = if machine_status.state.can_resume() {[0.2, 1.0, 0.2, 1.0]} else {[1.0, 0.8, 0.2, 1.0]};
+                        ui.text_colored(color, machine_status.state.description().unwrap_or_default());
+                    }
+                    MachineState::Jog     => {}
+                    MachineState::Alarm   => {}
+                    MachineState::Door(n) => {
+                        let color = if machine_status.state.can_resume() {[0.2, 1.0, 0.2, 1.0]} else {[1.0, 0.8, 0.2, 1.0]};
+                        ui.text_colored(color, machine_status.state.description().unwrap_or("Unknown door state."));
+
+                        // the steps of the door cycle, with the current one highlighted
+                        let steps = [(2, "Parking"), (1, "Door Open"), (0, "Door Closed"), (3, "Restoring")];
+                        for (i, &(step, label)) in steps.iter().enumerate() {
+                            if i > 0 {
+                                ui.same_line(0.0);
+                                ui.text_disabled(">");
+                                ui.same_line(0.0);
+                            }
+                            if step == n {
+                                ui.text_colored([1.0, 0.8, 0.2, 1.0], label);
+                            } else {
+                                ui.text_disabled(label);
+                            }
+                        }
+                    }
+                    MachineState::Check   => {}
+                    MachineState::Home    => {}
+                    MachineState::Sleep   => {}
+                    MachineState::Tool    => {}
                 }
 
+                if machine_status.restoring_spindle {
+                    ui.text_colored([1.0, 0.8, 0.2, 1.0], "Restoring spindle...");
+                }
 
-                let tok = ui.push_style_colors(&[
-                    (StyleColor::Button,        [0.0, 0.5, 0.0, 1.0]),
-                    (StyleColor::ButtonActive,  [0.0, 0.75, 0.0, 1.0]),
-                    (StyleColor::ButtonHovered, [0.0, 1.0, 0.0, 1.0])
-                ]);
+                // GRBL ignores a cycle start until the hold or door cycle is complete
+                let can_resume = machine_status.state.can_resume();
+
+
+                let tok = if can_resume {
+                    ui.push_style_colors(&[
+                        (StyleColor::Button,        [0.0, 0.5, 0.0, 1.0]),
+                        (StyleColor::ButtonActive,  [0.0, 0.75, 0.0, 1.0]),
+                        (StyleColor::ButtonHovered, [0.0, 1.0, 0.0, 1.0])
+                    ])
+                } else {
+                    ui.push_style_colors(&[
+                        (StyleColor::Button,        [0.3, 0.3, 0.3, 1.0]),
+                        (StyleColor::ButtonActive,  [0.3, 0.3, 0.3, 1.0]),
+                        (StyleColor::ButtonHovered, [0.3, 0.3, 0.3, 1.0])
+                    ])
+                };
 
 
-                if ui.button(im_str!("Start##Cycle Start"), [ww / 3.0 - 10.0, 24.0]) {
+                if ui.button(im_str!("Start##Cycle Start"), [ww / 3.0 - 10.0, 24.0]) && can_resume {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::CycleStartOrResume);
+                        conn.1.realtime(RealtimeAction::CycleStart);
                     }
                 }
+                if !can_resume && ui.is_item_hovered() {
+                    ui.tooltip_text("Wait for the hold to complete and the door to close.");
+                }
                 tok.pop(ui);
 
                 ui.same_line(ww / 3.0 + 10.0);
@@ -494,7 +967,7 @@ impl UIState {
                 if ui.button(im_str!("Hold##Feed Hold"), [ww / 3.0 - 10.0, 24.0]) {
 
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::FeedHold);
+                        conn.1.realtime(RealtimeAction::FeedHold);
                     }
                 }
                 tok.pop(ui);
@@ -509,56 +982,82 @@ impl UIState {
                 if ui.button(im_str!("Reset##Reset"), [ww / 3.0 - 10.0, 24.0]) {
 
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::SoftReset);
+                        conn.1.realtime(RealtimeAction::Reset);
                     }
                 }
 
 
                 tok.pop(ui);
 
-                ui.separator();
-                ui.text("Tool Position");
-                ui.separator();
+                if ui.button(im_str!("Home##Homing Cycle"), [ww / 3.0 - 10.0, 24.0]) {
+                    if let Some(ref conn) = self.connection {
+                        conn.1.home();
+                    }
+                }
 
+                ui.same_line(ww / 3.0 + 10.0);
 
-                self.machine_coords = machine_status.machine_position;
-                for i in 0..3 {
-                    self.work_coords[i] = machine_status.machine_position[i] - machine_status.work_offset[i];
+                if ui.button(im_str!("Unlock##Kill Alarm Lock"), [ww / 3.0 - 10.0, 24.0]) {
+                    if let Some(ref conn) = self.connection {
+                        conn.1.unlock();
+                    }
                 }
 
-                if ui.input_float3(im_str!("Machine Coords"), &mut self.machine_coords).build() {
-                    //set machine offset
-                }
+                ui.same_line(2.0 * ww / 3.0 + 10.0);
 
-                if ui.input_float3(im_str!("Work Coords"), &mut self.work_coords)
-                    .enter_returns_true(true)
-                    .build() {
-                    
-                    
-                    //set work offset
+                // behaves like opening the safety door: the machine holds, parks if enabled, and waits for a cycle start
+                if ui.button(im_str!("Door##Safety Door"), [ww / 3.0 - 10.0, 24.0]) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_string(format!("G10 P0 L2 X{} Y{} Z{}", 
-                            self.machine_coords[0] - self.work_coords[0],
-                            self.machine_coords[1] - self.work_coords[1],
-                            self.machine_coords[2] - self.work_coords[2]
-                        ));
+                        conn.1.realtime(RealtimeAction::SafetyDoor);
                     }
                 }
 
-                let mut work_offset = machine_status.work_offset;
+                ui.separator();
+                ui.text("Tool Position");
+                ui.separator();
 
-                if ui.input_float3(im_str!("Work Offset"), &mut work_offset)
-                    .enter_returns_true(true)
-                    .build() {
-                    //set work offset
-                    
-                    if let Some(ref conn) = self.connection {
-                        conn.1.send_string(format!("G10 P0 L2 X{} Y{} Z{}", 
-                            work_offset[0],
-                            work_offset[1],
-                            work_offset[2]
-                        ));
+
+                let axis_count = machine_status.axis_count();
+                let work_position = machine_status.work_position();
+
+                ui.text_disabled("    Machine     Work        Offset");
+
+                for axis in 0..axis_count {
+                    let name = AXIS_NAMES[axis];
+
+                    self.machine_coords[axis] = machine_status.machine_position[axis];
+                    self.work_coords[axis] = work_position[axis];
+                    let mut work_offset = machine_status.work_offset[axis];
+
+                    ui.text(format!("{}", name));
+                    ui.same_line(32.0);
+
+                    let width = ui.push_item_width(80.0);
+
+                    ui.input_float(im_strf!("##Machine {}", name), &mut self.machine_coords[axis])
+                        .read_only(true)
+                        .build();
+                    ui.same_line(0.0);
+
+                    if ui.input_float(im_strf!("##Work {}", name), &mut self.work_coords[axis])
+                        .enter_returns_true(true)
+                        .build() {
+                        //set work offset
+                        if let Some(ref conn) = self.connection {
+                            conn.1.send_line(format!("G10 P0 L2 {}{}", name, self.machine_coords[axis] - self.work_coords[axis]));
+                        }
+                    }
+                    ui.same_line(0.0);
+
+                    if ui.input_float(im_strf!("##Offset {}", name), &mut work_offset)
+                        .enter_returns_true(true)
+                        .build() {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.send_line(format!("G10 P0 L2 {}{}", name, work_offset));
+                        }
                     }
+
+                    width.pop(ui);
                 }
 
                 let work_coords = [
@@ -571,16 +1070,25 @@ impl UIState {
                     im_str!("G59"),
                 ];
 
+                let info = self.connection.as_ref().map(|conn| conn.1.info()).unwrap_or_default();
+
+                // follow the coordinate system reported by GRBL, unless machine coordinates are selected
+                if let Some(parser_state) = info.parser_state {
+                    if self.work_coord_system != 0 {
+                        self.work_coord_system = parser_state.coordinate_system as usize + 1;
+                    }
+                }
+
                 if imgui::ComboBox::new(im_str!("Work Coordinate System")).build_simple_string(ui, &mut self.work_coord_system, &work_coords) {
                     if let Some(ref conn) = self.connection {
                         match self.work_coord_system {
-                            0 => {conn.1.send_string(format!("G53"));}
-                            1 => {conn.1.send_string(format!("G54"));}
-                            2 => {conn.1.send_string(format!("G55"));}
-                            3 => {conn.1.send_string(format!("G56"));}
-                            4 => {conn.1.send_string(format!("G57"));}
-                            5 => {conn.1.send_string(format!("G58"));}
-                            6 => {conn.1.send_string(format!("G59"));}
+                            0 => {conn.1.send_line(format!("G53"));}
+                            1 => {conn.1.send_line(format!("G54"));}
+                            2 => {conn.1.send_line(format!("G55"));}
+                            3 => {conn.1.send_line(format!("G56"));}
+                            4 => {conn.1.send_line(format!("G57"));}
+                            5 => {conn.1.send_line(format!("G58"));}
+                            6 => {conn.1.send_line(format!("G59"));}
                             _ => panic!()
                         }
                     }
@@ -588,15 +1096,82 @@ impl UIState {
 
                 if let Some(ref conn) = self.connection {
                     if self.work_coord_system != 0 {
-                        if ui.small_button(im_str!("X = 0")) {conn.1.send_string(format!("G10 P0 L2 X{}", -machine_status.machine_position[0]));}
-                        ui.same_line(64.0);
-                        if ui.small_button(im_str!("Y = 0")) {conn.1.send_string(format!("G10 P0 L2 Y{}", -machine_status.machine_position[1]));}
-                        ui.same_line(128.0);
-                        if ui.small_button(im_str!("Z = 0")) {conn.1.send_string(format!("G10 P0 L2 Z{}", -machine_status.machine_position[2]));}
-                        
-                        if ui.small_button(im_str!("XY = 0")) {conn.1.send_string(format!("G10 P0 L2 X{} Y{}", -machine_status.machine_position[0], -machine_status.machine_position[1]));}
+                        for axis in 0..axis_count {
+                            if axis > 0 {
+                                ui.same_line(8.0 + axis as f32 * 64.0);
+                            }
+                            let name = AXIS_NAMES[axis];
+                            if ui.small_button(im_strf!("{} = 0", name)) {conn.1.send_line(format!("G10 P0 L2 {}{}", name, -machine_status.machine_position[axis]));}
+                        }
+
+                        let zero_all = AXIS_NAMES.iter().take(axis_count)
+                            .enumerate()
+                            .map(|(axis, name)| format!("{}{}", name, -machine_status.machine_position[axis]))
+                            .collect::<Vec<_>>()
+                            .join(" ");
+
+                        if ui.small_button(im_str!("XY = 0")) {conn.1.send_line(format!("G10 P0 L2 X{} Y{}", -machine_status.machine_position[0], -machine_status.machine_position[1]));}
                         ui.same_line(64.0);
-                        if ui.small_button(im_str!("XYZ = 0")) {conn.1.send_string(format!("G10 P0 L2 X{} Y{} Z{}", -machine_status.machine_position[0], -machine_status.machine_position[1], -machine_status.machine_position[2]));}
+                        if ui.small_button(im_str!("All = 0")) {conn.1.send_line(format!("G10 P0 L2 {}", zero_all));}
+                    }
+                }
+
+                if CollapsingHeader::new(im_str!("Offsets")).build(ui) {
+
+                    let names = ["G54", "G55", "G56", "G57", "G58", "G59"];
+                    let active = info.parser_state.map(|ps| ps.coordinate_system as usize);
+
+                    // one column per axis, e.g. "G54     0.000     0.000     0.000"
+                    let columns = |values : AxisValues| values.as_slice().iter()
+                        .map(|v| format!(" {:>9.3}", v))
+                        .collect::<String>();
+
+                    for (i, name) in names.iter().enumerate() {
+                        let text = format!("{}{}", name, columns(info.offsets.work_coordinates[i]));
+                        if active == Some(i) {
+                            ui.text_colored([0.2, 1.0, 0.2, 1.0], text);
+                        } else {
+                            ui.text(text);
+                        }
+                    }
+
+                    for (name, values) in [("G28", info.offsets.g28), ("G30", info.offsets.g30), ("G92", info.offsets.g92)].iter() {
+                        ui.text(format!("{}{}", name, columns(*values)));
+                    }
+
+                    ui.text(format!("TLO {:>9.3}", info.offsets.tool_length_offset));
+
+                    match info.probe {
+                        Some(probe) => {
+                            ui.text(format!("PRB{}", columns(probe.position)));
+                            ui.same_line(0.0);
+                            if probe.success {
+                                ui.text_colored([0.2, 1.0, 0.2, 1.0], "ok");
+                            } else {
+                                ui.text_colored([1.0, 0.3, 0.3, 1.0], "failed");
+                            }
+                        }
+                        None => {
+                            ui.text_disabled("PRB   (no probe cycle yet)");
+                        }
+                    }
+
+                    if let Some(parser_state) = info.parser_state {
+                        ui.text(format!("Parser: {:?} {:?} {:?} {:?} T{} F{} S{}",
+                            parser_state.motion_mode,
+                            parser_state.units,
+                            parser_state.distance_mode,
+                            parser_state.plane,
+                            parser_state.tool,
+                            parser_state.feed,
+                            parser_state.speed,
+                        ));
+                    }
+
+                    if ui.small_button(im_str!("Refresh##Refresh Offsets")) {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.refresh_info();
+                        }
                     }
                 }
 
@@ -606,9 +1181,11 @@ impl UIState {
                 ui.text(if prev_spindle_on {"Spindle (on)"} else {"Spindle (off)"});
                 ui.separator();
 
-                Slider::new(im_str!("Target RPM"))
-                    .range(7200..=24000)
-                    .build(ui, &mut self.spindle_rpm_setpoint);
+                if variable_spindle {
+                    Slider::new(im_str!("Target RPM"))
+                        .range(7200..=24000)
+                        .build(ui, &mut self.spindle_rpm_setpoint);
+                }
 
 
                 self.spindle_on = prev_spindle_on;
@@ -624,7 +1201,11 @@ impl UIState {
 
                 if ui.button(im_str!("On##Spindle On"), [ww / 2.0 - 10.0, 24.0]) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_string(format!("M3 S{}", self.spindle_rpm_setpoint));
+                        if variable_spindle {
+                            conn.1.send_line(format!("M3 S{}", self.spindle_rpm_setpoint));
+                        } else {
+                            conn.1.send_line(format!("M3"));
+                        }
                     }
                 }
 
@@ -640,45 +1221,67 @@ impl UIState {
                 if ui.button(im_str!("Off##Spindle Off"), [ww / 2.0 - 10.0, 24.0]) {
 
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_string(format!("M5"));
+                        conn.1.send_line(format!("M5"));
                     }
                 }
 
                 tok.pop(ui);    
 
                 ui.separator();
-                ui.text("Overrides");
+                ui.text("Coolant");
                 ui.separator();
 
-                ui.text(format!("Spindle Override:      {:>3}%", machine_status.override_speed));
-
-                if ui.small_button(im_str!("-10%##Spindle Override -10")) {
+                if ui.button(im_strf!("Flood ({})##Flood Toggle", if machine_status.flood_coolant {"on"} else {"off"}), [ww / 2.0 - 10.0, 24.0]) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::SpindleOverrideDec10);
+                        conn.1.realtime(RealtimeAction::FloodToggle);
                     }
                 }
-                ui.same_line(48.0);
-                if ui.small_button(im_str!("-1%##Spindle Override -1")) {
-                    if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::SpindleOverrideDec01);
+
+                if info.supports(Features::MIST_COOLANT) {
+                    ui.same_line(ww / 2.0 + 10.0);
+                    if ui.button(im_strf!("Mist ({})##Mist Toggle", if machine_status.mist_coolant {"on"} else {"off"}), [ww / 2.0 - 10.0, 24.0]) {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.realtime(RealtimeAction::MistToggle);
+                        }
                     }
                 }
-                ui.same_line(48.0+32.0);
-                if ui.small_button(im_str!("+1%##Spindle Override +1")) {
-                    if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::SpindleOverrideInc01);
+
+                ui.separator();
+                ui.text("Overrides");
+                ui.separator();
+
+                // the spindle speed can only be overridden with a variable spindle
+                if variable_spindle {
+                    ui.text(format!("Spindle Override:      {:>3}%", machine_status.override_speed));
+
+                    if ui.small_button(im_str!("-10%##Spindle Override -10")) {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.realtime(RealtimeAction::SpindleOverride(OverrideStep::Decrease10));
+                        }
                     }
-                }
-                ui.same_line(48.0+32.0+32.0);
-                if ui.small_button(im_str!("+10%##Spindle Override +10")) {
-                    if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::SpindleOverrideInc10);
+                    ui.same_line(48.0);
+                    if ui.small_button(im_str!("-1%##Spindle Override -1")) {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.realtime(RealtimeAction::SpindleOverride(OverrideStep::Decrease1));
+                        }
                     }
-                }
-                ui.same_line(48.0+32.0+32.0+40.0);
-                if ui.small_button(im_str!("Reset##Spindle Override Reset")) {
-                    if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::SpindleOverrideReset);
+                    ui.same_line(48.0+32.0);
+                    if ui.small_button(im_str!("+1%##Spindle Override +1")) {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.realtime(RealtimeAction::SpindleOverride(OverrideStep::Increase1));
+                        }
+                    }
+                    ui.same_line(48.0+32.0+32.0);
+                    if ui.small_button(im_str!("+10%##Spindle Override +10")) {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.realtime(RealtimeAction::SpindleOverride(OverrideStep::Increase10));
+                        }
+                    }
+                    ui.same_line(48.0+32.0+32.0+40.0);
+                    if ui.small_button(im_str!("Reset##Spindle Override Reset")) {
+                        if let Some(ref conn) = self.connection {
+                            conn.1.realtime(RealtimeAction::SpindleOverride(OverrideStep::Reset));
+                        }
                     }
                 }
 
@@ -686,31 +1289,31 @@ impl UIState {
 
                 if ui.small_button(im_str!("-10%##Feed Override -10")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::FeedOverrideDec10);
+                        conn.1.realtime(RealtimeAction::FeedOverride(OverrideStep::Decrease10));
                     }
                 }
                 ui.same_line(48.0);
                 if ui.small_button(im_str!("-1%##Feed Override -1")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::FeedOverrideDec01);
+                        conn.1.realtime(RealtimeAction::FeedOverride(OverrideStep::Decrease1));
                     }
                 }
                 ui.same_line(48.0+32.0);
                 if ui.small_button(im_str!("+1%##Feed Override +1")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::FeedOverrideInc01);
+                        conn.1.realtime(RealtimeAction::FeedOverride(OverrideStep::Increase1));
                     }
                 }
                 ui.same_line(48.0+32.0+32.0);
                 if ui.small_button(im_str!("+10%##Feed Override +10")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::FeedOverrideInc10);
+                        conn.1.realtime(RealtimeAction::FeedOverride(OverrideStep::Increase10));
                     }
                 }
                 ui.same_line(48.0+32.0+32.0+40.0);
                 if ui.small_button(im_str!("Reset##Feed Override Reset")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::FeedOverrideReset);
+                        conn.1.realtime(RealtimeAction::FeedOverride(OverrideStep::Reset));
                     }
                 }
 
@@ -719,19 +1322,19 @@ impl UIState {
 
                 if ui.small_button(im_str!(" 25%##Rapid Override 25")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::RapidOverrideQuarter);
+                        conn.1.realtime(RealtimeAction::RapidOverride(RapidOverride::Quarter));
                     }
                 }
                 ui.same_line(48.0);
                 if ui.small_button(im_str!("50%##Rapid Override 50")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::RapidOverrideHalf);
+                        conn.1.realtime(RealtimeAction::RapidOverride(RapidOverride::Half));
                     }
                 }
                 ui.same_line(48.0+32.0);
                 if ui.small_button(im_str!("100%##Rapid Override 100")) {
                     if let Some(ref conn) = self.connection {
-                        conn.1.send_realtime_command(crate::grbl::GRBLRealtimeCommand::RapidOverrideFull);
+                        conn.1.realtime(RealtimeAction::RapidOverride(RapidOverride::Full));
                     }
                 }
 
@@ -750,79 +1353,858 @@ impl UIState {
                 
                 let jog_distance = jog_distances[self.jog_distance];
 
-                ui.text("Jog X");
-                let mut jog_x = 0;
-                if ui.small_button(im_str!("   <<   ##Jog X -10")) {jog_x = -10;}
-                ui.same_line(8.0 + 1.0*72.0);
-                if ui.small_button(im_str!("    <   ##Jog X  -1")) {jog_x =  -1;}
-                ui.same_line(8.0 + 2.0*72.0);
-                if ui.small_button(im_str!("   >    ##Jog X  10")) {jog_x =   1;}
-                ui.same_line(8.0 + 3.0*72.0);
-                if ui.small_button(im_str!("   >>   ##Jog X   1")) {jog_x =  10;}
-
-                ui.text("Jog Y");
-                let mut jog_y = 0;
-                if ui.small_button(im_str!("   vv   ##Jog Y -10")) {jog_y = -10;}
-                ui.same_line(8.0 + 1.0*72.0);
-                if ui.small_button(im_str!("    v   ##Jog Y  -1")) {jog_y =  -1;}
-                ui.same_line(8.0 + 2.0*72.0);
-                if ui.small_button(im_str!("   ^    ##Jog Y  10")) {jog_y =   1;}
-                ui.same_line(8.0 + 3.0*72.0);
-                if ui.small_button(im_str!("   ^^   ##Jog Y   1")) {jog_y =  10;}
-
-                ui.text("Jog Z");
-                let mut jog_z = 0;
-                if ui.small_button(im_str!("   vv   ##Jog Z -10")) {jog_z = -10;}
-                ui.same_line(8.0 + 1.0*72.0);
-                if ui.small_button(im_str!("    v   ##Jog Z  -1")) {jog_z =  -1;}
-                ui.same_line(8.0 + 2.0*72.0);
-                if ui.small_button(im_str!("   ^    ##Jog Z   1")) {jog_z =   1;}
-                ui.same_line(8.0 + 3.0*72.0);
-                if ui.small_button(im_str!("   ^^   ##Jog Z  10")) {jog_z =  10;}
+                // rotary axes get the same buttons, the jog distance is in degrees for them
+                let mut jog = [0; MAX_AXES];
+
+                for axis in 0..axis_count {
+                    let name = AXIS_NAMES[axis];
+                    let (fast_down, down, up, fast_up) = if axis == 0 {("<<", "<", ">", ">>")} else {("vv", "v", "^", "^^")};
+
+                    ui.text(format!("Jog {}", name));
+                    if ui.small_button(im_strf!("   {:<2}   ##Jog {} -10", fast_down, name)) {jog[axis] = -10;}
+                    ui.same_line(8.0 + 1.0*72.0);
+                    if ui.small_button(im_strf!("   {:<2}   ##Jog {}  -1", down, name)) {jog[axis] =  -1;}
+                    ui.same_line(8.0 + 2.0*72.0);
+                    if ui.small_button(im_strf!("   {:>2}   ##Jog {}   1", up, name)) {jog[axis] =   1;}
+                    ui.same_line(8.0 + 3.0*72.0);
+                    if ui.small_button(im_strf!("   {:>2}   ##Jog {}  10", fast_up, name)) {jog[axis] =  10;}
+                }
 
                 if let Some(ref conn) = self.connection {
+                    for (axis, &steps) in jog.iter().enumerate() {
+                        if steps != 0 {
+                            let mut axes = [None; MAX_AXES];
+                            axes[axis] = Some(jog_distance * steps as f32);
 
-                    if jog_x != 0 {
-                        conn.1.send_command(GRBLCommand::Jog{
-                            x: Some(jog_distance * jog_x as f32),
-                            y: None,
-                            z: None,
-                            feed: self.jog_feed_rate,
-                            incremental: true,
-                            machine_coords: false,
-                        });
+                            conn.1.jog(axes, self.jog_feed_rate, true);
+                        }
                     }
+                }
 
-                    if jog_y != 0 {
-                        conn.1.send_command(GRBLCommand::Jog{
-                            x: None,
-                            y: Some(jog_distance * jog_y as f32),
-                            z: None,
-                            feed: self.jog_feed_rate,
-                            incremental: true,
-                            machine_coords: false,
-                        });
+
+                ui.separator();
+                ui.text("Move to Point");
+                ui.separator();
+
+            });
+
+
+        // this popup explains an alarm and offers the actions that clear it,
+        // and closes by itself once the machine leaves the alarm state
+        let alarm_status = self.connection.as_ref()
+            .map(|conn| conn.1.machine_status())
+            .filter(|status| status.state == MachineState::Alarm);
+
+        match alarm_status {
+            Some(_) if !self.alarm_dismissed => ui.open_popup(im_str!("Alarm")),
+            Some(_) => {}
+            None => self.alarm_dismissed = false,
+        }
+
+        PopupModal::new(ui, im_str!("Alarm"))
+            .always_auto_resize(true)
+            .build(|| {
+
+                let (conn, status) = match (self.connection.as_ref(), alarm_status) {
+                    (Some((_, conn)), Some(status)) => (conn, status),
+                    _ => {
+                        ui.close_current_popup();
+                        return;
                     }
+                };
 
-                    if jog_z != 0 {
-                        conn.1.send_command(GRBLCommand::Jog{
-                            x: None,
-                            y: None,
-                            z: Some(jog_distance * jog_z as f32),
-                            feed: self.jog_feed_rate,
-                            incremental: true,
-                            machine_coords: false,
-                        });
+                let wrap = ui.push_text_wrap_pos(360.0);
+
+                // GRBL starts locked when homing is enabled, without sending an alarm code
+                match status.alarm {
+                    Some(ref alarm) => {
+                        ui.text_colored([1.0, 0.3, 0.3, 1.0], &alarm.name);
+                        ui.text_wrapped(im_strf!("{}", alarm.description));
+                    }
+                    None => {
+                        ui.text_colored([1.0, 0.3, 0.3, 1.0], "Alarm lock");
+                        ui.text_wrapped(im_str!("The machine is locked until it is homed or unlocked."));
                     }
                 }
 
+                if let Some(reason) = conn.program_stopped() {
+                    ui.text_colored([1.0, 0.8, 0.2, 1.0], format!("Program: {}", reason));
+                }
+
+                wrap.pop(ui);
+
+                let recovery = match status.alarm {
+                    _ if status.reset_required => &[AlarmRecovery::SoftReset][..],
+                    Some(ref alarm) => &alarm.recovery[..],
+                    None => &[AlarmRecovery::Home, AlarmRecovery::Unlock][..],
+                };
+
+                if status.reset_required {
+                    ui.text("A soft reset is required before the alarm can be cleared.");
+                }
 
                 ui.separator();
-                ui.text("Move to Point");
+
+                for (i, action) in recovery.iter().enumerate() {
+                    if i > 0 {
+                        ui.same_line(0.0);
+                    }
+
+                    let label = match action {
+                        AlarmRecovery::Unlock    => im_str!("Unlock"),
+                        AlarmRecovery::Home      => im_str!("Home"),
+                        AlarmRecovery::SoftReset => im_str!("Soft Reset"),
+                    };
+
+                    if ui.button(label, [100.0, 24.0]) {
+                        match action {
+                            AlarmRecovery::Unlock    => {conn.unlock();}
+                            AlarmRecovery::Home      => {conn.home();}
+                            AlarmRecovery::SoftReset => {conn.realtime(RealtimeAction::Reset);}
+                        }
+                    }
+                }
+
+                ui.same_line(0.0);
+                if ui.button(im_str!("Dismiss"), [100.0, 24.0]) {
+                    self.alarm_dismissed = true;
+                    ui.close_current_popup();
+                }
+            });
+
+        // this popup asks for the new tool when the program stops at a tool change,
+        // and closes by itself once the program continues
+        let tool_change = self.connection.as_ref().and_then(|conn| conn.1.tool_change());
+
+        if tool_change.is_some() {
+            ui.open_popup(im_str!("Tool Change"));
+        }
+
+        PopupModal::new(ui, im_str!("Tool Change"))
+            .always_auto_resize(true)
+            .build(|| {
+
+                let (conn, change) = match (self.connection.as_ref(), tool_change) {
+                    (Some((_, conn)), Some(change)) => (conn, change),
+                    _ => {
+                        ui.close_current_popup();
+                        return;
+                    }
+                };
+
+                match change.tool {
+                    Some(tool) => ui.text(format!("Insert tool T{}", tool)),
+                    None       => ui.text("Insert the next tool"),
+                }
+                ui.text_disabled(format!("for line {}", change.line + 1));
+
                 ui.separator();
 
+                match change.stage {
+                    ToolChangeStage::ReadingState       => ui.text("Finishing the lines before the tool change..."),
+                    ToolChangeStage::Moving             => ui.text("Moving to the tool change position..."),
+                    ToolChangeStage::MeasuringReference => ui.text("Measuring the tool that set work Z..."),
+                    ToolChangeStage::Measuring          => ui.text("Measuring the tool length..."),
+                    ToolChangeStage::Resuming           => ui.text("Restarting the spindle..."),
+                    ToolChangeStage::WaitingForTool     => {
+                        ui.text("Change the tool, then continue.");
+
+                        match (self.tool_change_settings.measure, change.has_reference) {
+                            (true, true)  => ui.text_disabled("The new tool is measured before the program continues."),
+                            (true, false) => ui.text_colored([1.0, 0.8, 0.2, 1.0], "The tool that set work Z wasn't measured, so the new tool can't be."),
+                            (false, _)    => {}
+                        }
+
+                        if ui.button(im_str!("Continue"), [100.0, 24.0]) {
+                            conn.continue_tool_change();
+                        }
+                        ui.same_line(0.0);
+                    }
+                }
+
+                if let Some(offset) = change.offset {
+                    ui.text(format!("Tool length offset {:.3}", offset));
+                }
+
+                if ui.button(im_str!("Stop Program"), [100.0, 24.0]) {
+                    conn.stop_program();
+                }
             });
 
+        // this window lists the controller's settings, and is used to edit them,
+        // write the changes back, and save or restore them from a file
+        let mut settings_window_open = self.settings_window_open;
+        if settings_window_open {
+            imgui::Window::new(im_str!("Settings"))
+                .size([480.0, 640.0], imgui::Condition::FirstUseEver)
+                .opened(&mut settings_window_open)
+                .build(ui, || {
+
+                    let conn = match self.connection {
+                        Some((_, ref conn)) => conn,
+                        None => {
+                            ui.text("Connect to a controller.");
+                            return;
+                        }
+                    };
+
+                    let settings = conn.settings();
+                    if settings != self.extended_settings_baseline {
+                        self.extended_settings_inputs = settings.values.iter()
+                            .map(|(&setting, value)| (setting, ImString::new(value.clone())))
+                            .collect();
+                        self.extended_settings_baseline = settings;
+                    }
+
+                    if ui.small_button(im_str!("Fetch##Fetch Settings")) {
+                        conn.fetch_settings();
+                    }
+
+                    if let Some(error) = self.settings_file_error.lock().unwrap().take() {
+                        self.settings_message = error;
+                    }
+
+                    let baseline = &self.extended_settings_baseline;
+
+                    if baseline.descriptors.is_empty() {
+                        ui.text("No setting descriptions received yet.");
+                        return;
+                    }
+
+                    // apply a file picked in the restore dialog as edits, so they can be reviewed before writing
+                    if let Some(file) = self.settings_file.lock().unwrap().take() {
+                        match conn.settings_from_file(&file) {
+                            Some(Ok(values)) => match values.iter().find(|(setting, _)| !baseline.values.contains_key(setting)) {
+                                Some((setting, _)) => {
+                                    self.settings_message = format!("Could not restore settings: unknown setting ${}", setting);
+                                }
+                                None => {
+                                    for (setting, value) in values {
+                                        self.extended_settings_inputs.insert(setting, ImString::new(value));
+                                    }
+                                    self.settings_message = String::from("Restored settings from file.");
+                                }
+                            }
+                            Some(Err(e)) => self.settings_message = format!("Could not restore settings: {}", e),
+                            None => {}
+                        }
+                    }
+
+                    // the groups in the order their first setting was described
+                    let mut groups = vec![];
+                    for d in baseline.descriptors.iter() {
+                        if !groups.contains(&d.group) {
+                            groups.push(d.group);
+                        }
+                    }
+
+                    let axis_count = conn.machine_status().axis_count();
+                    let mut changes = vec![];
+                    let mut all_valid = true;
+
+                    ui.separator();
+
+                    for group in groups {
+
+                        // settings in collapsed groups are still checked
+                        let open = CollapsingHeader::new(im_strf!("{}##group {}", baseline.group_name(group), group)).build(ui);
+
+                        for d in baseline.descriptors.iter().filter(|d| d.group == group) {
+
+                            // settings without a value aren't available, e.g. those of missing axes
+                            let original = match baseline.values.get(&d.setting) {
+                                Some(value) => value,
+                                None => continue,
+                            };
+
+                            let input = self.extended_settings_inputs.entry(d.setting)
+                                .or_insert_with(|| ImString::new(original.clone()));
+
+                            let validated = d.validate(input.to_str());
+                            let changed = match validated {
+                                Ok(ref value) => d.validate(original).ok().as_ref() != Some(value),
+                                Err(_) => true,
+                            };
+
+                            match validated {
+                                Ok(ref value) if changed => changes.push((d.setting, value.clone())),
+                                Ok(_) => {}
+                                Err(_) => all_valid = false,
+                            }
+
+                            if !open {
+                                continue;
+                            }
+
+                            if changed {
+                                ui.text_colored([1.0, 0.8, 0.2, 1.0], format!("${}", d.setting));
+                            } else {
+                                ui.text(format!("${}", d.setting));
+                            }
+                            ui.same_line(48.0);
+                            ui.text(&d.name);
+                            ui.same_line(280.0);
+
+                            let width = ui.push_item_width(100.0);
+                            match d.kind {
+                                ExtendedSettingType::Boolean => {
+                                    let mut checked = input.to_str().trim() == "1";
+                                    if ui.checkbox(im_strf!("##setting {}", d.setting), &mut checked) {
+                                        *input = ImString::new(if checked {"1"} else {"0"});
+                                    }
+                                }
+                                ExtendedSettingType::RadioButtons => {
+                                    let labels = d.options().into_iter().map(ImString::new).collect::<Vec<_>>();
+                                    let labels = labels.iter().map(|l| l.as_ref()).collect::<Vec<&ImStr>>();
+                                    let mut selected = input.to_str().trim().parse::<usize>().unwrap_or(0).min(labels.len().saturating_sub(1));
+                                    if imgui::ComboBox::new(im_strf!("##setting {}", d.setting)).build_simple_string(ui, &mut selected, &labels) {
+                                        *input = ImString::new(selected.to_string());
+                                    }
+                                }
+                                ExtendedSettingType::Bitfield | ExtendedSettingType::ExclusiveBitfield | ExtendedSettingType::AxisMask => {
+                                    let names = match d.kind {
+                                        ExtendedSettingType::AxisMask => AXIS_NAMES.iter().take(axis_count).map(|a| a.to_string()).collect::<Vec<_>>(),
+                                        _ => d.options().into_iter().map(String::from).collect(),
+                                    };
+
+                                    ui.input_text(im_strf!("##setting {}", d.setting), input)
+                                        .resize_buffer(true)
+                                        .build();
+
+                                    // one checkbox per named bit, below the value
+                                    let mut bits = input.to_str().trim().parse::<u32>().unwrap_or(0);
+                                    for (bit, name) in names.iter().enumerate().filter(|(_, name)| !name.is_empty() && name.as_str() != "N/A") {
+                                        let mut set = bits & (1 << bit) != 0;
+                                        ui.text("");
+                                        ui.same_line(64.0);
+                                        if ui.checkbox(im_strf!("{}##setting {} bit {}", name, d.setting, bit), &mut set) {
+                                            bits ^= 1 << bit;
+                                            *input = ImString::new(bits.to_string());
+                                        }
+                                    }
+                                }
+                                _ => {
+                                    ui.input_text(im_strf!("##setting {}", d.setting), input)
+                                        .password(d.kind == ExtendedSettingType::Password)
+                                        .resize_buffer(true)
+                                        .build();
+                                    ui.same_line(0.0);
+                                    ui.text_disabled(&d.unit);
+                                }
+                            }
+                            width.pop(ui);
+
+                            if let Err(e) = validated {
+                                ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("  {}", e));
+                            }
+                        }
+                    }
+
+                    ui.separator();
+
+                    // the values as edited, for saving them to a file
+                    let mut edited = baseline.values.clone();
+                    edited.extend(changes.iter().cloned());
+
+                    ui.text(format!("{} changed", changes.len()));
+
+                    if all_valid && !changes.is_empty() {
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Write Changes")) {
+                            if conn.write_settings(changes) {
+                                self.settings_message = String::from("Writing settings...");
+                            } else {
+                                self.settings_message = String::from("Cannot write settings while a program is running.");
+                            }
+                        }
+                    }
+
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Revert")) {
+                        self.extended_settings_inputs.clear();
+                    }
+
+                    // not every controller can save its settings
+                    let file = conn.settings_to_file(&edited);
+
+                    if let Some(contents) = file.clone().filter(|_| all_valid) {
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Save to File")) && !self.dialog_open.fetch_or(true, Ordering::SeqCst) {
+                            let dialog_open = self.dialog_open.clone();
+                            let settings_file_error = self.settings_file_error.clone();
+                            async_runtime.spawn_blocking(move || {
+
+                                match nfd::open_save_dialog(Some("txt"), None) {
+                                    Ok(nfd::Response::Okay(path)) => {
+                                        if let Err(e) = std::fs::write(&path, contents) {
+                                            *settings_file_error.lock().unwrap() = Some(format!("Could not save settings to {}: {}", path, e));
+                                        }
+                                    }
+                                    Ok(nfd::Response::Cancel) => log::debug!("saving settings canceled"),
+                                    _ => {}
+                                }
+
+                                dialog_open.store(false, Ordering::SeqCst);
+                            });
+                        }
+                    }
+
+                    if file.is_some() {
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Restore from File")) && !self.dialog_open.fetch_or(true, Ordering::SeqCst) {
+                            let dialog_open = self.dialog_open.clone();
+                            let settings_file = self.settings_file.clone();
+                            let settings_file_error = self.settings_file_error.clone();
+                            async_runtime.spawn_blocking(move || {
+
+                                match nfd::open_file_dialog(Some("txt"), None) {
+                                    Ok(nfd::Response::Okay(path)) => {
+                                        match std::fs::read_to_string(&path) {
+                                            Ok(contents) => *settings_file.lock().unwrap() = Some(contents),
+                                            Err(e) => *settings_file_error.lock().unwrap() = Some(format!("Could not read settings from {}: {}", path, e)),
+                                        }
+                                    }
+                                    Ok(nfd::Response::Cancel) => log::debug!("restoring settings canceled"),
+                                    _ => {}
+                                }
+
+                                dialog_open.store(false, Ordering::SeqCst);
+                            });
+                        }
+                    }
+
+                    if !self.settings_message.is_empty() {
+                        ui.text_wrapped(im_strf!("{}", self.settings_message));
+                    }
+                });
+        }
+        self.settings_window_open = settings_window_open;
+
+        // this window lists the startup blocks the controller runs after every reset,
+        // and is used to edit, clear and check them
+        let mut startup_window_open = self.startup_window_open;
+        if startup_window_open {
+            imgui::Window::new(im_str!("Startup Blocks"))
+                .size([480.0, 240.0], imgui::Condition::FirstUseEver)
+                .opened(&mut startup_window_open)
+                .build(ui, || {
+
+                    let conn = match self.connection {
+                        Some((_, ref conn)) => conn,
+                        None => {
+                            ui.text("Connect to a controller.");
+                            return;
+                        }
+                    };
+
+                    let startup = match conn.startup_blocks() {
+                        Some(startup) => startup,
+                        None => {
+                            ui.text("This controller doesn't have startup blocks.");
+                            return;
+                        }
+                    };
+
+                    if startup.blocks != self.startup_baseline {
+                        self.startup_inputs = startup.blocks.iter()
+                            .map(|block| ImString::new(block.clone().unwrap_or_default()))
+                            .collect();
+                        self.startup_baseline = startup.blocks.clone();
+                    }
+
+                    if ui.small_button(im_str!("Fetch##Fetch Startup Blocks")) {
+                        conn.fetch_startup_blocks();
+                    }
+
+                    if startup.blocks.iter().all(|b| b.is_none()) {
+                        ui.text("No startup blocks received yet.");
+                        return;
+                    }
+
+                    ui.separator();
+
+                    for (i, input) in self.startup_inputs.iter_mut().enumerate() {
+                        let changed = Some(input.to_str().trim()) != self.startup_baseline[i].as_deref();
+
+                        if changed {
+                            ui.text_colored([1.0, 0.8, 0.2, 1.0], format!("N{}", i));
+                        } else {
+                            ui.text(format!("N{}", i));
+                        }
+                        ui.same_line(48.0);
+
+                        let width = ui.push_item_width(240.0);
+                        ui.input_text(im_strf!("##startup block {}", i), input)
+                            .resize_buffer(true)
+                            .build();
+                        width.pop(ui);
+
+                        let validated = conn.validate_startup_block(input.to_str());
+
+                        if changed && validated.is_ok() {
+                            ui.same_line(0.0);
+                            if ui.small_button(im_strf!("Save##startup block {}", i)) {
+                                if let Err(e) = conn.write_startup_block(i, input.to_str()) {
+                                    self.startup_message = e;
+                                }
+                            }
+                        }
+
+                        ui.same_line(0.0);
+                        if ui.small_button(im_strf!("Clear##startup block {}", i)) {
+                            if let Err(e) = conn.write_startup_block(i, "") {
+                                self.startup_message = e;
+                            }
+                        }
+
+                        if let Err(e) = validated {
+                            ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("  {}", e));
+                        }
+                    }
+
+                    if !startup.results.is_empty() {
+                        ui.separator();
+                        ui.text("Last startup:");
+                        for (line, result) in startup.results.iter() {
+                            match result {
+                                Ok(())     => ui.text(format!("  {}: ok", line)),
+                                Err(error) => {
+                                    ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("  {}: {}", line, error));
+                                    if ui.is_item_hovered() {
+                                        ui.tooltip_text(&error.description);
+                                    }
+                                }
+                            }
+                        }
+                    }
+
+                    if !self.startup_message.is_empty() {
+                        ui.text_wrapped(im_strf!("{}", self.startup_message));
+                    }
+                });
+        }
+        self.startup_window_open = startup_window_open;
+
+        // this window shows every line sent to and received from GRBL
+        let mut console_window_open = self.console_window_open;
+        if console_window_open {
+            imgui::Window::new(im_str!("Console"))
+                .size([560.0, 400.0], imgui::Condition::FirstUseEver)
+                .opened(&mut console_window_open)
+                .build(ui, || {
+
+                    let conn = match self.connection {
+                        Some((_, ref conn)) => conn,
+                        None => {
+                            ui.text("Connect to a controller.");
+                            return;
+                        }
+                    };
+
+                    ui.checkbox(im_str!("Status reports"), &mut self.console_show_status);
+                    ui.same_line(0.0);
+                    ui.checkbox(im_str!("Auto-scroll"), &mut self.console_auto_scroll);
+                    ui.same_line(0.0);
+                    let width = ui.push_item_width(160.0);
+                    ui.input_text(im_str!("Filter##Console Filter"), &mut self.console_filter)
+                        .resize_buffer(true)
+                        .build();
+                    width.pop(ui);
+
+                    let show_status = self.console_show_status;
+                    let text_filter = self.console_filter.to_str().to_ascii_uppercase();
+                    let filter = |e : &TrafficEntry| {
+                        (show_status || !e.is_status()) && e.line.to_ascii_uppercase().contains(&text_filter)
+                    };
+
+                    let traffic = conn.traffic();
+                    let mut traffic = traffic.lock().unwrap();
+
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Clear##Clear Console")) {
+                        traffic.clear();
+                    }
+
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Export")) {
+                        if !self.dialog_open.fetch_or(true, Ordering::SeqCst) {
+                            let dialog_open = self.dialog_open.clone();
+                            let contents = traffic.export(&filter);
+                            async_runtime.spawn_blocking(move || {
+
+                                match nfd::open_save_dialog(Some("log,txt"), None) {
+                                    Ok(nfd::Response::Okay(path)) => {
+                                        if let Err(e) = std::fs::write(&path, contents) {
+                                            log::error!("failed to export console to {:?}: {}", path, e);
+                                        }
+                                    }
+                                    Ok(nfd::Response::Cancel) => log::debug!("exporting the console canceled"),
+                                    _ => {}
+                                }
+
+                                dialog_open.store(false, Ordering::SeqCst);
+                            });
+                        }
+                    }
+
+                    if let Some(path) = self.recording_file.lock().unwrap().take() {
+                        match conn.start_recording(&path) {
+                            Ok(()) => self.recording = Some(path),
+                            Err(e) => log::error!("failed to record to {:?}: {}", path, e),
+                        }
+                    }
+
+                    ui.same_line(0.0);
+                    if self.recording.is_some() {
+                        if ui.small_button(im_str!("Stop Recording")) {
+                            conn.stop_recording();
+                            self.recording = None;
+                        }
+                    } else if ui.small_button(im_str!("Record")) {
+                        if !self.dialog_open.fetch_or(true, Ordering::SeqCst) {
+                            let dialog_open = self.dialog_open.clone();
+                            let recording_file = self.recording_file.clone();
+                            async_runtime.spawn_blocking(move || {
+
+                                match nfd::open_save_dialog(Some("grbl"), None) {
+                                    Ok(nfd::Response::Okay(path)) => {
+                                        *recording_file.lock().unwrap() = Some(PathBuf::from(path));
+                                    }
+                                    Ok(nfd::Response::Cancel) => log::debug!("recording canceled"),
+                                    _ => {}
+                                }
+
+                                dialog_open.store(false, Ordering::SeqCst);
+                            });
+                        }
+                    }
+
+                    if let Some(ref path) = self.recording {
+                        ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("Recording to {}", path.display()));
+                        ui.text_disabled(format!("Replay it by connecting to replay://{}", path.display()));
+                    }
+
+                    ui.separator();
+
+                    let shown = traffic.entries.iter()
+                        .filter(|e| filter(e))
+                        .collect::<Vec<_>>();
+
+                    ChildWindow::new("##Console Lines").build(ui, || {
+                        let mut clipper = ListClipper::new(shown.len() as i32).begin(ui);
+                        while clipper.step() {
+                            for entry in shown[clipper.display_start() as usize..clipper.display_end() as usize].iter() {
+                                let line = entry.to_log_line();
+                                if entry.is_error() {
+                                    ui.text_colored([1.0, 0.3, 0.3, 1.0], line);
+                                } else if entry.direction != TrafficDirection::Received {
+                                    ui.text_colored([0.5, 0.8, 1.0, 1.0], line);
+                                } else {
+                                    ui.text(line);
+                                }
+                            }
+                        }
+                        clipper.end();
+
+                        if self.console_auto_scroll && ui.scroll_y() >= ui.scroll_max_y() - 20.0 {
+                            ui.set_scroll_here_y_with_ratio(1.0);
+                        }
+                    });
+                });
+        }
+        self.console_window_open = console_window_open;
+
+        // this window charts the status reports received in the last few seconds
+        let mut history_window_open = self.history_window_open;
+        if history_window_open {
+            imgui::Window::new(im_str!("Status History"))
+                .size([480.0, 560.0], imgui::Condition::FirstUseEver)
+                .opened(&mut history_window_open)
+                .build(ui, || {
+
+                    let conn = match self.connection {
+                        Some((_, ref conn)) => conn,
+                        None => {
+                            ui.text("Connect to a controller.");
+                            return;
+                        }
+                    };
+
+                    let width = ui.push_item_width(160.0);
+                    Slider::new(im_str!("Seconds"))
+                        .range(5..=600)
+                        .build(ui, &mut self.history_seconds);
+                    width.pop(ui);
+
+                    let history = conn.status_history();
+
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Clear##Clear History")) {
+                        history.lock().unwrap().clear();
+                    }
+
+                    let history = history.lock().unwrap();
+                    let samples = history.recent(Duration::from_secs(self.history_seconds as u64)).collect::<Vec<_>>();
+
+                    if samples.is_empty() {
+                        ui.text("No status received yet.");
+                        return;
+                    }
+
+                    let [ww, _] = ui.content_region_avail();
+                    let size = [ww, 60.0];
+
+                    let plot = |label : &ImStr, values : &[f32]| {
+                        let max = values.iter().cloned().fold(f32::MIN, f32::max);
+                        let min = values.iter().cloned().fold(f32::MAX, f32::min);
+                        let last = values.last().cloned().unwrap_or_default();
+                        ui.text(format!("{}: {:.1} (min {:.1}, max {:.1})", label, last, min, max));
+                        PlotLines::new(ui, &im_stringf!("##{}", label), values)
+                            .graph_size(size)
+                            .build();
+                    };
+
+                    let feed = samples.iter().map(|s| s.status.feed).collect::<Vec<_>>();
+                    let speed = samples.iter().map(|s| s.status.speed).collect::<Vec<_>>();
+
+                    plot(im_str!("Feed"), &feed);
+                    plot(im_str!("Spindle Speed"), &speed);
+
+                    // the buffer sizes are only known from `$I`, so chart how much of them is free
+                    let free_blocks = samples.iter().map(|s| s.status.buffer_free_blocks as f32).collect::<Vec<_>>();
+                    let free_bytes = samples.iter().map(|s| s.status.buffer_free_bytes as f32).collect::<Vec<_>>();
+
+                    plot(im_str!("Free Planner Blocks"), &free_blocks);
+                    plot(im_str!("Free RX Bytes"), &free_bytes);
+
+                    let axis_count = samples.last().map(|s| s.status.axis_count()).unwrap_or(DEFAULT_AXIS_COUNT);
+                    for axis in 0..axis_count {
+                        let position = samples.iter()
+                            .map(|s| s.status.machine_position.as_slice().get(axis).cloned().unwrap_or_default())
+                            .collect::<Vec<_>>();
+                        plot(&im_stringf!("Machine {}", AXIS_NAMES[axis]), &position);
+                    }
+                });
+        }
+        self.history_window_open = history_window_open;
+
+        // this window configures the passes programs go through before they are sent,
+        // and previews what the active program becomes
+        let mut preprocess_window_open = self.preprocess_window_open;
+        if preprocess_window_open {
+            imgui::Window::new(im_str!("Preprocessing"))
+                .size([520.0, 560.0], imgui::Condition::FirstUseEver)
+                .opened(&mut preprocess_window_open)
+                .build(ui, || {
+
+                    let mut changed = false;
+
+                    for (i, (pass, enabled)) in self.pipeline.passes.iter_mut().enumerate() {
+                        changed |= ui.checkbox(im_strf!("{}##pass {}", pass.name(), i), enabled);
+
+                        let width = ui.push_item_width(80.0);
+                        match pass {
+                            PreprocessPass::RoundCoordinates{decimals} => {
+                                let mut value = *decimals as i32;
+                                ui.same_line(200.0);
+                                if ui.input_int(im_str!("Decimals"), &mut value).build() {
+                                    *decimals = value.max(0).min(6) as u32;
+                                    changed = true;
+                                }
+                            }
+                            PreprocessPass::SplitLongLines{max_length} => {
+                                let mut value = *max_length as i32;
+                                ui.same_line(200.0);
+                                if ui.input_int(im_str!("Max Length"), &mut value).build() {
+                                    *max_length = value.max(16) as usize;
+                                    changed = true;
+                                }
+                            }
+                            _ => {}
+                        }
+                        width.pop(ui);
+                    }
+
+                    if changed {
+                        if let Some((_, ref conn)) = self.connection {
+                            conn.set_pipeline(self.pipeline.clone());
+                        }
+                    }
+
+                    ui.text_disabled("Applies to programs started from now on.");
+                    ui.separator();
+
+                    let program = match self.active_program {
+                        Some(ref program) => program,
+                        None => {
+                            ui.text("Load a program to preview it.");
+                            return;
+                        }
+                    };
+
+                    // the preview is only run again when the program, the passes or the firmware change
+                    let firmware = self.connection.as_ref().map(|(_, conn)| conn.firmware());
+                    let stale = match self.preprocess_preview {
+                        Some((ref path, ref pipeline, ref previewed, _)) => *path != program.filepath || *pipeline != self.pipeline || *previewed != firmware,
+                        None => true,
+                    };
+                    if stale {
+                        let processed = match self.connection {
+                            Some((_, ref conn)) => conn.preprocess(&self.pipeline, &program.lines),
+                            None => self.pipeline.run(&program.lines),
+                        };
+                        self.preprocess_preview = Some((program.filepath.clone(), self.pipeline.clone(), firmware, processed));
+                    }
+
+                    let processed = match self.preprocess_preview.as_ref().unwrap().3 {
+                        Ok(ref processed) => processed,
+                        Err(ref e) => {
+                            ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("Cannot be sent: {}", e));
+                            return;
+                        }
+                    };
+
+                    // each line is sent with a newline
+                    let before = program.lines.iter().map(|l| l.trim_end().len() + 1).sum::<usize>();
+                    let after = processed.lines.iter().map(|(_, l)| l.trim_end().len() + 1).sum::<usize>();
+
+                    ui.text(format!("{} lines, {} bytes", program.lines.len(), before));
+                    ui.same_line(200.0);
+                    ui.text(format!("-> {} lines, {} bytes", processed.lines.len(), after));
+
+                    if !processed.warnings.is_empty() {
+                        ui.text_colored([1.0, 0.8, 0.2, 1.0], format!("Warnings ({})", processed.warnings.len()));
+                        ChildWindow::new("##Preprocess Warnings").size([0.0, 100.0]).border(true).build(ui, || {
+                            let mut clipper = ListClipper::new(processed.warnings.len() as i32).begin(ui);
+                            while clipper.step() {
+                                for warning in processed.warnings[clipper.display_start() as usize..clipper.display_end() as usize].iter() {
+                                    ui.text(format!("Line {}: {}", warning.line + 1, warning.message));
+                                }
+                            }
+                            clipper.end();
+                        });
+                    }
+
+                    ui.separator();
+
+                    // every line sent, with the number of the line it came from
+                    ChildWindow::new("##Preprocess Preview").build(ui, || {
+                        let mut clipper = ListClipper::new(processed.lines.len() as i32).begin(ui);
+                        while clipper.step() {
+                            for (i, line) in processed.lines[clipper.display_start() as usize..clipper.display_end() as usize].iter() {
+                                ui.text_disabled(format!("{:>6}", i + 1));
+                                ui.same_line(60.0);
+                                ui.text(line.trim_end());
+                                if ui.is_item_hovered() {
+                                    ui.tooltip_text(program.lines[*i].trim_end());
+                                }
+                            }
+                        }
+                        clipper.end();
+                    });
+                });
+        }
+        self.preprocess_window_open = preprocess_window_open;
 
         let tok = ui.push_style_var(StyleVar::WindowPadding([0.0; 2]));
 
@@ -16,4 +16,15 @@ impl std::fmt::Display for ByteSize {
 
         write!(f, "{:.1}{}", size, SUFFIXES[i])
     }
+}
+
+/// A duration in seconds, displayed as `h:mm:ss`.
+pub struct HoursMinutesSeconds(pub f32);
+
+impl std::fmt::Display for HoursMinutesSeconds {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let seconds = self.0.max(0.0).round() as u64;
+
+        write!(f, "{}:{:02}:{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+    }
 }
\ No newline at end of file